@@ -0,0 +1,222 @@
+// Benchmark of the package decoding in util::signal against the iterator
+// based decoding it replaced, kept below as `legacy`. Both decode the same
+// random bits with packages in between and have to find the same packages.
+//
+// Usage: bench_signal [bits]
+
+use bit_vec::BitVec;
+use humanize_duration::prelude::DurationExt;
+use humanize_duration::Truncate;
+use lightchannel::tools::signal::{decode_packages, encode_package_with, StreamDecoder};
+use lightchannel::{Address, Header};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::{Duration, Instant};
+
+const DEFAULT_BITS: usize = 200_000;
+// the old stream decoder rescans its whole buffer for every bit
+const LEGACY_STREAM_BITS: usize = 2_000;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let len = args
+        .get(1)
+        .map(|bits| bits.parse().expect("Invalid number of bits"))
+        .unwrap_or(DEFAULT_BITS);
+
+    let (bits, sent) = random_bits(len);
+    println!("bits: {} with {} packages\n", bits.len(), sent);
+
+    println!("Decoding packages, iterators...");
+    let (legacy_packages, legacy_took) = timed(|| legacy::decode_packages(&bits));
+    report(bits.len(), legacy_took);
+
+    println!("Decoding packages, bit reader...");
+    let (packages, took) = timed(|| decode_packages(&bits));
+    report(bits.len(), took);
+    assert_eq!(packages, legacy_packages, "Decoded packages differ");
+    println!(
+        "Found {} packages, {:.1}x faster\n",
+        packages.len(),
+        legacy_took.as_secs_f64() / took.as_secs_f64()
+    );
+
+    let prefix: BitVec = bits.iter().take(LEGACY_STREAM_BITS).collect();
+    println!("Streaming {} bits, iterators...", prefix.len());
+    let mut legacy_decoder = legacy::StreamDecoder::new();
+    let (legacy_streamed, legacy_took) = timed(|| stream(&prefix, |bit| legacy_decoder.push(bit)));
+    report(prefix.len(), legacy_took);
+
+    println!("Streaming {} bits, bit reader...", bits.len());
+    let mut decoder = StreamDecoder::new();
+    let (streamed, took) = timed(|| stream(&bits, |bit| decoder.push(bit)));
+    report(bits.len(), took);
+    let mut decoder = StreamDecoder::new();
+    assert_eq!(
+        stream(&prefix, |bit| decoder.push(bit)),
+        legacy_streamed,
+        "Streamed packages differ"
+    );
+    println!(
+        "Found {} packages, {:.1}x faster per bit",
+        streamed.len(),
+        (legacy_took.as_secs_f64() / prefix.len() as f64)
+            / (took.as_secs_f64() / bits.len() as f64)
+    );
+}
+
+// random bits with packages of random data in between, and their number
+fn random_bits(len: usize) -> (BitVec, usize) {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut bits = BitVec::with_capacity(len);
+    let mut sent = 0;
+    while bits.len() < len {
+        for _ in 0..rng.gen::<u32>() % 512 {
+            bits.push(rng.gen());
+        }
+        let data: Vec<u8> = (0..rng.gen::<u32>() % 64).map(|_| rng.gen()).collect();
+        let header = Header {
+            seq: sent as u8,
+            src: Address(rng.gen::<u8>() % 128),
+            ..Header::default()
+        };
+        let package = encode_package_with(&BitVec::from_bytes(&data), &header).unwrap();
+        bits.extend(package.iter());
+        sent += 1;
+    }
+    (bits, sent)
+}
+
+fn stream(bits: &BitVec, mut push: impl FnMut(bool) -> Option<BitVec>) -> Vec<BitVec> {
+    bits.iter().filter_map(&mut push).collect()
+}
+
+fn timed<T>(run: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = run();
+    (result, start.elapsed())
+}
+
+fn report(bits: usize, took: Duration) {
+    println!(
+        "Speed: {}/s",
+        human_bytes::human_bytes(bits as f64 / 8.0 / took.as_secs_f64())
+    );
+    println!("Took: {}\n", took.human(Truncate::Nano));
+}
+
+// The decoding before util::bits, with BitVec iterators and a copy for
+// every byte read.
+mod legacy {
+    use bit_vec::BitVec;
+    use crc::{Crc, CRC_8_BLUETOOTH};
+    use lightchannel::tools::signal::{
+        get_preamble, HEADER_LEN, PACKAGE_VERSION, PREAMBLE_LEN, TRAILER_LEN,
+    };
+    use lightchannel::{Address, Header};
+
+    const CRC: Crc<u8> = Crc::<u8>::new(&CRC_8_BLUETOOTH);
+    const FIELDS_LEN: usize = HEADER_LEN - PREAMBLE_LEN;
+    const MAX_PACKAGE_LEN: usize = HEADER_LEN + u8::MAX as usize * 8 + TRAILER_LEN;
+
+    pub fn decode_packages(bits: &BitVec) -> Vec<(Header, BitVec)> {
+        let mut packages = Vec::new();
+        let mut rest = bits.clone();
+        while let Some((header, package, end)) = find_package(&rest) {
+            packages.push((header, package));
+            rest = rest.iter().skip(end).collect();
+        }
+        packages
+    }
+
+    fn find_package(package_bits: &BitVec) -> Option<(Header, BitVec, usize)> {
+        let preamble_bits = get_preamble();
+        for i in 0..package_bits.len() {
+            let window = package_bits.iter().skip(i).take(PREAMBLE_LEN);
+            if window.eq(preamble_bits.iter()) {
+                if let Some(package) = decode_package_at_index(package_bits, i + PREAMBLE_LEN) {
+                    return Some(package);
+                }
+            }
+        }
+        None
+    }
+
+    fn decode_package_at_index(
+        package_bits: &BitVec,
+        start_index: usize,
+    ) -> Option<(Header, BitVec, usize)> {
+        if start_index + FIELDS_LEN > package_bits.len() {
+            return None;
+        }
+        let fields: Vec<u8> = (0..FIELDS_LEN / 8)
+            .map(|i| read_one_byte(package_bits, start_index + i * 8))
+            .collect();
+        let (crc, fields) = fields.split_last().unwrap();
+        if *crc != CRC.checksum(fields) || fields[0] >> 4 != PACKAGE_VERSION {
+            return None;
+        }
+        let header = Header {
+            flags: fields[0] & 0b1111,
+            dst: Address(fields[1]),
+            src: Address(fields[2]),
+            mode: fields[3],
+            seq: fields[4],
+        };
+        let size = fields[5] as usize;
+
+        let data_index = start_index + FIELDS_LEN;
+        let end = data_index + size * 8 + TRAILER_LEN;
+        if end > package_bits.len() {
+            return None;
+        }
+        let data: BitVec = package_bits
+            .iter()
+            .skip(data_index)
+            .take(size * 8)
+            .collect();
+        let crc = read_one_byte(package_bits, data_index + size * 8);
+        let mut digest = CRC.digest();
+        digest.update(fields);
+        digest.update(&data.to_bytes());
+        if crc != digest.finalize() {
+            return None;
+        }
+        Some((header, data, end))
+    }
+
+    fn read_one_byte(data: &BitVec, start: usize) -> u8 {
+        data.iter()
+            .skip(start)
+            .take(8)
+            .collect::<BitVec>()
+            .to_bytes()[0]
+    }
+
+    pub struct StreamDecoder {
+        bits: BitVec,
+    }
+
+    impl StreamDecoder {
+        pub fn new() -> Self {
+            StreamDecoder {
+                bits: BitVec::new(),
+            }
+        }
+
+        pub fn push(&mut self, bit: bool) -> Option<BitVec> {
+            self.bits.push(bit);
+
+            if let Some((_header, package, end)) = find_package(&self.bits) {
+                self.bits = self.bits.iter().skip(end).collect();
+                return Some(package);
+            }
+
+            if self.bits.len() > MAX_PACKAGE_LEN {
+                let excess = self.bits.len() - MAX_PACKAGE_LEN;
+                self.bits = self.bits.iter().skip(excess).collect();
+            }
+            None
+        }
+    }
+}
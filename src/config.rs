@@ -0,0 +1,41 @@
+// Parameters both ends of a link have to agree on.
+
+use crate::util::codec::Format;
+use crate::util::coding::Coding;
+use crate::util::modulation::Modulation;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    /// Frames per second, one symbol per frame
+    pub fps: u32,
+    /// Size of the rendered frames
+    pub width: u32,
+    pub height: u32,
+    /// Gray levels per frame
+    pub modulation: Modulation,
+    /// Error correction of the package bits
+    pub coding: Coding,
+    /// Serialization of the messages sent
+    pub format: Format,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            fps: 30,
+            width: 64,
+            height: 64,
+            modulation: Modulation::Ook,
+            coding: Coding::None,
+            format: Format::Compact,
+        }
+    }
+}
+
+impl Config {
+    /// Bits per second of the channel, before coding and framing.
+    pub fn bit_rate(&self) -> f32 {
+        (self.fps as usize * self.modulation.bits_per_symbol()) as f32
+    }
+}
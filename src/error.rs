@@ -0,0 +1,36 @@
+// What can go wrong between a payload and a video file.
+
+use crate::util::signal::PackageError;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The payload doesn't fit into packages or a package wasn't received
+    Package(PackageError),
+    /// Reading or writing a video file failed
+    Video(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Package(e) => write!(f, "{}", e),
+            Error::Video(e) => write!(f, "Video: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Package(e) => Some(e),
+            Error::Video(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+impl From<PackageError> for Error {
+    fn from(e: PackageError) -> Self {
+        Error::Package(e)
+    }
+}
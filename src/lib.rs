@@ -0,0 +1,50 @@
+//! Send data from a screen to a camera by blinking light.
+//!
+//! A [`Transmitter`] splits a payload into [`Package`]s, protects them with
+//! the [`Coding`] and modulates them into the gray level of video frames,
+//! a [`Receiver`] samples the frames of a video or camera and decodes the
+//! packages again. Both sides have to agree on the [`Config`]. Structured
+//! content is sent as [`Message`].
+//!
+//! ```
+//! use lightchannel::{Config, Modulation, Receiver, Transmitter};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let config = Config {
+//!     modulation: Modulation::Pam4,
+//!     ..Config::default()
+//! };
+//! let frames = Transmitter::new(config.clone()).frames(b"Hello World!")?;
+//! let payload = Receiver::new(config).receive_frames(&frames)?;
+//! assert_eq!(payload, b"Hello World!");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything the API needs is re-exported here. Video files
+//! (`Transmitter::write_video`, `Receiver::receive_video`) need the `ffmpeg`
+//! feature, cameras and windows the `opencv` or `camera` feature, all of
+//! them are on by default.
+
+mod config;
+mod error;
+mod package;
+mod receiver;
+#[doc(hidden)]
+pub mod tools;
+mod transmitter;
+pub(crate) mod util;
+
+pub use config::Config;
+pub use error::Error;
+pub use package::Package;
+pub use receiver::Receiver;
+pub use transmitter::Transmitter;
+pub use util::codec::Format;
+pub use util::coding::Coding;
+pub use util::message::{decode_message, encode_message, Message, MessageError};
+pub use util::modulation::Modulation;
+pub use util::rate::{LinkQuality, RateMode};
+pub use util::sampler::{Region, Roi, Sampler, Statistic};
+pub use util::signal::{Address, AddressFilter, Header, PackageError};
+pub use util::transfer::{FileChunk, FileManifest};
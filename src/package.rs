@@ -0,0 +1,164 @@
+// The unit of transmission: preamble, a header with its own CRC, data and
+// CRC, see util::signal.
+
+use crate::util::rate::RateMode;
+use crate::util::signal::{
+    decode_package_with, encode_package_with, Address, Header, PackageError, FLAG_LAST,
+};
+use bit_vec::BitVec;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Package {
+    header: Header,
+    data: Vec<u8>,
+}
+
+impl Package {
+    /// Largest data of a single package in bytes
+    pub const MAX_LEN: usize = u8::MAX as usize;
+    /// Most packages of a payload, one per sequence number
+    pub const MAX_COUNT: usize = u8::MAX as usize + 1;
+    /// Largest payload in bytes, about 64 KB
+    pub const MAX_PAYLOAD: usize = Package::MAX_COUNT * Package::MAX_LEN;
+
+    pub fn new(data: Vec<u8>) -> Result<Self, PackageError> {
+        if data.len() > Package::MAX_LEN {
+            return Err(PackageError::TooLarge(data.len()));
+        }
+        Ok(Package {
+            header: Header::default(),
+            data,
+        })
+    }
+
+    /// Packages of a payload up to `MAX_PAYLOAD` bytes, at least one,
+    /// numbered from 0 and the last one flagged.
+    pub fn split(payload: &[u8]) -> Result<Vec<Package>, PackageError> {
+        if payload.len() > Package::MAX_PAYLOAD {
+            return Err(PackageError::TooManyPackages(payload.len()));
+        }
+        if payload.is_empty() {
+            return Ok(vec![Package {
+                header: Header {
+                    flags: FLAG_LAST,
+                    ..Header::default()
+                },
+                data: Vec::new(),
+            }]);
+        }
+        let count = payload.len().div_ceil(Package::MAX_LEN);
+        Ok(payload
+            .chunks(Package::MAX_LEN)
+            .enumerate()
+            .map(|(i, chunk)| Package {
+                header: Header {
+                    flags: if i + 1 == count { FLAG_LAST } else { 0 },
+                    seq: i as u8,
+                    ..Header::default()
+                },
+                data: chunk.to_vec(),
+            })
+            .collect())
+    }
+
+    /// Received package with the header it came with.
+    pub(crate) fn with_header(header: Header, data: Vec<u8>) -> Self {
+        Package { header, data }
+    }
+
+    /// From `src` to `dst` instead of from nobody to everyone.
+    pub fn addressed(self, src: Address, dst: Address) -> Self {
+        Package {
+            header: Header {
+                src,
+                dst,
+                ..self.header
+            },
+            ..self
+        }
+    }
+
+    /// Says in the header that the data is sent in `mode`.
+    pub fn with_mode(self, mode: RateMode) -> Self {
+        Package {
+            header: Header {
+                mode: mode.tag(),
+                ..self.header
+            },
+            ..self
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Bits on the channel, before coding.
+    pub fn encode(&self) -> BitVec {
+        encode_package_with(&BitVec::from_bytes(&self.data), &self.header)
+            .expect("Packages have valid sizes and flags")
+    }
+
+    /// First package in `bits`, whatever the destination.
+    pub fn decode(bits: &BitVec) -> Option<Package> {
+        decode_package_with(bits)
+            .map(|(header, data)| Package::with_header(header, data.to_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_numbers_packages() {
+        let payload: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let packages = Package::split(&payload).unwrap();
+        assert_eq!(packages.len(), 3);
+        for (i, package) in packages.iter().enumerate() {
+            assert_eq!(package.header().seq, i as u8);
+            assert_eq!(package.header().is_last(), i == 2);
+        }
+        let joined: Vec<u8> = packages.into_iter().flat_map(Package::into_data).collect();
+        assert_eq!(joined, payload);
+    }
+
+    #[test]
+    fn split_empty_payload() {
+        let packages = Package::split(&[]).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert!(packages[0].header().is_last());
+        assert!(packages[0].data().is_empty());
+    }
+
+    #[test]
+    fn split_rejects_more_packages_than_sequence_numbers() {
+        assert_eq!(
+            Package::split(&vec![0; Package::MAX_PAYLOAD])
+                .unwrap()
+                .len(),
+            Package::MAX_COUNT
+        );
+        assert_eq!(
+            Package::split(&vec![0; Package::MAX_PAYLOAD + 1]),
+            Err(PackageError::TooManyPackages(Package::MAX_PAYLOAD + 1))
+        );
+    }
+
+    #[test]
+    fn encode_decode() {
+        let package = Package::split(b"Hello World!")
+            .unwrap()
+            .remove(0)
+            .addressed(Address(3), Address::group(1));
+        assert_eq!(Package::decode(&package.encode()), Some(package));
+    }
+}
@@ -0,0 +1,168 @@
+/// Decode packages from the webcam in real time.
+/// Usage: receive [SOURCE] [--out DIR] [--address A] [--group G]...
+/// SOURCE is "camera" (default), "camera:N", "nokhwa:N", a video file or a
+/// directory of recorded PNG frames. The transmitter is located during the
+/// first second, decoded messages are printed and saved to DIR (default
+/// "received"), raw data only once all its packages arrived. Transferred
+/// files are written to DIR as well, a replayed transfer fills the gaps of
+/// the partial file. With --address only packages to the address, its
+/// groups (e.g. g3) and broadcasts are received.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use lightchannel::tools::locator::Locator;
+use lightchannel::tools::signal::StreamDecoder;
+use lightchannel::tools::source::open_source;
+use lightchannel::tools::transfer::{FileReceiver, TransferEvent};
+use lightchannel::{decode_message, Address, AddressFilter, Message};
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut source_name = String::from("camera");
+    let mut out_dir = String::from("received");
+    let mut filter = AddressFilter::default();
+    let args: Vec<String> = std::env::args().collect();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--out" => {
+                out_dir = args.get(i + 1).ok_or("Missing directory")?.clone();
+                i += 1;
+            }
+            flag @ ("--address" | "--group") => {
+                let name = args.get(i + 1).ok_or("Missing address")?;
+                let address =
+                    Address::from_name(name).ok_or_else(|| format!("Invalid address: {}", name))?;
+                if flag == "--address" {
+                    filter.address = Some(address);
+                } else if address.is_group() {
+                    filter.join(address);
+                } else {
+                    return Err(format!("Not a group: {}", name).into());
+                }
+                i += 1;
+            }
+            name => source_name = name.to_string(),
+        }
+        i += 1;
+    }
+
+    let mut source = open_source(&source_name)?;
+    let fps = source.fps().unwrap_or(30.0);
+    println!("Receiving from {} at {} fps", source_name, fps);
+    fs::create_dir_all(&out_dir)?;
+
+    let mut locator = Locator::for_camera(fps as u32, 1.0);
+    let mut decoder = StreamDecoder::with_filter(filter);
+    let mut received = 0;
+    let mut files = FileReceiver::new(Path::new(&out_dir));
+    // raw data received so far and the sequence number expected next,
+    // `None` after a gap until the next package 0
+    let mut raw = Vec::new();
+    let mut raw_next = None;
+
+    // until the end of a video file or recording
+    while let Some(frame) = source.next_frame()? {
+        let roi_before = locator.roi();
+        // the frames of the first second come at once, when located
+        let samples = locator.push_and_sample(frame.luma());
+        if let Some(roi) = locator.roi().filter(|roi| Some(*roi) != roi_before) {
+            println!("Transmitter at {:?}", roi);
+        }
+
+        for sample in samples {
+            if let Some(package) = decoder.push(sample.bit) {
+                received += 1;
+                let path = format!("{}/message_{:04}", out_dir, received);
+                if let Some(header) = decoder.header() {
+                    if header.src != Address::UNASSIGNED {
+                        println!("From {} to {}", header.src, header.dst);
+                    }
+                }
+                match decode_message(&package) {
+                    Ok(Message::Text { id, content }) => {
+                        println!("Message {}: {:?}", id, content);
+                        fs::write(path + ".txt", content)?;
+                    }
+                    Ok(Message::Url(url)) => {
+                        println!("URL: {}", url);
+                        fs::write(path + ".url", url)?;
+                    }
+                    Ok(Message::Binary(data)) => {
+                        println!("Binary: {} bytes", data.len());
+                        fs::write(path + ".bin", data)?;
+                    }
+                    Ok(message @ (Message::FileManifest(_) | Message::FileChunk(_))) => {
+                        match files.push(message) {
+                            Ok(event) => print_event(event),
+                            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                                println!("Ignored: {}", e)
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    Ok(Message::Data { seq, data, .. }) => {
+                        println!("Data {}: {} bytes", seq, data.len());
+                        fs::write(path + ".bin", data)?;
+                    }
+                    Ok(Message::Ack { id }) => println!("Ack {}", id),
+                    Ok(Message::Nack { id }) => println!("Nack {}", id),
+                    Ok(Message::Over { ack }) => println!("Over, ack {}", ack),
+                    Ok(Message::Ping { id }) => println!("Ping {}", id),
+                    Ok(Message::Config(config)) => println!("Transmitter config: {:?}", config),
+                    Ok(Message::LinkQuality(quality)) => println!("Link quality: {:?}", quality),
+                    // raw data, e.g. from video_signal send, is only saved
+                    // as a whole: the packages 0 to the one flagged last
+                    // without a gap, so noise passing the CRCs by chance
+                    // isn't written to DIR
+                    Err(e) => {
+                        let header = decoder.header().expect("Header of the package");
+                        let seq = header.seq as usize;
+                        if seq == 0 {
+                            raw.clear();
+                            raw_next = Some(0);
+                        }
+                        if raw_next != Some(seq) {
+                            println!("Package {} ({}) out of order, dropped", seq, e);
+                            raw_next = None;
+                            continue;
+                        }
+                        raw.extend(package.to_bytes());
+                        if !header.is_last() {
+                            println!("Package {} ({})", seq, e);
+                            raw_next = Some(seq + 1);
+                            continue;
+                        }
+                        let text = String::from_utf8_lossy(&raw).to_string();
+                        println!("Raw data of {} packages: {:?}", seq + 1, text);
+                        fs::write(path + ".txt", text)?;
+                        raw.clear();
+                        raw_next = None;
+                    }
+                }
+            }
+        }
+    }
+
+    println!("Received {} packages", received);
+    if let Some(manifest) = files.manifest().filter(|_| !files.is_complete()) {
+        println!(
+            "{} incomplete, missing bytes {:?}",
+            manifest.name,
+            files.missing_ranges()
+        );
+    }
+    Ok(())
+}
+
+fn print_event(event: TransferEvent) {
+    match event {
+        TransferEvent::Started { name, received } => {
+            println!("Receiving {}, {} chunks from before", name, received)
+        }
+        TransferEvent::Chunk { received, total } => println!("Chunk {}/{}", received, total),
+        TransferEvent::Completed(path) => println!("File saved as {}", path.display()),
+        TransferEvent::HashMismatch => println!("SHA-256 mismatch"),
+        TransferEvent::Ignored => {}
+    }
+}
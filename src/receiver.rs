@@ -0,0 +1,206 @@
+// Frames to payload, the inverse of the transmitter. The data of every
+// package is read in the mode its header says, see util::rate.
+
+use crate::config::Config;
+#[cfg(feature = "ffmpeg")]
+use crate::error::Error;
+use crate::package::Package;
+use crate::transmitter::REFERENCE_FRAMES;
+use crate::util::rate;
+use crate::util::sampler::Sampler;
+use crate::util::signal::{AddressFilter, PackageError};
+#[cfg(feature = "ffmpeg")]
+use crate::util::video::read_video_timed;
+use image::GrayImage;
+
+pub struct Receiver {
+    pub config: Config,
+    /// Region of the frames showing the transmitter
+    pub sampler: Sampler,
+    /// Packages to other receivers are dropped
+    pub filter: AddressFilter,
+}
+
+impl Receiver {
+    pub fn new(config: Config) -> Self {
+        Receiver {
+            config,
+            sampler: Sampler::default(),
+            filter: AddressFilter::default(),
+        }
+    }
+
+    /// Packages in the sampled value of every frame, each in the mode of
+    /// its header.
+    pub fn decode_values(&self, values: &[f32]) -> Vec<Package> {
+        let (packages, _) = rate::decode_values(values);
+        packages
+            .into_iter()
+            .filter(|package| self.filter.accepts(package.header().dst))
+            .collect()
+    }
+
+    /// Packages in frames with one symbol each.
+    pub fn decode_frames(&self, frames: &[GrayImage]) -> Vec<Package> {
+        let values: Vec<f32> = frames
+            .iter()
+            .map(|frame| self.sampler.sample_image(frame).value)
+            .collect();
+        self.decode_values(&values)
+    }
+
+    /// Packages in a video file, sampled at the symbol instants.
+    #[cfg(feature = "ffmpeg")]
+    pub fn decode_video(&self, video_file: &str) -> Result<Vec<Package>, Error> {
+        let (samples, _) = read_video_timed(video_file, &self.sampler, self.config.fps as f64)
+            .map_err(|e| Error::Video(e.into()))?;
+        let values: Vec<f32> = samples.iter().map(|sample| sample.value).collect();
+        Ok(self.decode_values(&values))
+    }
+
+    /// Payload of the packages in a video file, an error if the file can't
+    /// be read or one of the packages is missing.
+    #[cfg(feature = "ffmpeg")]
+    pub fn receive_video(&self, video_file: &str) -> Result<Vec<u8>, Error> {
+        Ok(join(self.decode_video(video_file)?)?)
+    }
+
+    pub fn receive_frames(&self, frames: &[GrayImage]) -> Result<Vec<u8>, PackageError> {
+        join(self.decode_frames(frames))
+    }
+}
+
+/// Received black and white, the darkest and brightest levels held as long
+/// as the reference.
+pub(crate) fn reference_levels(values: &[f32]) -> (f32, f32) {
+    let means: Vec<f32> = values
+        .windows(REFERENCE_FRAMES.min(values.len()))
+        .map(|window| window.iter().sum::<f32>() / window.len() as f32)
+        .collect();
+    let black = means.iter().copied().fold(f32::MAX, f32::min);
+    let white = means.iter().copied().fold(f32::MIN, f32::max);
+    (black, white)
+}
+
+// The payload of the first run of packages numbered 0 to the one flagged
+// last without a gap. A repeated transmission starts over at 0, so a run
+// with a package lost is replaced by the next one.
+fn join(packages: Vec<Package>) -> Result<Vec<u8>, PackageError> {
+    let mut payload = Vec::new();
+    // sequence number expected next, `None` after a gap until the next 0
+    let mut next = Some(0);
+    let mut missing = None;
+    for package in packages {
+        let seq = package.header().seq as usize;
+        if seq == 0 {
+            payload.clear();
+            next = Some(0);
+        }
+        match next {
+            // the package before again
+            Some(next) if seq + 1 == next => continue,
+            Some(next) if seq == next => {}
+            _ => {
+                if let Some(next) = next {
+                    missing.get_or_insert(next);
+                }
+                next = None;
+                continue;
+            }
+        }
+        let last = package.header().is_last();
+        payload.extend(package.into_data());
+        if last {
+            return Ok(payload);
+        }
+        next = Some(seq + 1);
+    }
+    Err(PackageError::MissingPackage(
+        missing.or(next).unwrap_or_default(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transmitter::Transmitter;
+    use crate::util::coding::Coding;
+    use crate::util::modulation::Modulation;
+    use crate::util::rate::RateMode;
+    use crate::util::signal::Address;
+
+    fn packages(payload: &[u8]) -> Vec<Package> {
+        Package::split(payload).unwrap()
+    }
+
+    fn payload() -> Vec<u8> {
+        (0..700).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn join_in_order() {
+        assert_eq!(join(packages(&payload())), Ok(payload()));
+        assert_eq!(join(packages(&[])), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn join_missing_package() {
+        let mut sent = packages(&payload());
+        sent.remove(1);
+        assert_eq!(join(sent), Err(PackageError::MissingPackage(1)));
+    }
+
+    #[test]
+    fn join_without_last_package() {
+        let mut sent = packages(&payload());
+        sent.pop();
+        assert_eq!(join(sent), Err(PackageError::MissingPackage(2)));
+        assert_eq!(join(Vec::new()), Err(PackageError::MissingPackage(0)));
+    }
+
+    #[test]
+    fn join_repeated_transmission() {
+        // the first run lost a package, the second one is complete
+        let mut sent = packages(&payload());
+        sent.remove(1);
+        sent.extend(packages(&payload()));
+        // a package decoded twice
+        sent.insert(4, sent[3].clone());
+        assert_eq!(join(sent), Ok(payload()));
+    }
+
+    #[test]
+    fn decode_rendered_frames() {
+        let config = Config {
+            modulation: Modulation::Pam4,
+            coding: Coding::Hamming74,
+            ..Config::default()
+        };
+        let transmitter = Transmitter {
+            src: Address(1),
+            dst: Address(2),
+            ..Transmitter::new(config.clone())
+        };
+        let frames = transmitter.frames(&payload()).unwrap();
+
+        // the header says the mode, the receiver needs no config for it
+        let receiver = Receiver::new(Config::default());
+        let received = receiver.decode_frames(&frames);
+        assert_eq!(received.len(), 3);
+        for (i, package) in received.iter().enumerate() {
+            assert_eq!(package.header().seq, i as u8);
+            assert_eq!(package.header().src, Address(1));
+            assert_eq!(
+                RateMode::from_tag(package.header().mode),
+                Some(RateMode::of_config(&config))
+            );
+        }
+        assert_eq!(receiver.receive_frames(&frames), Ok(payload()));
+
+        let other = Receiver {
+            filter: AddressFilter::new(Address(3)),
+            ..Receiver::new(config)
+        };
+        assert!(other.decode_frames(&frames).is_empty());
+    }
+}
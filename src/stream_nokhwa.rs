@@ -1,19 +1,11 @@
-use std::{
-    fs,
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use bit_vec::BitVec;
+use std::time::Duration;
 
-//use image::{ImageBuffer, Rgb};
-use nokhwa::{
-    native_api_backend,
-    pixel_format::RgbFormat,
-    query,
-    utils::{
-        CameraFormat, CameraIndex, FrameFormat, RequestedFormat, RequestedFormatType, Resolution,
-    },
-    CallbackCamera,
-};
+use lightchannel::tools::locator::Locator;
+use lightchannel::tools::signal::decode_package;
+use lightchannel::tools::sink::open_sink;
+use lightchannel::tools::source::{FrameSource, NokhwaSource};
+use nokhwa::{native_api_backend, query};
 
 fn main() {
     nokhwa::nokhwa_initialize(|x| {
@@ -31,52 +23,45 @@ fn nokhwa_main() {
         println!("{device}");
     }
 
-    let index = CameraIndex::Index(0);
-    let resolution = Resolution::new(1280, 720);
-    let fourcc = FrameFormat::NV12;
     let fps = 30;
-    let camera_format = CameraFormat::new(resolution, fourcc, fps);
-    let requested = RequestedFormat::new::<RgbFormat>(RequestedFormatType::Exact(camera_format));
-    println!("Requested Format: {:?}", requested);
+    let mut source = NokhwaSource::new(0, 1280, 720, fps).unwrap();
+    // PNG frames by default, or any sink given on the command line
+    let sink_name = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "frames_nokhwa".to_string());
+    let mut sink = open_sink(&sink_name, fps as f64).unwrap();
 
-    //let mut previousImage: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(0, 0);
-    let frames = Arc::new(Mutex::new(Vec::new()));
-    let frames_clone = Arc::clone(&frames);
-    let mut cb = CallbackCamera::new(index, requested, move |frame| {
+    // record two seconds, locate the transmitter in the first second and
+    // sample it, the frames of the first second as well once located
+    let mut locator = Locator::for_camera(fps, 1.0);
+    let mut bits = BitVec::new();
+    while let Some(frame) = source.next_frame().unwrap() {
+        if frame.timestamp > Duration::from_secs(2) {
+            break;
+        }
         println!(
-            "Captured Single Frame of {} bytes, format: {:?}",
-            frame.buffer().len(),
-            frame.source_frame_format()
+            "Captured frame {}x{} at {:?}",
+            frame.image.width(),
+            frame.image.height(),
+            frame.timestamp
         );
-
-        let decoded = frame.decode_image::<RgbFormat>();
-        if decoded.is_err() {
-            println!("Error decoding frame");
-            return;
+        if !sink.write_frame(&frame).unwrap() {
+            break;
         }
-        let decoded = decoded.unwrap();
 
-        //previousImage = decoded.clone();
-        if decoded.width() == 0 || decoded.height() == 0 {
-            println!("Empty Frame");
-            return;
+        for sample in locator.push_and_sample(frame.luma()) {
+            bits.push(sample.bit);
         }
+    }
+    drop(source);
+    sink.finish().unwrap();
 
-        frames_clone.lock().unwrap().push(decoded.clone());
-    })
-    .unwrap();
-
-    cb.open_stream().unwrap();
-
-    std::thread::sleep(Duration::from_secs(2));
-
-    cb.stop_stream().unwrap();
-
-    let frames_dir = "frames_nokhwa";
-    fs::remove_dir_all(frames_dir).ok();
-    fs::create_dir_all(frames_dir).expect("Failed to create output directory");
-    for (i, frame) in frames.lock().unwrap().iter().enumerate() {
-        let frame_path = format!("{}/frame_{:04}.png", frames_dir, i);
-        frame.save(frame_path).unwrap();
+    match locator.roi() {
+        Some(roi) => {
+            println!("Transmitter at {:?}", roi);
+            println!("Received bits: {:?}", bits);
+            println!("Decoded Package: {:?}", decode_package(&bits));
+        }
+        None => println!("Transmitter not found"),
     }
 }
@@ -3,11 +3,10 @@
 /// https://github.com/twistedfall/opencv-rust/blob/master/INSTALL.md
 /// Source: https://github.com/twistedfall/opencv-rust/blob/master/examples/video_capture_http_stream.rs
 use std::collections::VecDeque;
-use std::io::{Cursor, Write};
+use std::io::Write;
 use std::net::{SocketAddr, TcpListener};
 
-use image::codecs::jpeg::JpegEncoder;
-use image::{imageops, ImageBuffer, Rgb, Rgba, RgbaImage};
+use image::{imageops, DynamicImage, Rgb, Rgba, RgbaImage};
 use opencv::core::{Mat, Vector, CV_8UC3};
 use opencv::imgcodecs::{imencode, IMWRITE_JPEG_QUALITY};
 use opencv::videoio::{VideoCapture, VideoCaptureTraitConst, CAP_ANY};
@@ -17,12 +16,13 @@ use opencv::{prelude::*, Result};
 use opencv::videoio::VideoCaptureTrait;
 
 use image::imageops::*;
-use mat2image::{bgr_buf_to_rgba_image, ToImage};
+use lightchannel::tools::sink::{FrameSink, MjpegSink};
+use lightchannel::tools::source::{open_source, Frame};
 
 const BASE_RESPONSE: &[u8] =
     b"HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary=frame\r\n\r\n";
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut image_backend = "image";
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 && (args[1] == "opencv" || args[1] == "image") {
@@ -39,9 +39,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
     println!("Lag frames: {}", lag_frames);
 
-    // Open the default camera
-    let mut cam = VideoCapture::new(0, CAP_ANY)?;
-    assert!(cam.is_opened()?, "Unable to open default camera!");
+    // The image backend reads any frame source, see util::source::open_source
+    let source_name = args.get(3).map(String::as_str).unwrap_or("camera");
 
     // Bind listener to a port
     let address: SocketAddr = "127.0.0.1:8080".parse()?;
@@ -52,12 +51,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let (mut stream, addr) = listener.accept()?;
     println!("Client connected: {}", addr);
 
-    // Write initial response
-    stream.write_all(BASE_RESPONSE)?;
-
-    let mut buffer = Mat::default(); // type: "CV_8UC3"
     match image_backend {
         "opencv" => {
+            // Write initial response
+            stream.write_all(BASE_RESPONSE)?;
+
+            // Open the default camera
+            let mut cam = VideoCapture::new(0, CAP_ANY)?;
+            assert!(cam.is_opened()?, "Unable to open default camera!");
+            let mut buffer = Mat::default(); // type: "CV_8UC3"
+
             let encode_params = Vector::from_slice(&[IMWRITE_JPEG_QUALITY, 80]);
             let mut formatted_bytes = Vector::default();
             let image_format = "jpeg";
@@ -125,16 +128,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
         "image" => {
+            let mut source = open_source(source_name)?;
+            let mut sink = MjpegSink::new(stream)?;
             let mut frame_buffer: VecDeque<RgbaImage> = VecDeque::with_capacity(lag_frames);
-            let image_format = "jpeg";
-            loop {
-                cam.read(&mut buffer)?;
-                if buffer.size()?.width <= 0 {
-                    continue;
-                }
-
-                let imb = buffer.as_image_buffer()?;
-                let rgba_image = bgr_buf_to_rgba_image(imb);
+            while let Some(frame) = source.next_frame()? {
+                let rgba_image = DynamicImage::ImageRgb8(frame.image).to_rgba8();
                 let mut out_img = rgba_image.clone();
 
                 // Apply motion visualization using the lagged frame
@@ -157,39 +155,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // Push the current frame into the buffer
                 frame_buffer.push_back(rgba_image.clone());
 
-                let out_img_rgb = rgba8_to_rgb8(out_img);
-                let jpeg_bytes = rgb_image_to_jpeg_bytes(out_img_rgb, 80);
-
-                let packet = {
-                    let header = format!(
-                        "--frame\r\nContent-Type: image/{}\r\nContent-Length: {}\r\n\r\n",
-                        image_format,
-                        jpeg_bytes.len()
-                    );
-                    [header.as_bytes(), jpeg_bytes.as_slice()].concat()
+                let out_frame = Frame {
+                    image: rgba8_to_rgb8(out_img),
+                    timestamp: frame.timestamp,
                 };
-                stream.write_all(&packet)?;
+                sink.write_frame(&out_frame)?;
             }
         }
         _ => {
             panic!("Invalid variant");
         }
     }
-}
-
-fn rgb_image_to_jpeg_bytes(image: ImageBuffer<Rgb<u8>, Vec<u8>>, quality: u8) -> Vec<u8> {
-    let mut jpeg_bytes: Vec<u8> = Vec::new();
-    let mut cursor = Cursor::new(&mut jpeg_bytes);
-    let mut encoder = JpegEncoder::new_with_quality(&mut cursor, quality);
-    encoder
-        .encode(
-            &image,
-            image.width(),
-            image.height(),
-            image::ExtendedColorType::Rgb8,
-        )
-        .unwrap();
-    jpeg_bytes
+    Ok(())
 }
 
 // Convert RGBA8 image to RGB8 image
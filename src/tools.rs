@@ -0,0 +1,92 @@
+// The building blocks of the library the binaries of this crate use,
+// module by module as in util. Not part of the API, anything here may
+// change any time.
+
+pub mod arq {
+    pub use crate::util::arq::{ArqMode, ArqReceiver, ArqSender};
+}
+
+pub mod back_channel {
+    pub use crate::util::back_channel::{memory_pair, BackChannel, Lossy};
+}
+
+pub mod channel {
+    pub use crate::util::channel::Channel;
+}
+
+pub mod complementary {
+    pub use crate::util::complementary::Complementary;
+}
+
+pub mod duplex {
+    pub use crate::util::duplex::{DuplexLink, DuplexMode, DuplexStats, Loopback};
+}
+
+pub mod layout {
+    pub use crate::util::layout::FrameLayout;
+}
+
+pub mod link {
+    pub use crate::util::link::link_sequence;
+}
+
+pub mod locator {
+    pub use crate::util::locator::Locator;
+}
+
+pub mod message {
+    pub use crate::util::message::{encode_message_as, try_decode_message};
+}
+
+#[cfg(feature = "opencv")]
+pub mod perspective {
+    pub use crate::util::perspective::{read_video_grid, Rectifier};
+}
+
+pub mod playback {
+    pub use crate::util::playback::{play, Pacer};
+}
+
+pub mod rate {
+    pub use crate::util::rate::{decode_values, frames, RateController};
+}
+
+pub mod rolling_shutter {
+    pub use crate::util::rolling_shutter::{calibration_pattern, RollingShutter};
+}
+
+pub mod signal {
+    pub use crate::util::signal::{
+        decode_package, decode_packages, encode_package, encode_package_with, get_preamble,
+        StreamDecoder, HEADER_LEN, PACKAGE_VERSION, PREAMBLE_LEN, TRAILER_LEN,
+    };
+}
+
+pub mod sink {
+    pub use crate::util::sink::{open_sink, FrameSink, MemorySink, MjpegSink};
+}
+
+pub mod source {
+    #[cfg(feature = "camera")]
+    pub use crate::util::source::NokhwaSource;
+    pub use crate::util::source::{open_source, Frame, FrameSource};
+}
+
+pub mod transfer {
+    pub use crate::util::transfer::{
+        file_messages, FileReceiver, TransferEvent, DEFAULT_CHUNK_SIZE,
+    };
+}
+
+#[cfg(feature = "ffmpeg")]
+pub mod video {
+    pub use crate::util::video::{
+        embed_video, encode_frames, encode_levels, read_video, read_video_complementary,
+        read_video_link, read_video_rolling_shutter, read_video_rows, read_video_watermark,
+        write_frames, write_levels, write_video, write_video_with_layout,
+    };
+}
+
+pub mod watermark {
+    pub use crate::util::watermark::Watermark;
+}
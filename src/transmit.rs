@@ -0,0 +1,104 @@
+/// Blink packages on screen in a fullscreen window.
+/// Usage: transmit [--text TEXT] [--fps FPS] [--refresh HZ] [--repeat N] [--out SINK] [--headless]
+/// SINK is "window" (default), "http://ADDRESS", a video file or a new or
+/// empty directory for PNG frames. The headless mode renders into memory and
+/// decodes the frames again.
+use lightchannel::tools::playback::{play, Pacer};
+use lightchannel::tools::sink::{open_sink, MemorySink};
+use lightchannel::{Config, Receiver, Transmitter};
+use std::process::exit;
+use std::str::FromStr;
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut text = String::from("https://github.com/patte");
+    let mut fps: u32 = 30;
+    let mut refresh_rate = None;
+    let mut repeat = 1;
+    let mut headless = false;
+    let mut out = String::from("window");
+
+    let args: Vec<String> = std::env::args().collect();
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (args[i].as_str(), value) {
+            ("--text", Some(value)) => text = value.clone(),
+            ("--fps", Some(value)) => fps = parse("--fps", value),
+            ("--refresh", Some(value)) => {
+                let hz: f64 = parse("--refresh", value);
+                if !hz.is_finite() || hz <= 0.0 {
+                    usage(&format!("Invalid refresh rate: {}", value));
+                }
+                refresh_rate = Some(hz);
+            }
+            ("--repeat", Some(value)) => repeat = parse("--repeat", value),
+            ("--out", Some(value)) => out = value.clone(),
+            ("--headless", _) => {
+                headless = true;
+                i += 1;
+                continue;
+            }
+            (arg, None) if arg.starts_with("--") => usage(&format!("Missing value for {}", arg)),
+            (arg, _) => usage(&format!("Invalid argument: {}", arg)),
+        }
+        i += 2;
+    }
+    if fps == 0 {
+        usage("fps must not be zero");
+    }
+
+    // the window is paced at the frame rate the receiver assumes
+    let config = Config {
+        fps,
+        ..Config::default()
+    };
+    let fps = fps as f64;
+    let package_frames = Transmitter::new(config.clone()).frames(text.as_bytes())?;
+    let frames: Vec<_> = (0..repeat).flat_map(|_| package_frames.clone()).collect();
+    println!(
+        "Transmitting {:?}: {} frames at {} fps",
+        text,
+        frames.len(),
+        fps
+    );
+
+    if headless {
+        let mut sink = MemorySink::default();
+        let stats = play(&frames, &mut Pacer::headless(fps), &mut sink)?;
+        println!("Rendered: {:?}", stats);
+
+        let luma: Vec<_> = sink.frames.iter().map(|frame| frame.luma()).collect();
+        let decoded = Receiver::new(config)
+            .receive_frames(&luma)
+            .expect("Failed to decode rendered frames");
+        println!("Decoded: {:?}", String::from_utf8_lossy(&decoded));
+        return Ok(());
+    }
+
+    let mut sink = open_sink(&out, fps)?;
+    // files are written as fast as possible
+    let mut pacer = if sink.realtime() {
+        Pacer::new(fps, refresh_rate)
+    } else {
+        Pacer::headless(fps)
+    };
+    let stats = play(&frames, &mut pacer, sink.as_mut())?;
+    sink.finish()?;
+
+    println!("Shown: {:?}", stats);
+    Ok(())
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("Error: {}", error);
+    eprintln!(
+        "Usage: transmit [--text TEXT] [--fps FPS] [--refresh HZ] [--repeat N] [--out SINK] [--headless]"
+    );
+    exit(2);
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage(&format!("Invalid number for {}: {}", flag, value)))
+}
@@ -0,0 +1,101 @@
+// Payload to frames.
+//
+// The payload is split into packages, every package follows a few idle
+// symbols. Its header is on-off keyed and uncoded and says in which mode
+// the data follows, here the modulation and coding of the config, see
+// util::rate. The frames start with a black and a white reference, so the
+// receiver can scale its thresholds to the received range.
+
+use crate::config::Config;
+#[cfg(feature = "ffmpeg")]
+use crate::error::Error;
+use crate::package::Package;
+use crate::util::rate::{self, RateMode};
+use crate::util::signal::{Address, PackageError};
+#[cfg(feature = "ffmpeg")]
+use crate::util::video::encode_levels;
+use image::{GrayImage, Luma};
+
+// idle symbols before and after every package
+pub(crate) const PADDING_SYMBOLS: usize = 3;
+// black and white frames at the start
+pub(crate) const REFERENCE_FRAMES: usize = 4;
+
+pub struct Transmitter {
+    pub config: Config,
+    /// Address of this transmitter
+    pub src: Address,
+    /// Receivers of the payloads, a device, a group or everyone
+    pub dst: Address,
+}
+
+impl Transmitter {
+    pub fn new(config: Config) -> Self {
+        Transmitter {
+            config,
+            src: Address::UNASSIGNED,
+            dst: Address::BROADCAST,
+        }
+    }
+
+    /// Mode of the data of every package.
+    pub fn mode(&self) -> RateMode {
+        RateMode::of_config(&self.config)
+    }
+
+    /// Gray level of every frame.
+    pub fn levels(&self, packages: &[Package]) -> Vec<u8> {
+        rate::levels(packages, self.mode())
+    }
+
+    /// Packages of a payload up to `Package::MAX_PAYLOAD` bytes, to `dst`.
+    pub fn packages(&self, payload: &[u8]) -> Result<Vec<Package>, PackageError> {
+        Ok(Package::split(payload)?
+            .into_iter()
+            .map(|package| package.addressed(self.src, self.dst))
+            .collect())
+    }
+
+    /// Frames of a payload up to `Package::MAX_PAYLOAD` bytes, to `dst`.
+    pub fn frames(&self, payload: &[u8]) -> Result<Vec<GrayImage>, PackageError> {
+        Ok(self.package_frames(&self.packages(payload)?))
+    }
+
+    /// Frames of the packages, e.g. one message each.
+    pub fn package_frames(&self, packages: &[Package]) -> Vec<GrayImage> {
+        level_frames(
+            &self.levels(packages),
+            self.config.width,
+            self.config.height,
+        )
+    }
+
+    /// Encode the frames as video file with ffmpeg.
+    #[cfg(feature = "ffmpeg")]
+    pub fn write_video(&self, payload: &[u8], output_video: &str) -> Result<(), Error> {
+        self.write_packages(&self.packages(payload)?, output_video)
+    }
+
+    /// Encode the frames of the packages as video file, every frame is
+    /// rendered just before it is encoded.
+    #[cfg(feature = "ffmpeg")]
+    pub fn write_packages(&self, packages: &[Package], output_video: &str) -> Result<(), Error> {
+        encode_levels(
+            &self.levels(packages),
+            &self.config.fps.to_string(),
+            self.config.width,
+            self.config.height,
+            output_video,
+            false,
+        )
+        .map_err(Error::Video)
+    }
+}
+
+/// Flat frames of the given gray levels.
+pub fn level_frames(levels: &[u8], width: u32, height: u32) -> Vec<GrayImage> {
+    levels
+        .iter()
+        .map(|value| GrayImage::from_pixel(width, height, Luma([*value])))
+        .collect()
+}
@@ -0,0 +1,512 @@
+// Reliable delivery over a lossy link.
+//
+// Every payload is sent as numbered `Data`, the receiver acknowledges every
+// sequence number it gets on the back-channel and delivers the payloads in
+// order. The sender retransmits what isn't acknowledged in time. With stop
+// and wait only one payload is under way at a time; selective repeat keeps
+// a window of them in flight, the receiver buffers what arrives out of
+// order and asks for the gaps with a `Nack`, so only the lost payloads are
+// sent again.
+
+use crate::util::back_channel::BackChannel;
+use crate::util::message::Message;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArqMode {
+    StopAndWait,
+    /// Up to `window` payloads in flight
+    SelectiveRepeat {
+        window: u32,
+    },
+}
+
+impl ArqMode {
+    /// Stop and wait for a window of 1.
+    pub fn with_window(window: u32) -> Self {
+        match window {
+            0 | 1 => ArqMode::StopAndWait,
+            window => ArqMode::SelectiveRepeat { window },
+        }
+    }
+
+    pub fn window(&self) -> u32 {
+        match self {
+            ArqMode::StopAndWait => 1,
+            ArqMode::SelectiveRepeat { window } => *window,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ArqError {
+    Io(io::Error),
+    /// No acknowledgement after all retries
+    Timeout {
+        seq: u32,
+    },
+}
+
+impl fmt::Display for ArqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArqError::Io(e) => write!(f, "{}", e),
+            ArqError::Timeout { seq } => write!(f, "No acknowledgement for {}", seq),
+        }
+    }
+}
+
+impl std::error::Error for ArqError {}
+
+impl From<io::Error> for ArqError {
+    fn from(e: io::Error) -> Self {
+        ArqError::Io(e)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ArqStats {
+    /// Data messages sent, including retransmissions
+    pub sent: usize,
+    pub retransmitted: usize,
+    pub acks: usize,
+    pub nacks: usize,
+    pub duration: Duration,
+}
+
+/// A payload to send, for the first time or again.
+#[derive(Debug, PartialEq)]
+pub struct Outgoing {
+    pub seq: u32,
+    pub data: Vec<u8>,
+    /// Times it was sent before
+    pub retries: u32,
+}
+
+// a payload waiting for its acknowledgement
+struct InFlight<T> {
+    data: Vec<u8>,
+    // sent again from then on
+    due: T,
+    retries: u32,
+}
+
+/// The selective repeat sender without a clock or a link: it decides what
+/// to send next at a time the caller gives, `Instant`s for `ArqSender`,
+/// frame ticks for util::duplex.
+pub struct SendWindow<T> {
+    // not sent yet
+    queue: VecDeque<Vec<u8>>,
+    next_seq: u32,
+    in_flight: BTreeMap<u32, InFlight<T>>,
+}
+
+impl<T: Copy + Ord> SendWindow<T> {
+    pub fn new(first_seq: u32) -> Self {
+        SendWindow {
+            queue: VecDeque::new(),
+            next_seq: first_seq,
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: Vec<u8>) {
+        self.queue.push_back(data);
+    }
+
+    /// Everything pushed was acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Sequence number of the next payload pushed.
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq + self.queue.len() as u32
+    }
+
+    /// Payload to send at `now`: one whose acknowledgement is overdue, else
+    /// a new one if it is within `window` of the oldest unacknowledged one.
+    /// Without an acknowledgement it is due again at `due`.
+    pub fn next(&mut self, window: u32, now: T, due: T) -> Option<Outgoing> {
+        if let Some((&seq, flight)) = self.in_flight.iter_mut().find(|(_, f)| f.due <= now) {
+            flight.due = due;
+            flight.retries += 1;
+            return Some(Outgoing {
+                seq,
+                data: flight.data.clone(),
+                retries: flight.retries,
+            });
+        }
+        let base = self
+            .in_flight
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.next_seq);
+        if self.next_seq - base >= window.max(1) {
+            return None;
+        }
+        let data = self.queue.pop_front()?;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.insert(
+            seq,
+            InFlight {
+                data: data.clone(),
+                due,
+                retries: 0,
+            },
+        );
+        Some(Outgoing {
+            seq,
+            data,
+            retries: 0,
+        })
+    }
+
+    pub fn ack(&mut self, seq: u32) {
+        self.in_flight.remove(&seq);
+    }
+
+    /// Acknowledges everything before `seq`.
+    pub fn ack_before(&mut self, seq: u32) {
+        self.in_flight.retain(|in_flight, _| *in_flight >= seq);
+    }
+
+    /// The payload `seq` was lost, it is sent again from `now` on.
+    pub fn nack(&mut self, seq: u32, now: T) {
+        if let Some(flight) = self.in_flight.get_mut(&seq) {
+            flight.due = now;
+        }
+    }
+
+    /// When the next unacknowledged payload is due again.
+    pub fn next_due(&self) -> Option<T> {
+        self.in_flight.values().map(|flight| flight.due).min()
+    }
+}
+
+pub struct ArqSender {
+    pub mode: ArqMode,
+    /// Time to wait for an acknowledgement before sending again
+    pub timeout: Duration,
+    pub max_retries: u32,
+    next_seq: u32,
+}
+
+impl ArqSender {
+    pub fn new(mode: ArqMode, timeout: Duration) -> Self {
+        ArqSender {
+            mode,
+            timeout,
+            max_retries: 10,
+            next_seq: 0,
+        }
+    }
+
+    /// Send the payloads and wait until all are acknowledged. `link` sends
+    /// to the receiver and receives its acknowledgements.
+    pub fn send(
+        &mut self,
+        payloads: &[Vec<u8>],
+        link: &mut dyn BackChannel,
+    ) -> Result<ArqStats, ArqError> {
+        let mut stats = ArqStats::default();
+        let started = Instant::now();
+        let mut window = SendWindow::new(self.next_seq);
+        for payload in payloads {
+            window.push(payload.clone());
+        }
+
+        while !window.is_idle() {
+            // what is overdue and what fits into the window
+            let now = Instant::now();
+            while let Some(outgoing) = window.next(self.mode.window(), now, now + self.timeout) {
+                if outgoing.retries > self.max_retries {
+                    return Err(ArqError::Timeout { seq: outgoing.seq });
+                }
+                if outgoing.retries > 0 {
+                    stats.retransmitted += 1;
+                }
+                send_data(link, outgoing.seq, &outgoing.data, &mut stats)?;
+            }
+
+            let deadline = window.next_due().unwrap_or(now);
+            match link.recv_timeout(deadline.saturating_duration_since(Instant::now()))? {
+                Some(Message::Ack { id }) => {
+                    stats.acks += 1;
+                    window.ack(id);
+                }
+                Some(Message::Nack { id }) => {
+                    stats.nacks += 1;
+                    // sent again right away
+                    window.nack(id, Instant::now());
+                }
+                _ => {}
+            }
+        }
+
+        self.next_seq = window.next_seq();
+        stats.duration = started.elapsed();
+        Ok(stats)
+    }
+}
+
+fn send_data(
+    link: &mut dyn BackChannel,
+    seq: u32,
+    data: &[u8],
+    stats: &mut ArqStats,
+) -> io::Result<()> {
+    stats.sent += 1;
+    link.send(&Message::Data {
+        seq,
+        ack: None,
+        data: data.to_vec(),
+    })
+}
+
+pub struct ArqReceiver {
+    pub mode: ArqMode,
+    // next sequence number to deliver
+    expected: u32,
+    // received out of order
+    buffer: BTreeMap<u32, Vec<u8>>,
+    // gaps already asked for
+    nacked: BTreeSet<u32>,
+}
+
+impl ArqReceiver {
+    pub fn new(mode: ArqMode) -> Self {
+        ArqReceiver {
+            mode,
+            expected: 0,
+            buffer: BTreeMap::new(),
+            nacked: BTreeSet::new(),
+        }
+    }
+
+    /// Sequence number of the next payload in order, everything before it
+    /// was delivered.
+    pub fn expected(&self) -> u32 {
+        self.expected
+    }
+
+    /// Handle a message from the link, answers go to `back`. Returns the
+    /// payloads that are complete in order, other messages are ignored.
+    pub fn push(
+        &mut self,
+        message: Message,
+        back: &mut dyn BackChannel,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let (seq, data) = match message {
+            Message::Data { seq, data, .. } => (seq, data),
+            _ => return Ok(Vec::new()),
+        };
+        // beyond the window of the sender, it can't have sent this yet
+        if seq >= self.expected.saturating_add(self.mode.window()) {
+            return Ok(Vec::new());
+        }
+        // acknowledged again, the first acknowledgement may have been lost
+        back.send(&Message::Ack { id: seq })?;
+        if seq < self.expected {
+            return Ok(Vec::new());
+        }
+        self.buffer.insert(seq, data);
+
+        for missing in self.expected..seq {
+            if !self.buffer.contains_key(&missing) && self.nacked.insert(missing) {
+                back.send(&Message::Nack { id: missing })?;
+            }
+        }
+
+        let mut delivered = Vec::new();
+        while let Some(data) = self.buffer.remove(&self.expected) {
+            delivered.push(data);
+            self.nacked.remove(&self.expected);
+            self.expected += 1;
+        }
+        Ok(delivered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::back_channel::memory_pair;
+
+    // acknowledges every payload it gets, except the first copy of the
+    // dropped ones or all when closed, and keeps track of the payloads under
+    // way
+    #[derive(Default)]
+    struct Scripted {
+        dropped: BTreeSet<u32>,
+        closed: bool,
+        acks: VecDeque<Message>,
+        sent: Vec<u32>,
+        unacked: BTreeSet<u32>,
+        max_unacked: usize,
+    }
+
+    impl BackChannel for Scripted {
+        fn send(&mut self, message: &Message) -> io::Result<()> {
+            if let Message::Data { seq, .. } = message {
+                self.sent.push(*seq);
+                self.unacked.insert(*seq);
+                self.max_unacked = self.max_unacked.max(self.unacked.len());
+                if !self.dropped.remove(seq) && !self.closed {
+                    self.acks.push_back(Message::Ack { id: *seq });
+                }
+            }
+            Ok(())
+        }
+
+        fn recv_timeout(&mut self, _timeout: Duration) -> io::Result<Option<Message>> {
+            let message = self.acks.pop_front();
+            if let Some(Message::Ack { id }) = &message {
+                self.unacked.remove(id);
+            }
+            Ok(message)
+        }
+    }
+
+    fn payloads(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i; 3]).collect()
+    }
+
+    fn send(mode: ArqMode, link: &mut Scripted) -> ArqStats {
+        ArqSender::new(mode, Duration::from_millis(1))
+            .send(&payloads(10), link)
+            .unwrap()
+    }
+
+    #[test]
+    fn stop_and_wait() {
+        let mut link = Scripted::default();
+        let stats = send(ArqMode::StopAndWait, &mut link);
+        assert_eq!(link.sent, (0..10).collect::<Vec<_>>());
+        assert_eq!(link.max_unacked, 1);
+        assert_eq!(stats.acks, 10);
+    }
+
+    #[test]
+    fn window_in_flight() {
+        let mut link = Scripted::default();
+        let stats = send(ArqMode::with_window(4), &mut link);
+        assert_eq!(link.sent, (0..10).collect::<Vec<_>>());
+        assert_eq!(link.max_unacked, 4);
+        assert_eq!(stats.retransmitted, 0);
+    }
+
+    #[test]
+    fn window_retransmits_lost_payloads() {
+        let mut link = Scripted {
+            dropped: [2, 5].into(),
+            ..Scripted::default()
+        };
+        let stats = send(ArqMode::with_window(4), &mut link);
+        assert_eq!(stats.retransmitted, 2);
+        assert_eq!(link.sent.len(), 12);
+        assert!(link.max_unacked <= 4);
+        assert!(link.unacked.is_empty());
+    }
+
+    #[test]
+    fn sender_gives_up() {
+        let mut link = Scripted {
+            closed: true,
+            ..Scripted::default()
+        };
+        let mut sender = ArqSender::new(ArqMode::StopAndWait, Duration::from_millis(1));
+        sender.max_retries = 2;
+        assert!(matches!(
+            sender.send(&payloads(1), &mut link),
+            Err(ArqError::Timeout { seq: 0 })
+        ));
+        assert_eq!(link.sent, [0, 0, 0]);
+    }
+
+    #[test]
+    fn send_window() {
+        let mut window = SendWindow::new(7);
+        for i in 0..4 {
+            window.push(vec![i]);
+        }
+        assert_eq!(window.next_seq(), 11);
+        let outgoing = |seq, retries| {
+            Some(Outgoing {
+                seq,
+                data: vec![(seq - 7) as u8],
+                retries,
+            })
+        };
+        assert_eq!(window.next(2, 0, 10), outgoing(7, 0));
+        assert_eq!(window.next(2, 0, 10), outgoing(8, 0));
+        assert_eq!(window.next(2, 0, 10), None);
+        // the window starts at the oldest payload not acknowledged
+        window.ack(8);
+        assert_eq!(window.next(2, 5, 15), None);
+        assert_eq!(window.next_due(), Some(10));
+        assert_eq!(window.next(2, 10, 20), outgoing(7, 1));
+        window.ack_before(8);
+        assert_eq!(window.next(2, 11, 21), outgoing(9, 0));
+        assert_eq!(window.next(2, 11, 21), outgoing(10, 0));
+        // lost, sent again before its time is up
+        window.nack(9, 12);
+        assert_eq!(window.next(2, 12, 22), outgoing(9, 1));
+        window.ack_before(11);
+        assert!(window.is_idle());
+        assert_eq!(window.next(2, 13, 23), None);
+    }
+
+    fn data(seq: u32) -> Message {
+        Message::Data {
+            seq,
+            ack: None,
+            data: vec![seq as u8],
+        }
+    }
+
+    fn answers(end: &mut dyn BackChannel) -> Vec<Message> {
+        std::iter::from_fn(|| end.recv_timeout(Duration::ZERO).unwrap()).collect()
+    }
+
+    #[test]
+    fn receiver_buffers_out_of_order() {
+        let (mut back, mut sender_end) = memory_pair();
+        let mut receiver = ArqReceiver::new(ArqMode::with_window(4));
+        assert!(receiver.push(data(1), &mut back).unwrap().is_empty());
+        assert_eq!(
+            answers(&mut sender_end),
+            [Message::Ack { id: 1 }, Message::Nack { id: 0 }]
+        );
+        assert_eq!(
+            receiver.push(data(0), &mut back).unwrap(),
+            [vec![0], vec![1]]
+        );
+        assert_eq!(answers(&mut sender_end), [Message::Ack { id: 0 }]);
+        assert_eq!(receiver.expected(), 2);
+    }
+
+    #[test]
+    fn receiver_window() {
+        let (mut back, mut sender_end) = memory_pair();
+        let mut receiver = ArqReceiver::new(ArqMode::with_window(4));
+        // beyond the window, not acknowledged
+        assert!(receiver.push(data(4), &mut back).unwrap().is_empty());
+        assert!(answers(&mut sender_end).is_empty());
+        // delivered once, acknowledged again
+        assert_eq!(receiver.push(data(0), &mut back).unwrap(), [vec![0]]);
+        assert!(receiver.push(data(0), &mut back).unwrap().is_empty());
+        assert_eq!(
+            answers(&mut sender_end),
+            [Message::Ack { id: 0 }, Message::Ack { id: 0 }]
+        );
+        // the window moved on
+        assert!(receiver.push(data(4), &mut back).unwrap().is_empty());
+        assert_eq!(answers(&mut sender_end)[0], Message::Ack { id: 4 });
+    }
+}
@@ -0,0 +1,345 @@
+// The path from the receiver back to the transmitter.
+//
+// Light only goes from the screen to the camera, but the receiver can
+// usually answer some other way: a TCP connection, a pipe to another
+// process, or a second screen and camera pointing the other way. All of
+// them carry messages and are interchangeable for the reliability layer,
+// see util::arq. Streams frame every message with its length, the light
+// link sends every message as one package.
+
+use crate::config::Config;
+use crate::package::Package;
+use crate::transmitter::Transmitter;
+use crate::util::locator::Locator;
+use crate::util::message::{decode_message, encode_message, try_decode_message, Message};
+use crate::util::playback::{play, Pacer};
+use crate::util::signal::StreamDecoder;
+use crate::util::sink::FrameSink;
+use crate::util::source::FrameSource;
+use bit_vec::BitVec;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait BackChannel {
+    fn send(&mut self, message: &Message) -> io::Result<()>;
+
+    /// Next message from the other end, `None` if none arrived in time.
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>>;
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "Back-channel closed")
+}
+
+fn recv_message(messages: &Receiver<Message>, timeout: Duration) -> io::Result<Option<Message>> {
+    match messages.recv_timeout(timeout) {
+        Ok(message) => Ok(Some(message)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => Err(closed()),
+    }
+}
+
+/// Messages over a byte stream, each with a 2 byte length in front.
+pub struct StreamBackChannel {
+    writer: Box<dyn Write + Send>,
+    // filled by a thread reading the stream
+    messages: Receiver<Message>,
+}
+
+impl StreamBackChannel {
+    pub fn new(reader: impl Read + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        let (sender, messages) = channel();
+        thread::spawn(move || read_messages(reader, sender));
+        StreamBackChannel {
+            writer: Box::new(writer),
+            messages,
+        }
+    }
+
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(StreamBackChannel::new(stream.try_clone()?, stream))
+    }
+
+    pub fn connect(address: &str) -> io::Result<Self> {
+        StreamBackChannel::tcp(TcpStream::connect(address)?)
+    }
+
+    /// Listen on `address` and wait for the other end.
+    pub fn accept(address: &str) -> io::Result<Self> {
+        StreamBackChannel::accept_on(&TcpListener::bind(address)?)
+    }
+
+    /// Wait for the other end on a bound listener, e.g. on a port picked by
+    /// the OS.
+    pub fn accept_on(listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        StreamBackChannel::tcp(stream)
+    }
+
+    /// Standard input and output, e.g. of a process started by the other end.
+    pub fn stdio() -> Self {
+        StreamBackChannel::new(io::stdin(), io::stdout())
+    }
+}
+
+// until the stream ends, messages that don't decode are skipped
+fn read_messages(mut reader: impl Read, messages: Sender<Message>) {
+    loop {
+        let mut len = [0; 2];
+        if reader.read_exact(&mut len).is_err() {
+            return;
+        }
+        let mut bytes = vec![0; u16::from_be_bytes(len) as usize];
+        if reader.read_exact(&mut bytes).is_err() {
+            return;
+        }
+        if let Some(message) = try_decode_message(&BitVec::from_bytes(&bytes)) {
+            if messages.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl BackChannel for StreamBackChannel {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        let bytes = encode_message(message).to_bytes();
+        let len = u16::try_from(bytes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Message too large"))?;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        recv_message(&self.messages, timeout)
+    }
+}
+
+/// One end of a link within the process, e.g. for simulations.
+pub struct MemoryBackChannel {
+    sender: Sender<Message>,
+    messages: Receiver<Message>,
+}
+
+/// Two connected ends.
+pub fn memory_pair() -> (MemoryBackChannel, MemoryBackChannel) {
+    let (a_sender, b_messages) = channel();
+    let (b_sender, a_messages) = channel();
+    (
+        MemoryBackChannel {
+            sender: a_sender,
+            messages: a_messages,
+        },
+        MemoryBackChannel {
+            sender: b_sender,
+            messages: b_messages,
+        },
+    )
+}
+
+impl BackChannel for MemoryBackChannel {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.sender.send(message.clone()).map_err(|_| closed())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        recv_message(&self.messages, timeout)
+    }
+}
+
+/// Sends on one link and receives on another, e.g. data over light and the
+/// acknowledgements over TCP.
+pub struct Split<F, B> {
+    pub forward: F,
+    pub back: B,
+}
+
+impl<F: BackChannel, B: BackChannel> BackChannel for Split<F, B> {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.forward.send(message)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        self.back.recv_timeout(timeout)
+    }
+}
+
+/// Loses sent messages at random, to test the reliability layer.
+pub struct Lossy<C> {
+    pub inner: C,
+    /// Probability of a message to be lost
+    pub drop_rate: f32,
+    rng: StdRng,
+}
+
+impl<C> Lossy<C> {
+    pub fn new(inner: C, drop_rate: f32, seed: u64) -> Self {
+        Lossy {
+            inner,
+            drop_rate,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl<C: BackChannel> BackChannel for Lossy<C> {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        if self.rng.gen::<f32>() < self.drop_rate {
+            return Ok(());
+        }
+        self.inner.send(message)
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        self.inner.recv_timeout(timeout)
+    }
+}
+
+/// A screen and a camera: messages are shown as packages on the sink and
+/// read from the source, on-off keyed without coding as in `receive`.
+pub struct LightBackChannel {
+    transmitter: Transmitter,
+    sink: Box<dyn FrameSink>,
+    source: Box<dyn FrameSource>,
+    locator: Locator,
+    decoder: StreamDecoder,
+    // sampled, not decoded yet
+    bits: VecDeque<bool>,
+}
+
+impl LightBackChannel {
+    pub fn new(sink: Box<dyn FrameSink>, source: Box<dyn FrameSource>, fps: u32) -> Self {
+        LightBackChannel {
+            transmitter: Transmitter::new(Config {
+                fps,
+                ..Config::default()
+            }),
+            sink,
+            source,
+            locator: Locator::for_camera(fps, 1.0),
+            decoder: StreamDecoder::new(),
+            bits: VecDeque::new(),
+        }
+    }
+}
+
+impl BackChannel for LightBackChannel {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        let package = Package::new(encode_message(message).to_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let frames = self.transmitter.package_frames(&[package]);
+        let fps = self.transmitter.config.fps as f64;
+        let mut pacer = if self.sink.realtime() {
+            Pacer::new(fps, None)
+        } else {
+            Pacer::headless(fps)
+        };
+        play(&frames, &mut pacer, self.sink.as_mut())
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let frame = match self.source.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Err(closed()),
+                Err(e) => return Err(io::Error::other(e.to_string())),
+            };
+            let samples = self.locator.push_and_sample(frame.luma());
+            self.bits.extend(samples.iter().map(|sample| sample.bit));
+            // the bits after a message stay for the next call
+            while let Some(bit) = self.bits.pop_front() {
+                if let Some(package) = self.decoder.push(bit) {
+                    if let Ok(message) = decode_message(&package) {
+                        return Ok(Some(message));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::arq::{ArqMode, ArqReceiver, ArqSender};
+
+    fn payloads() -> Vec<Vec<u8>> {
+        (0..8).map(|i| vec![i; 40]).collect()
+    }
+
+    // delivers the payloads the sender gets through, until it goes quiet
+    fn receive(link: &mut dyn BackChannel, mode: ArqMode) -> io::Result<Vec<Vec<u8>>> {
+        let mut receiver = ArqReceiver::new(mode);
+        let mut delivered = Vec::new();
+        while let Some(message) = link.recv_timeout(Duration::from_secs(2))? {
+            delivered.extend(receiver.push(message, link)?);
+            if delivered.len() == payloads().len() {
+                break;
+            }
+        }
+        Ok(delivered)
+    }
+
+    #[test]
+    fn arq_over_tcp() {
+        let mode = ArqMode::with_window(4);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let receiver = thread::spawn(move || {
+            let mut link = StreamBackChannel::accept_on(&listener)?;
+            receive(&mut link, mode)
+        });
+
+        let mut link = StreamBackChannel::connect(&address).unwrap();
+        let stats = ArqSender::new(mode, Duration::from_millis(500))
+            .send(&payloads(), &mut link)
+            .unwrap();
+        assert_eq!(stats.acks, payloads().len());
+        assert_eq!(receiver.join().unwrap().unwrap(), payloads());
+    }
+
+    #[test]
+    fn stream_skips_undecodable_messages() {
+        // garbage with its length, then a message
+        let mut bytes = vec![0, 2, 0xff, 0xff];
+        let encoded = encode_message(&Message::Ack { id: 3 }).to_bytes();
+        bytes.extend((encoded.len() as u16).to_be_bytes());
+        bytes.extend(encoded);
+        let mut reader = StreamBackChannel::new(io::Cursor::new(bytes), io::sink());
+        assert_eq!(
+            reader.recv_timeout(Duration::from_secs(1)).unwrap(),
+            Some(Message::Ack { id: 3 })
+        );
+        // the stream ended
+        assert!(reader.recv_timeout(Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn split_links() {
+        let (forward, mut far_end) = memory_pair();
+        let (mut near_end, back) = memory_pair();
+        let mut split = Split { forward, back };
+        split.send(&Message::Ping { id: 1 }).unwrap();
+        near_end.send(&Message::Ack { id: 1 }).unwrap();
+        assert_eq!(
+            far_end.recv_timeout(Duration::ZERO).unwrap(),
+            Some(Message::Ping { id: 1 })
+        );
+        assert_eq!(
+            split.recv_timeout(Duration::ZERO).unwrap(),
+            Some(Message::Ack { id: 1 })
+        );
+    }
+}
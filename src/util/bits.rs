@@ -0,0 +1,433 @@
+// Bit buffers for the package path.
+//
+// `BitVec` stores its bits in words but only hands them out one at a time,
+// so reading a byte at some bit offset walks an iterator and every slice is
+// a copy. `BitReader` reads straight from bytes, most significant bit first
+// like `BitVec::to_bytes`: up to 64 bits at any offset with one load, a
+// search for a sync word that checks every offset of a loaded word, and
+// slices that borrow instead of copy. `BitWriter` appends bits and fields
+// of any width to a byte buffer for it.
+
+use bit_vec::BitVec;
+use std::borrow::Cow;
+
+/// Bits being written, most significant bit of every byte first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Room for `bits` bits without reallocating.
+    pub fn with_capacity(bits: usize) -> Self {
+        BitWriter {
+            bytes: Vec::with_capacity(bits.div_ceil(8)),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, bit: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    /// The lowest `count` bits of `value`, the highest of them first.
+    pub fn push_bits(&mut self, value: u64, count: usize) {
+        assert!(count <= 64, "Too many bits: {}", count);
+        let mut left = count;
+        while left > 0 {
+            let used = self.len % 8;
+            if used == 0 {
+                self.bytes.push(0);
+            }
+            let take = (8 - used).min(left);
+            let chunk = (value >> (left - take)) as u8 & (0xff >> (8 - take));
+            *self.bytes.last_mut().unwrap() |= chunk << (8 - used - take);
+            self.len += take;
+            left -= take;
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        if self.len.is_multiple_of(8) {
+            self.bytes.extend_from_slice(bytes);
+            self.len += bytes.len() * 8;
+        } else {
+            for byte in bytes {
+                self.push_bits(*byte as u64, 8);
+            }
+        }
+    }
+
+    /// Appends the bits of `bits`, a word at a time.
+    pub fn extend(&mut self, bits: &BitReader) {
+        let mut pos = 0;
+        while pos < bits.len() {
+            let count = (bits.len() - pos).min(64);
+            self.push_bits(bits.read_bits(pos, count).unwrap(), count);
+            pos += count;
+        }
+    }
+
+    /// Drops the first `count` bytes, the bits after them move to the front.
+    pub fn discard_bytes(&mut self, count: usize) {
+        let count = count.min(self.bytes.len());
+        self.bytes.drain(..count);
+        self.len = self.len.saturating_sub(count * 8);
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.len = 0;
+    }
+
+    pub fn reader(&self) -> BitReader<'_> {
+        BitReader::new(&self.bytes, self.len)
+    }
+
+    pub fn into_bitvec(self) -> BitVec {
+        let mut bits = BitVec::from_bytes(&self.bytes);
+        bits.truncate(self.len);
+        bits
+    }
+}
+
+/// Bits to read in borrowed bytes, a view of `len` bits from a bit offset.
+#[derive(Clone, Copy, Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    // bit offset of the view in `bytes`
+    start: usize,
+    len: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// The first `len` bits of `bytes`.
+    pub fn new(bytes: &'a [u8], len: usize) -> Self {
+        assert!(
+            len <= bytes.len() * 8,
+            "{} bits in {} bytes",
+            len,
+            bytes.len()
+        );
+        BitReader {
+            bytes,
+            start: 0,
+            len,
+        }
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        BitReader::new(bytes, bytes.len() * 8)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bit(&self, pos: usize) -> bool {
+        assert!(pos < self.len, "Bit {} out of {}", pos, self.len);
+        let offset = self.start + pos;
+        self.bytes[offset / 8] & (0x80 >> (offset % 8)) != 0
+    }
+
+    /// `count` bits from `pos` in the lowest bits, the first one highest.
+    /// `None` past the end.
+    pub fn read_bits(&self, pos: usize, count: usize) -> Option<u64> {
+        assert!(count <= 64, "Too many bits: {}", count);
+        if pos + count > self.len {
+            return None;
+        }
+        if count == 0 {
+            return Some(0);
+        }
+        Some(self.word(pos) >> (64 - count))
+    }
+
+    pub fn read_u8(&self, pos: usize) -> Option<u8> {
+        self.read_bits(pos, 8).map(|byte| byte as u8)
+    }
+
+    // the 64 bits from `pos` with the first one highest, zeros past the
+    // bytes, the bytes after the view aren't masked
+    fn word(&self, pos: usize) -> u64 {
+        let offset = self.start + pos;
+        let first = offset / 8;
+        let mut buffer = [0u8; 9];
+        let end = (first + 9).min(self.bytes.len());
+        if first < end {
+            buffer[..end - first].copy_from_slice(&self.bytes[first..end]);
+        }
+        let shift = offset % 8;
+        let high = u64::from_be_bytes(buffer[..8].try_into().unwrap());
+        if shift == 0 {
+            high
+        } else {
+            high << shift | (buffer[8] >> (8 - shift)) as u64
+        }
+    }
+
+    /// Position of the first `count` bits equal to the lowest bits of
+    /// `pattern` at or after `from`. Every load of a word checks all the
+    /// offsets the pattern fits in.
+    pub fn find(&self, pattern: u64, count: usize, from: usize) -> Option<usize> {
+        assert!(
+            (1..=32).contains(&count),
+            "Pattern of {} bits, 1 to 32 are searched",
+            count
+        );
+        let mask = u64::MAX >> (64 - count);
+        let pattern = pattern & mask;
+        let offsets = 64 - count + 1;
+        let mut pos = from;
+        while pos + count <= self.len {
+            let word = self.word(pos);
+            let fitting = offsets.min(self.len - count + 1 - pos);
+            for k in 0..fitting {
+                if (word >> (64 - count - k)) & mask == pattern {
+                    return Some(pos + k);
+                }
+            }
+            pos += fitting;
+        }
+        None
+    }
+
+    /// `len` bits from `pos`, without copying.
+    pub fn slice(&self, pos: usize, len: usize) -> BitReader<'a> {
+        assert!(
+            pos + len <= self.len,
+            "Slice {}..{} out of {}",
+            pos,
+            pos + len,
+            self.len
+        );
+        BitReader {
+            bytes: self.bytes,
+            start: self.start + pos,
+            len,
+        }
+    }
+
+    /// The bits as bytes like `BitVec::to_bytes`, the last one filled up
+    /// with zeros. Borrowed when the view starts and ends on a byte.
+    pub fn to_bytes(self) -> Cow<'a, [u8]> {
+        if self.start.is_multiple_of(8) && self.len.is_multiple_of(8) {
+            let first = self.start / 8;
+            return Cow::Borrowed(&self.bytes[first..first + self.len / 8]);
+        }
+        let mut bytes = Vec::with_capacity(self.len.div_ceil(8));
+        let mut pos = 0;
+        while pos < self.len {
+            let count = (self.len - pos).min(64);
+            let word = self.read_bits(pos, count).unwrap() << (64 - count);
+            bytes.extend_from_slice(&word.to_be_bytes()[..count.div_ceil(8)]);
+            pos += count;
+        }
+        Cow::Owned(bytes)
+    }
+
+    pub fn to_bitvec(self) -> BitVec {
+        let mut bits = BitVec::from_bytes(&self.to_bytes());
+        bits.truncate(self.len);
+        bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn bitvec(text: &str) -> BitVec {
+        text.chars().map(|c| c == '1').collect()
+    }
+
+    fn writer(text: &str) -> BitWriter {
+        let mut bits = BitWriter::new();
+        for c in text.chars() {
+            bits.push(c == '1');
+        }
+        bits
+    }
+
+    #[test]
+    fn push_bits_across_bytes() {
+        let mut bits = BitWriter::new();
+        bits.push_bits(0b101, 3);
+        bits.push_bits(0xabcd, 16);
+        bits.push_bits(0, 0);
+        bits.push_bits(u64::MAX, 64);
+        assert_eq!(bits.len(), 83);
+        let mut expected = bitvec("101");
+        expected.extend(BitVec::from_bytes(&[0xab, 0xcd]));
+        expected.extend(std::iter::repeat_n(true, 64));
+        assert_eq!(bits.into_bitvec(), expected);
+    }
+
+    #[test]
+    fn push_bits_ignores_higher_bits() {
+        let mut bits = BitWriter::new();
+        bits.push_bits(0xff0f, 4);
+        bits.push(false);
+        assert_eq!(bits.into_bitvec(), bitvec("11110"));
+    }
+
+    #[test]
+    fn push_bytes_unaligned() {
+        let mut bits = writer("1");
+        bits.push_bytes(&[0x81, 0x7e]);
+        assert_eq!(bits.len(), 17);
+        assert_eq!(bits.reader().to_bytes().as_ref(), [0xc0, 0xbf, 0x00]);
+    }
+
+    #[test]
+    fn read_bits_at_any_offset() {
+        let bytes = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x0f, 0xff];
+        let bits = BitReader::from_bytes(&bytes);
+        assert_eq!(bits.read_bits(0, 64), Some(0x123456789abcdef0));
+        assert_eq!(bits.read_bits(4, 64), Some(0x23456789abcdef00));
+        assert_eq!(bits.read_bits(3, 8), Some(0x91));
+        assert_eq!(bits.read_bits(76, 4), Some(0xf));
+        assert_eq!(bits.read_bits(80, 0), Some(0));
+        assert_eq!(bits.read_bits(77, 4), None);
+        assert_eq!(bits.read_u8(72), Some(0xff));
+        assert_eq!(bits.read_u8(73), None);
+    }
+
+    #[test]
+    fn read_bits_stops_at_the_view() {
+        let bits = BitReader::new(&[0xff, 0xff], 12);
+        assert_eq!(bits.read_bits(8, 4), Some(0xf));
+        assert_eq!(bits.read_bits(8, 5), None);
+        // the word is read past the view but not handed out
+        let slice = bits.slice(2, 4);
+        assert_eq!(slice.read_bits(0, 4), Some(0xf));
+        assert_eq!(slice.read_bits(1, 4), None);
+    }
+
+    #[test]
+    fn find_pattern() {
+        let bits = BitReader::from_bytes(&[0x00, 0x0a, 0xa0, 0x00]);
+        assert_eq!(bits.find(0xaa, 8, 0), Some(12));
+        assert_eq!(bits.find(0xaa, 8, 12), Some(12));
+        assert_eq!(bits.find(0xaa, 8, 13), None);
+        assert_eq!(bits.find(0b101, 3, 13), Some(14));
+        // the higher bits of the pattern are ignored
+        assert_eq!(bits.find(0xf0aa, 8, 0), Some(12));
+        assert_eq!(bits.find(0xff, 8, 0), None);
+        assert_eq!(bits.find(0x1, 1, 32), None);
+    }
+
+    #[test]
+    fn find_across_words() {
+        // a pattern starting in one loaded word and ending in the next
+        let mut bits = BitWriter::new();
+        bits.push_bits(0, 60);
+        bits.push_bits(0xdeadbeef, 32);
+        bits.push_bits(0, 20);
+        let bits = bits.reader();
+        assert_eq!(bits.find(0xdeadbeef, 32, 0), Some(60));
+        assert_eq!(bits.find(0xdeadbeef, 32, 61), None);
+        // at the very end
+        assert_eq!(bits.find(0, 20, 61), Some(92));
+        assert_eq!(bits.slice(0, 91).find(0xdeadbeef, 32, 0), None);
+    }
+
+    #[test]
+    fn find_in_a_slice() {
+        let bits = BitReader::from_bytes(&[0xaa, 0x00, 0xaa]);
+        let slice = bits.slice(4, 16);
+        assert_eq!(slice.find(0xaa, 8, 0), None);
+        assert_eq!(slice.find(0xa, 4, 0), Some(0));
+        assert_eq!(slice.find(0xa, 4, 1), Some(12));
+    }
+
+    #[test]
+    fn slice_to_bytes() {
+        let bytes = [0x12, 0x34, 0x56];
+        let bits = BitReader::from_bytes(&bytes);
+        let aligned = bits.slice(8, 16).to_bytes();
+        assert!(matches!(aligned, Cow::Borrowed(_)));
+        assert_eq!(aligned.as_ref(), [0x34, 0x56]);
+        let unaligned = bits.slice(4, 12).to_bytes();
+        assert!(matches!(unaligned, Cow::Owned(_)));
+        assert_eq!(unaligned.as_ref(), [0x23, 0x40]);
+        assert!(bits.slice(24, 0).to_bytes().is_empty());
+    }
+
+    #[test]
+    fn extend_and_discard_bytes() {
+        let mut bits = writer("101");
+        let other = [0xff; 10];
+        bits.extend(&BitReader::from_bytes(&other).slice(1, 70));
+        assert_eq!(bits.len(), 73);
+        assert_eq!(bits.reader().read_bits(0, 3), Some(0b101));
+        assert_eq!(bits.reader().find(0, 1, 3), None);
+
+        bits.discard_bytes(1);
+        assert_eq!(bits.len(), 65);
+        assert_eq!(bits.reader().read_bits(0, 64), Some(u64::MAX));
+        bits.discard_bytes(20);
+        assert!(bits.is_empty());
+        bits.push(true);
+        assert_eq!(bits.into_bitvec(), bitvec("1"));
+    }
+
+    #[test]
+    fn same_as_bitvec() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..50 {
+            let mut expected = BitVec::new();
+            let mut bits = BitWriter::new();
+            for _ in 0..20 {
+                let count = (rng.gen::<u32>() % 65) as usize;
+                let value = rng.gen::<u64>();
+                bits.push_bits(value, count);
+                expected.extend((0..count).rev().map(|i| value >> i & 1 == 1));
+            }
+            let reader = bits.reader();
+            assert_eq!(reader.to_bitvec(), expected);
+
+            let len = expected.len();
+            for _ in 0..20 {
+                let pos = rng.gen::<u32>() as usize % (len + 1);
+                let count = (rng.gen::<u32>() % 65) as usize;
+                let read = (pos + count <= len)
+                    .then(|| (pos..pos + count).fold(0, |word, i| word << 1 | expected[i] as u64));
+                assert_eq!(reader.read_bits(pos, count), read);
+
+                let count = (rng.gen::<u32>() % 8 + 1) as usize;
+                let pattern = rng.gen::<u64>() & (u64::MAX >> (64 - count));
+                let found = (pos..(len + 1).saturating_sub(count)).find(|&start| {
+                    (start..start + count).fold(0, |word, i| word << 1 | expected[i] as u64)
+                        == pattern
+                });
+                assert_eq!(reader.find(pattern, count, pos), found);
+            }
+        }
+    }
+}
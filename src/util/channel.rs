@@ -0,0 +1,75 @@
+// Impairments of a screen to camera channel, to test the decoders without
+// a camera.
+//
+// The frames lose contrast and brightness, get pixel noise and a flicker
+// of the whole frame, and frames are lost or received twice when the
+// camera and the screen run out of step.
+
+use image::GrayImage;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Channel {
+    /// Scale of the black to white range around mid gray, 1.0 keeps it
+    pub contrast: f32,
+    /// Added to every pixel
+    pub brightness: f32,
+    /// Standard deviation of the noise of every pixel
+    pub noise: f32,
+    /// Standard deviation of the brightness of whole frames
+    pub flicker: f32,
+    /// Probability of a frame to be lost
+    pub drop_rate: f32,
+    /// Probability of a frame to be received twice
+    pub repeat_rate: f32,
+    /// Same seed, same impairments
+    pub seed: u64,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel {
+            contrast: 1.0,
+            brightness: 0.0,
+            noise: 0.0,
+            flicker: 0.0,
+            drop_rate: 0.0,
+            repeat_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl Channel {
+    pub fn apply(&self, frames: &[GrayImage]) -> Vec<GrayImage> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut received = Vec::with_capacity(frames.len());
+        for frame in frames {
+            if rng.gen::<f32>() < self.drop_rate {
+                continue;
+            }
+            let offset = self.brightness + self.flicker * gaussian(&mut rng);
+            let mut frame = frame.clone();
+            for pixel in frame.pixels_mut() {
+                let mut value = 128.0 + (pixel[0] as f32 - 128.0) * self.contrast + offset;
+                if self.noise > 0.0 {
+                    value += self.noise * gaussian(&mut rng);
+                }
+                pixel[0] = value.round().clamp(0.0, 255.0) as u8;
+            }
+            if rng.gen::<f32>() < self.repeat_rate {
+                received.push(frame.clone());
+            }
+            received.push(frame);
+        }
+        received
+    }
+}
+
+// standard normal distribution by the Box-Muller transform
+fn gaussian(rng: &mut StdRng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
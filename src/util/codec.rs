@@ -0,0 +1,625 @@
+// Serialization of the message fields.
+//
+// bincode 1 writes integers and lengths with fixed width, a string costs 8
+// bytes of length before its first character. The channel moves a few bytes
+// per second, so the format is selectable per link: bincode with varints,
+// postcard, CBOR, or the compact format of this module, which writes
+// integers as LEB128 varints and leaves out the length of the last field,
+// it ends with the message. The format is in the flags of every message,
+// see util::message, so the receiver doesn't have to know it in advance.
+
+use crate::config::Config;
+use crate::util::coding::Coding;
+use crate::util::message::{Message, MessageError, MessageKind};
+use crate::util::modulation::Modulation;
+use crate::util::rate::LinkQuality;
+use crate::util::transfer::{FileChunk, FileManifest};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Fields of a message to bytes and back, the kind is in the header.
+pub trait Codec {
+    fn encode_fields(&self, message: &Message) -> Vec<u8>;
+    fn decode_fields(&self, kind: MessageKind, fields: &[u8]) -> Result<Message, MessageError>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    /// bincode 1 with fixed width integers, the format before there was a choice
+    Bincode,
+    BincodeVarint,
+    Postcard,
+    Cbor,
+    #[default]
+    Compact,
+}
+
+impl Format {
+    pub const ALL: [Format; 5] = [
+        Format::Bincode,
+        Format::BincodeVarint,
+        Format::Postcard,
+        Format::Cbor,
+        Format::Compact,
+    ];
+
+    /// "bincode", "bincode-varint", "postcard", "cbor" or "compact"
+    pub fn from_name(name: &str) -> Option<Self> {
+        Format::ALL.into_iter().find(|format| format.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Bincode => "bincode",
+            Format::BincodeVarint => "bincode-varint",
+            Format::Postcard => "postcard",
+            Format::Cbor => "cbor",
+            Format::Compact => "compact",
+        }
+    }
+
+    /// Number in the message flags, bincode is 0 as in the messages before
+    pub fn tag(&self) -> u8 {
+        match self {
+            Format::Bincode => 0,
+            Format::BincodeVarint => 1,
+            Format::Postcard => 2,
+            Format::Cbor => 3,
+            Format::Compact => 4,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        Format::ALL.into_iter().find(|format| format.tag() == tag)
+    }
+
+    pub fn codec(&self) -> &'static dyn Codec {
+        match self {
+            Format::Bincode => &Bincode,
+            Format::BincodeVarint => &BincodeVarint,
+            Format::Postcard => &Postcard,
+            Format::Cbor => &Cbor,
+            Format::Compact => &Compact,
+        }
+    }
+}
+
+/// A serde format, every one is a `Codec` of the message fields.
+pub trait SerdeFormat {
+    fn serialize<T: Serialize>(&self, value: &T) -> Vec<u8>;
+    /// Fails unless `bytes` is exactly one value
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, MessageError>;
+}
+
+impl<F: SerdeFormat> Codec for F {
+    fn encode_fields(&self, message: &Message) -> Vec<u8> {
+        match message {
+            Message::Text { id, content } => self.serialize(&(id, content)),
+            Message::Url(url) => self.serialize(url),
+            Message::Binary(data) => self.serialize(data),
+            Message::FileManifest(manifest) => self.serialize(manifest),
+            Message::FileChunk(chunk) => self.serialize(chunk),
+            Message::Ack { id } | Message::Ping { id } | Message::Nack { id } => self.serialize(id),
+            Message::Config(config) => self.serialize(config),
+            Message::Data { seq, ack, data } => self.serialize(&(seq, ack, data)),
+            Message::Over { ack } => self.serialize(ack),
+            Message::LinkQuality(quality) => self.serialize(quality),
+        }
+    }
+
+    fn decode_fields(&self, kind: MessageKind, fields: &[u8]) -> Result<Message, MessageError> {
+        Ok(match kind {
+            MessageKind::Text => {
+                let (id, content) = self.deserialize(fields)?;
+                Message::Text { id, content }
+            }
+            MessageKind::Url => Message::Url(self.deserialize(fields)?),
+            MessageKind::Binary => Message::Binary(self.deserialize(fields)?),
+            MessageKind::FileManifest => Message::FileManifest(self.deserialize(fields)?),
+            MessageKind::FileChunk => Message::FileChunk(self.deserialize(fields)?),
+            MessageKind::Ack => Message::Ack {
+                id: self.deserialize(fields)?,
+            },
+            MessageKind::Ping => Message::Ping {
+                id: self.deserialize(fields)?,
+            },
+            MessageKind::Config => Message::Config(self.deserialize(fields)?),
+            MessageKind::Nack => Message::Nack {
+                id: self.deserialize(fields)?,
+            },
+            MessageKind::Data => {
+                let (seq, ack, data) = self.deserialize(fields)?;
+                Message::Data { seq, ack, data }
+            }
+            MessageKind::Over => Message::Over {
+                ack: self.deserialize(fields)?,
+            },
+            MessageKind::LinkQuality => Message::LinkQuality(self.deserialize(fields)?),
+        })
+    }
+}
+
+pub struct Bincode;
+
+impl SerdeFormat for Bincode {
+    fn serialize<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        bincode::serialize(value).expect("Failed to serialize")
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, MessageError> {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(bytes)
+            .map_err(|_| MessageError::Malformed)
+    }
+}
+
+pub struct BincodeVarint;
+
+impl SerdeFormat for BincodeVarint {
+    fn serialize<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        bincode::DefaultOptions::new()
+            .serialize(value)
+            .expect("Failed to serialize")
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, MessageError> {
+        bincode::DefaultOptions::new()
+            .reject_trailing_bytes()
+            .deserialize(bytes)
+            .map_err(|_| MessageError::Malformed)
+    }
+}
+
+pub struct Postcard;
+
+impl SerdeFormat for Postcard {
+    fn serialize<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        postcard::to_allocvec(value).expect("Failed to serialize")
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, MessageError> {
+        match postcard::take_from_bytes(bytes) {
+            Ok((value, [])) => Ok(value),
+            _ => Err(MessageError::Malformed),
+        }
+    }
+}
+
+pub struct Cbor;
+
+impl SerdeFormat for Cbor {
+    fn serialize<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).expect("Failed to serialize");
+        bytes
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, mut bytes: &[u8]) -> Result<T, MessageError> {
+        let value = ciborium::from_reader(&mut bytes).map_err(|_| MessageError::Malformed)?;
+        if !bytes.is_empty() {
+            return Err(MessageError::Malformed);
+        }
+        Ok(value)
+    }
+}
+
+/// Varint integers, the last string or data runs to the end of the message.
+pub struct Compact;
+
+impl Codec for Compact {
+    fn encode_fields(&self, message: &Message) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match message {
+            Message::Text { id, content } => {
+                write_varint(&mut bytes, *id as u64);
+                bytes.extend(content.as_bytes());
+            }
+            Message::Url(url) => bytes.extend(url.as_bytes()),
+            Message::Binary(data) => bytes.extend(data),
+            Message::FileManifest(manifest) => {
+                write_varint(&mut bytes, manifest.id as u64);
+                write_varint(&mut bytes, manifest.size);
+                bytes.extend(manifest.sha256);
+                write_varint(&mut bytes, manifest.chunk_size as u64);
+                write_varint(&mut bytes, manifest.chunk_count as u64);
+                bytes.extend(manifest.name.as_bytes());
+            }
+            Message::FileChunk(chunk) => {
+                write_varint(&mut bytes, chunk.id as u64);
+                write_varint(&mut bytes, chunk.offset);
+                bytes.extend(&chunk.data);
+            }
+            Message::Ack { id } | Message::Ping { id } | Message::Nack { id } => {
+                write_varint(&mut bytes, *id as u64)
+            }
+            Message::Config(config) => {
+                write_varint(&mut bytes, config.fps as u64);
+                write_varint(&mut bytes, config.width as u64);
+                write_varint(&mut bytes, config.height as u64);
+                bytes.push(config.modulation.bits_per_symbol() as u8);
+                bytes.push(coding_tag(config.coding));
+                bytes.push(config.format.tag());
+            }
+            Message::Data { seq, ack, data } => {
+                write_varint(&mut bytes, *seq as u64);
+                // 0 for none
+                write_varint(&mut bytes, ack.map_or(0, |ack| ack as u64 + 1));
+                bytes.extend(data);
+            }
+            Message::Over { ack } => write_varint(&mut bytes, *ack as u64),
+            Message::LinkQuality(quality) => {
+                write_varint(&mut bytes, quality.packages as u64);
+                write_varint(&mut bytes, quality.crc_failures as u64);
+                write_varint(&mut bytes, quality.corrections as u64);
+                write_varint(&mut bytes, quality.blocks as u64);
+                // left out without packages
+                if let Some(snr) = quality.snr {
+                    bytes.extend(snr.to_le_bytes());
+                }
+            }
+        }
+        bytes
+    }
+
+    fn decode_fields(&self, kind: MessageKind, fields: &[u8]) -> Result<Message, MessageError> {
+        let mut reader = Reader { bytes: fields };
+        let message = match kind {
+            MessageKind::Text => Message::Text {
+                id: reader.varint_u32()?,
+                content: reader.rest_string()?,
+            },
+            MessageKind::Url => Message::Url(reader.rest_string()?),
+            MessageKind::Binary => Message::Binary(reader.rest()),
+            MessageKind::FileManifest => Message::FileManifest(FileManifest {
+                id: reader.varint_u32()?,
+                size: reader.varint()?,
+                sha256: reader.array()?,
+                chunk_size: reader.varint_u32()?,
+                chunk_count: reader.varint_u32()?,
+                name: reader.rest_string()?,
+            }),
+            MessageKind::FileChunk => Message::FileChunk(FileChunk {
+                id: reader.varint_u32()?,
+                offset: reader.varint()?,
+                data: reader.rest(),
+            }),
+            MessageKind::Ack => Message::Ack {
+                id: reader.varint_u32()?,
+            },
+            MessageKind::Ping => Message::Ping {
+                id: reader.varint_u32()?,
+            },
+            MessageKind::Config => Message::Config(Config {
+                fps: reader.varint_u32()?,
+                width: reader.varint_u32()?,
+                height: reader.varint_u32()?,
+                modulation: Modulation::from_levels(1 << reader.byte()?.min(8))
+                    .ok_or(MessageError::Malformed)?,
+                coding: coding_from_tag(reader.byte()?).ok_or(MessageError::Malformed)?,
+                format: Format::from_tag(reader.byte()?).ok_or(MessageError::Malformed)?,
+            }),
+            MessageKind::Nack => Message::Nack {
+                id: reader.varint_u32()?,
+            },
+            MessageKind::Data => Message::Data {
+                seq: reader.varint_u32()?,
+                ack: match reader.varint()? {
+                    0 => None,
+                    ack => Some(u32::try_from(ack - 1).map_err(|_| MessageError::Malformed)?),
+                },
+                data: reader.rest(),
+            },
+            MessageKind::Over => Message::Over {
+                ack: reader.varint_u32()?,
+            },
+            MessageKind::LinkQuality => Message::LinkQuality(LinkQuality {
+                packages: reader.varint_usize()?,
+                crc_failures: reader.varint_usize()?,
+                corrections: reader.varint_usize()?,
+                blocks: reader.varint_usize()?,
+                snr: if reader.bytes.is_empty() {
+                    None
+                } else {
+                    Some(f32::from_le_bytes(reader.array()?))
+                },
+            }),
+        };
+        if !reader.bytes.is_empty() {
+            return Err(MessageError::Malformed);
+        }
+        Ok(message)
+    }
+}
+
+fn coding_tag(coding: Coding) -> u8 {
+    match coding {
+        Coding::None => 0,
+        Coding::Repetition3 => 1,
+        Coding::Hamming74 => 2,
+    }
+}
+
+fn coding_from_tag(tag: u8) -> Option<Coding> {
+    match tag {
+        0 => Some(Coding::None),
+        1 => Some(Coding::Repetition3),
+        2 => Some(Coding::Hamming74),
+        _ => None,
+    }
+}
+
+// LEB128: 7 bits per byte, the high bit is set on all but the last
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, MessageError> {
+        let (byte, rest) = self.bytes.split_first().ok_or(MessageError::Malformed)?;
+        self.bytes = rest;
+        Ok(*byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, MessageError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            // the 10th byte only has room for the highest bit
+            if shift == 63 && byte > 1 {
+                return Err(MessageError::Malformed);
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MessageError::Malformed)
+    }
+
+    fn varint_u32(&mut self) -> Result<u32, MessageError> {
+        self.varint()?
+            .try_into()
+            .map_err(|_| MessageError::Malformed)
+    }
+
+    fn varint_usize(&mut self) -> Result<usize, MessageError> {
+        self.varint()?
+            .try_into()
+            .map_err(|_| MessageError::Malformed)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], MessageError> {
+        if self.bytes.len() < N {
+            return Err(MessageError::Malformed);
+        }
+        let (array, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(array.try_into().unwrap())
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes).to_vec()
+    }
+
+    fn rest_string(&mut self) -> Result<String, MessageError> {
+        String::from_utf8(self.rest()).map_err(|_| MessageError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one of every kind, with integers of several varint lengths
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Text {
+                id: 300,
+                content: "Hello World!".to_string(),
+            },
+            Message::Url("https://example.com".to_string()),
+            Message::Binary(vec![0, 1, 0xff]),
+            Message::FileManifest(FileManifest {
+                id: u32::MAX,
+                name: "notes.txt".to_string(),
+                size: 1 << 40,
+                sha256: [7; 32],
+                chunk_size: 128,
+                chunk_count: 1 << 20,
+            }),
+            Message::FileChunk(FileChunk {
+                id: 1,
+                offset: u64::MAX,
+                data: b"chunk".to_vec(),
+            }),
+            Message::Ack { id: 0 },
+            Message::Ping { id: 127 },
+            Message::Config(Config {
+                fps: 60,
+                width: 1920,
+                height: 1080,
+                modulation: Modulation::Pam4,
+                coding: Coding::Hamming74,
+                format: Format::Postcard,
+            }),
+            Message::Nack { id: 128 },
+            Message::Data {
+                seq: 5,
+                ack: Some(u32::MAX),
+                data: vec![1, 2, 3],
+            },
+            Message::Data {
+                seq: 0,
+                ack: None,
+                data: Vec::new(),
+            },
+            Message::Over { ack: 16384 },
+            Message::LinkQuality(LinkQuality {
+                packages: 4,
+                crc_failures: 1,
+                corrections: 200,
+                blocks: 1000,
+                snr: Some(17.5),
+            }),
+            Message::LinkQuality(LinkQuality::default()),
+        ]
+    }
+
+    fn compact(kind: MessageKind, fields: &[u8]) -> Result<Message, MessageError> {
+        Compact.decode_fields(kind, fields)
+    }
+
+    #[test]
+    fn every_kind_in_every_format() {
+        let messages = messages();
+        for tag in 1..=12 {
+            let kind = MessageKind::from_tag(tag).unwrap();
+            assert!(messages.iter().any(|message| message.kind() == kind));
+        }
+        for format in Format::ALL {
+            let codec = format.codec();
+            for message in &messages {
+                let fields = codec.encode_fields(message);
+                assert_eq!(
+                    codec.decode_fields(message.kind(), &fields).as_ref(),
+                    Ok(message),
+                    "{}",
+                    format.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn format_names_and_tags() {
+        for format in Format::ALL {
+            assert_eq!(Format::from_name(format.name()), Some(format));
+            assert_eq!(Format::from_tag(format.tag()), Some(format));
+        }
+        assert_eq!(Format::from_tag(5), None);
+    }
+
+    #[test]
+    fn varint_lengths() {
+        for value in [
+            0,
+            127,
+            128,
+            16383,
+            16384,
+            u32::MAX as u64,
+            1 << 63,
+            u64::MAX,
+        ] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, value);
+            assert_eq!(
+                bytes.len(),
+                (64 - value.leading_zeros() as usize).div_ceil(7).max(1)
+            );
+            let mut reader = Reader { bytes: &bytes };
+            assert_eq!(reader.varint(), Ok(value));
+            assert!(reader.bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn varint_overflow() {
+        // the 10th byte carries bit 63 only
+        let mut bytes = vec![0xff; 9];
+        bytes.push(0x02);
+        assert_eq!(
+            Reader { bytes: &bytes }.varint(),
+            Err(MessageError::Malformed)
+        );
+        bytes[9] = 0x81;
+        assert_eq!(
+            Reader { bytes: &bytes }.varint(),
+            Err(MessageError::Malformed)
+        );
+        // more than 32 bits for an id
+        let mut fields = Vec::new();
+        write_varint(&mut fields, 1 << 32);
+        assert_eq!(
+            compact(MessageKind::Ack, &fields),
+            Err(MessageError::Malformed)
+        );
+    }
+
+    #[test]
+    fn compact_truncated() {
+        // varint without its last byte
+        assert_eq!(
+            compact(MessageKind::Ack, &[0x80]),
+            Err(MessageError::Malformed)
+        );
+        assert_eq!(
+            compact(MessageKind::Over, &[]),
+            Err(MessageError::Malformed)
+        );
+        // hash cut short
+        let manifest = &messages()[3];
+        let fields = Compact.encode_fields(manifest);
+        assert_eq!(
+            compact(MessageKind::FileManifest, &fields[..20]),
+            Err(MessageError::Malformed)
+        );
+        // config without the format
+        let config = &messages()[7];
+        let fields = Compact.encode_fields(config);
+        assert_eq!(
+            compact(MessageKind::Config, &fields[..fields.len() - 1]),
+            Err(MessageError::Malformed)
+        );
+    }
+
+    #[test]
+    fn compact_trailing_bytes() {
+        for message in [Message::Ack { id: 1 }, Message::Over { ack: 2 }] {
+            let mut fields = Compact.encode_fields(&message);
+            fields.push(0);
+            assert_eq!(
+                compact(message.kind(), &fields),
+                Err(MessageError::Malformed)
+            );
+        }
+        let mut fields = Compact.encode_fields(&Message::Config(Config::default()));
+        fields.push(0);
+        assert_eq!(
+            compact(MessageKind::Config, &fields),
+            Err(MessageError::Malformed)
+        );
+        // the SNR cut short
+        let fields = Compact.encode_fields(&messages()[12]);
+        assert_eq!(
+            compact(MessageKind::LinkQuality, &fields[..fields.len() - 1]),
+            Err(MessageError::Malformed)
+        );
+    }
+
+    #[test]
+    fn compact_unknown_config_tags() {
+        let fields = Compact.encode_fields(&Message::Config(Config::default()));
+        let len = fields.len();
+        // bits per symbol, coding and format are the last three bytes
+        for (index, tag) in [(len - 3, 0), (len - 3, 9), (len - 2, 3), (len - 1, 5)] {
+            let mut fields = fields.clone();
+            fields[index] = tag;
+            assert_eq!(
+                compact(MessageKind::Config, &fields),
+                Err(MessageError::Malformed)
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// Forward error correction of the bits on the channel.
+//
+// Repetition sends every bit three times and decides by majority, it
+// corrects one error in every three bits at a third of the rate. Hamming
+// (7,4) adds three parity bits to every four data bits and corrects one
+// error in every block of seven at 4/7 of the rate.
+
+use bit_vec::BitVec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Coding {
+    None,
+    Repetition3,
+    Hamming74,
+}
+
+impl Coding {
+    /// "none", "rep3" or "hamming74"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Coding::None),
+            "rep3" => Some(Coding::Repetition3),
+            "hamming74" => Some(Coding::Hamming74),
+            _ => None,
+        }
+    }
+
+    /// Data bits per block
+    pub fn data_len(&self) -> usize {
+        match self {
+            Coding::None => 1,
+            Coding::Repetition3 => 1,
+            Coding::Hamming74 => 4,
+        }
+    }
+
+    /// Coded bits per block
+    pub fn block_len(&self) -> usize {
+        match self {
+            Coding::None => 1,
+            Coding::Repetition3 => 3,
+            Coding::Hamming74 => 7,
+        }
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.data_len() as f32 / self.block_len() as f32
+    }
+
+    /// Coded bits, the last block is padded with zeros.
+    pub fn encode(&self, data: &BitVec) -> BitVec {
+        let bits: Vec<bool> = data.iter().collect();
+        let mut coded =
+            BitVec::with_capacity(bits.len().div_ceil(self.data_len()) * self.block_len());
+        for chunk in bits.chunks(self.data_len()) {
+            match self {
+                Coding::None => coded.push(chunk[0]),
+                Coding::Repetition3 => coded.extend([chunk[0]; 3]),
+                Coding::Hamming74 => {
+                    let d = |i: usize| chunk.get(i).copied().unwrap_or(false);
+                    let (d1, d2, d3, d4) = (d(0), d(1), d(2), d(3));
+                    coded.extend([d1 ^ d2 ^ d4, d1 ^ d3 ^ d4, d1, d2 ^ d3 ^ d4, d2, d3, d4]);
+                }
+            }
+        }
+        coded
+    }
+
+    /// Data bits with single errors per block corrected, an incomplete
+    /// block at the end is dropped.
+    pub fn decode(&self, coded: &BitVec) -> BitVec {
+        self.decode_counted(coded).0
+    }
+
+    /// Data bits and the number of blocks with a corrected error, a measure
+    /// of the link quality.
+    pub fn decode_counted(&self, coded: &BitVec) -> (BitVec, usize) {
+        let bits: Vec<bool> = coded.iter().collect();
+        let mut data = BitVec::with_capacity(bits.len() / self.block_len() * self.data_len());
+        let mut corrected = 0;
+        for block in bits.chunks_exact(self.block_len()) {
+            match self {
+                Coding::None => data.push(block[0]),
+                Coding::Repetition3 => {
+                    let ones = block.iter().filter(|bit| **bit).count();
+                    if ones == 1 || ones == 2 {
+                        corrected += 1;
+                    }
+                    data.push(ones >= 2);
+                }
+                Coding::Hamming74 => {
+                    let mut block = [
+                        block[0], block[1], block[2], block[3], block[4], block[5], block[6],
+                    ];
+                    // the syndrome is the position of the flipped bit
+                    let s1 = block[0] ^ block[2] ^ block[4] ^ block[6];
+                    let s2 = block[1] ^ block[2] ^ block[5] ^ block[6];
+                    let s3 = block[3] ^ block[4] ^ block[5] ^ block[6];
+                    let position = s1 as usize | (s2 as usize) << 1 | (s3 as usize) << 2;
+                    if position > 0 {
+                        block[position - 1] = !block[position - 1];
+                        corrected += 1;
+                    }
+                    data.extend([block[2], block[4], block[5], block[6]]);
+                }
+            }
+        }
+        (data, corrected)
+    }
+}
@@ -0,0 +1,137 @@
+// Flicker-free modulation with complementary frames.
+//
+// Instead of blinking black and white, every bit is shown as a pair of
+// frames around a carrier image: carrier + Δ followed by carrier - Δ for a
+// one and the other way round for a zero. At high frame rates the eye
+// integrates the pair back to the carrier, so the data is invisible, while
+// the receiver recovers the sign of Δ by differencing consecutive frames.
+
+use bit_vec::BitVec;
+use image::{GrayImage, Luma};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Complementary {
+    /// Luminance offset added to and subtracted from the carrier
+    pub delta: u8,
+    /// Frame pairs per bit, more pairs average out noise
+    pub pairs_per_bit: usize,
+}
+
+impl Default for Complementary {
+    fn default() -> Self {
+        Complementary {
+            delta: 6,
+            pairs_per_bit: 1,
+        }
+    }
+}
+
+impl Complementary {
+    pub fn frames_per_bit(&self) -> usize {
+        2 * self.pairs_per_bit
+    }
+
+    /// Modulate `data` onto the carrier.
+    pub fn render(&self, data: &BitVec, carrier: &GrayImage) -> Vec<GrayImage> {
+        (0..data.len() * self.frames_per_bit())
+            .map(|index| self.offset(carrier, self.frame_sign(data, index).unwrap()))
+            .collect()
+    }
+
+    /// Sign of the offset in frame `index`, `None` after the last bit or
+    /// without pairs per bit.
+    pub fn frame_sign(&self, data: &BitVec, index: usize) -> Option<i16> {
+        let sign = if data.get(index.checked_div(self.frames_per_bit())?)? {
+            1
+        } else {
+            -1
+        };
+        Some(if index.is_multiple_of(2) { sign } else { -sign })
+    }
+
+    // flat mid-gray carrier leaving room for the offset in both directions
+    pub fn gray_carrier(width: u32, height: u32) -> GrayImage {
+        GrayImage::from_pixel(width, height, Luma([128u8]))
+    }
+
+    fn offset(&self, carrier: &GrayImage, sign: i16) -> GrayImage {
+        let delta = sign * self.delta as i16;
+        let mut frame = carrier.clone();
+        for pixel in frame.pixels_mut() {
+            pixel.0[0] = (pixel.0[0] as i16 + delta).clamp(0, 255) as u8;
+        }
+        frame
+    }
+
+    /// Recover the bits from the analog value of every received frame, none
+    /// without pairs per bit.
+    pub fn demodulate(&self, values: &[f32]) -> BitVec {
+        if self.pairs_per_bit == 0 {
+            return BitVec::new();
+        }
+        // the aligned pairs always differ by 2Δ, misaligned ones only when
+        // two consecutive bits are equal
+        let differences = |phase: usize| -> Vec<f32> {
+            values[phase..]
+                .chunks_exact(2)
+                .map(|pair| pair[0] - pair[1])
+                .collect()
+        };
+        let energy = |diffs: &[f32]| diffs.iter().map(|d| d.abs()).sum::<f32>();
+        let even = differences(0);
+        let odd = if values.len() > 1 {
+            differences(1)
+        } else {
+            Vec::new()
+        };
+        let diffs = if energy(&even) >= energy(&odd) {
+            even
+        } else {
+            odd
+        };
+
+        diffs
+            .chunks_exact(self.pairs_per_bit)
+            .map(|pairs| pairs.iter().sum::<f32>() > 0.0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mean of every frame, what the receiver samples
+    fn values(frames: &[GrayImage]) -> Vec<f32> {
+        frames
+            .iter()
+            .map(|frame| frame.pixels().map(|p| p.0[0] as f32).sum::<f32>() / 4.0)
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let modulation = Complementary {
+            delta: 4,
+            pairs_per_bit: 2,
+        };
+        let data = BitVec::from_bytes(&[0b1011_0010]);
+        let frames = modulation.render(&data, &Complementary::gray_carrier(2, 2));
+        assert_eq!(frames.len(), 8 * 4);
+        assert_eq!(modulation.demodulate(&values(&frames)), data);
+    }
+
+    #[test]
+    fn no_pairs_per_bit() {
+        let modulation = Complementary {
+            delta: 4,
+            pairs_per_bit: 0,
+        };
+        let data = BitVec::from_bytes(&[0xff]);
+        assert!(modulation
+            .render(&data, &Complementary::gray_carrier(2, 2))
+            .is_empty());
+        assert_eq!(modulation.frame_sign(&data, 0), None);
+        assert!(modulation.demodulate(&[1.0, 2.0, 3.0]).is_empty());
+    }
+}
@@ -0,0 +1,488 @@
+// Both directions at once between two screen and camera pairs.
+//
+// Every endpoint shows its messages on its screen and reads the messages of
+// the other end from its camera, one frame per tick. The ends exchange
+// pings until both have heard and been heard, then send numbered data with
+// selective repeat as in util::arq. Data carries the acknowledgement of the
+// other direction, so acknowledgements mostly travel for free; only an end
+// with nothing to send or a gap to report sends an `Ack` or `Nack` of its
+// own. In half-duplex mode only one end transmits at a time and hands the
+// turn over with `Over`, e.g. when a screen lights up the camera next to it.
+// `Loopback` runs two ends through the channel simulator.
+
+use crate::config::Config;
+use crate::package::Package;
+use crate::transmitter::Transmitter;
+use crate::util::arq::{ArqMode, ArqReceiver, SendWindow};
+use crate::util::back_channel::BackChannel;
+use crate::util::channel::Channel;
+use crate::util::locator::Locator;
+use crate::util::message::{decode_message, encode_message, Message};
+use crate::util::playback::Pacer;
+use crate::util::signal::StreamDecoder;
+use crate::util::sink::{FrameSink, SinkResult};
+use crate::util::source::{Frame, FrameSource};
+use image::{DynamicImage, GrayImage};
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplexMode {
+    /// Both ends transmit whenever they have something to send
+    Full,
+    /// The ends take turns
+    Half,
+}
+
+#[derive(Debug, Default)]
+pub struct DuplexStats {
+    pub frames: u64,
+    pub messages: usize,
+    pub retransmitted: usize,
+    /// Acknowledgements that went with data instead of on their own
+    pub piggybacked: usize,
+}
+
+// answers of the receiver, sent with the next free frames
+#[derive(Default)]
+struct Outbox {
+    messages: VecDeque<Message>,
+}
+
+impl BackChannel for Outbox {
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        self.messages.push_back(message.clone());
+        Ok(())
+    }
+
+    fn recv_timeout(&mut self, _timeout: Duration) -> io::Result<Option<Message>> {
+        Ok(None)
+    }
+}
+
+/// One end of a duplex link, on-off keyed without coding as in `receive`.
+pub struct DuplexLink {
+    pub mode: DuplexMode,
+    /// Frames to wait for an acknowledgement before sending again
+    pub timeout: u64,
+    /// Payloads in flight, and messages per turn in half-duplex mode
+    pub window: usize,
+    /// Payload bytes per message, short messages give the other end more
+    /// chances to acknowledge
+    pub payload_len: usize,
+    pub stats: DuplexStats,
+    transmitter: Transmitter,
+    tick: u64,
+
+    // handshake
+    nonce: u32,
+    peer_nonce: Option<u32>,
+    // the other end heard our ping
+    heard: bool,
+    established: bool,
+    ping_due: u64,
+    answer_ping: bool,
+
+    // half-duplex
+    has_turn: bool,
+    turn_messages: usize,
+    // tick to take the turn back if the other end stays silent
+    turn_due: Option<u64>,
+
+    // screen
+    frames: VecDeque<GrayImage>,
+    // camera
+    locator: Locator,
+    decoder: StreamDecoder,
+
+    // due in ticks
+    sender: SendWindow<u64>,
+    receiver: ArqReceiver,
+    outbox: Outbox,
+    delivered: VecDeque<Vec<u8>>,
+}
+
+impl DuplexLink {
+    pub fn new(config: Config, mode: DuplexMode) -> Self {
+        // the high bit keeps pings apart from the sequence numbers of data
+        DuplexLink::with_nonce(config, mode, rand::random::<u32>() | 1 << 31)
+    }
+
+    /// `nonce` identifies this end, it has to differ from the other end.
+    pub fn with_nonce(config: Config, mode: DuplexMode, nonce: u32) -> Self {
+        let window = 4;
+        let payload_len = 64;
+        let transmitter = Transmitter::new(config);
+        // a message each way and back
+        let message_frames = transmitter
+            .package_frames(&[Package::new(vec![0; payload_len + 8]).expect("Payload too large")])
+            .len() as u64;
+        DuplexLink {
+            mode,
+            timeout: 3 * message_frames,
+            window,
+            payload_len,
+            stats: DuplexStats::default(),
+            locator: Locator::for_camera(transmitter.config.fps, 1.0),
+            transmitter,
+            tick: 0,
+            nonce,
+            peer_nonce: None,
+            heard: false,
+            established: false,
+            ping_due: 0,
+            answer_ping: false,
+            has_turn: false,
+            turn_messages: 0,
+            turn_due: None,
+            frames: VecDeque::new(),
+            decoder: StreamDecoder::new(),
+            sender: SendWindow::new(0),
+            receiver: ArqReceiver::new(ArqMode::with_window(window as u32)),
+            outbox: Outbox::default(),
+            delivered: VecDeque::new(),
+        }
+    }
+
+    /// Queue a payload, split into messages of `payload_len`.
+    pub fn send(&mut self, payload: &[u8]) {
+        for chunk in payload.chunks(self.payload_len) {
+            self.sender.push(chunk.to_vec());
+        }
+    }
+
+    /// Next payload of the other end, in order.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.delivered.pop_front()
+    }
+
+    pub fn is_established(&self) -> bool {
+        self.established
+    }
+
+    /// Everything sent was acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.sender.is_idle()
+    }
+
+    /// One frame period: take the frame the camera saw, if any, and return
+    /// the frame to show.
+    pub fn tick(&mut self, seen: Option<&GrayImage>) -> GrayImage {
+        self.tick += 1;
+        self.stats.frames += 1;
+        if let Some(frame) = seen {
+            self.see(frame);
+        }
+        if self.frames.is_empty() {
+            if let Some(message) = self.next_message() {
+                self.stats.messages += 1;
+                let package =
+                    Package::new(encode_message(&message).to_bytes()).expect("Message too large");
+                self.frames
+                    .extend(self.transmitter.package_frames(&[package]));
+            }
+        }
+        let config = &self.transmitter.config;
+        self.frames
+            .pop_front()
+            .unwrap_or_else(|| GrayImage::new(config.width, config.height))
+    }
+
+    /// One frame on real hardware: read the camera and show the next frame.
+    /// Returns `false` when the sink stops.
+    pub fn step(
+        &mut self,
+        source: &mut dyn FrameSource,
+        sink: &mut dyn FrameSink,
+        pacer: &mut Pacer,
+    ) -> SinkResult<bool> {
+        let seen = source.next_frame()?.map(|frame| frame.luma());
+        let frame = self.tick(seen.as_ref());
+        let image = DynamicImage::ImageLuma8(frame).to_rgb8();
+        let (timestamp, _) = pacer.wait();
+        sink.write_frame(&Frame { image, timestamp })
+    }
+
+    fn see(&mut self, frame: &GrayImage) {
+        for sample in self.locator.push_and_sample(frame.clone()) {
+            if let Some(package) = self.decoder.push(sample.bit) {
+                if let Ok(message) = decode_message(&package) {
+                    self.handle(message);
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        // the other end is alive, don't take its turn
+        if !self.has_turn {
+            self.turn_due = Some(self.tick + self.timeout);
+        }
+        match message {
+            Message::Ping { id } if id != self.nonce => {
+                self.peer_nonce = Some(id);
+                self.answer_ping = true;
+            }
+            Message::Ack { id } if id == self.nonce => self.heard = true,
+            Message::Ack { id } => self.sender.ack(id),
+            Message::Nack { id } => self.sender.nack(id, self.tick),
+            // only sent once the other end is established
+            Message::Data { seq, ack, data } => {
+                self.heard = true;
+                if let Some(ack) = ack {
+                    self.sender.ack_before(ack);
+                }
+                // both think they have the turn, the larger nonce keeps it
+                if self.mode == DuplexMode::Half
+                    && self.has_turn
+                    && self.peer_nonce.is_some_and(|peer| peer > self.nonce)
+                {
+                    self.has_turn = false;
+                }
+                let delivered = self
+                    .receiver
+                    .push(
+                        Message::Data {
+                            seq,
+                            ack: None,
+                            data,
+                        },
+                        &mut self.outbox,
+                    )
+                    .expect("The outbox doesn't fail");
+                self.delivered.extend(delivered);
+            }
+            Message::Over { ack } => {
+                self.heard = true;
+                self.sender.ack_before(ack);
+                self.has_turn = true;
+                self.turn_messages = 0;
+                self.turn_due = None;
+            }
+            _ => {}
+        }
+
+        if !self.established && self.heard && self.peer_nonce.is_some() {
+            self.established = true;
+            self.has_turn = self.peer_nonce.is_some_and(|peer| self.nonce > peer);
+        }
+    }
+
+    fn next_message(&mut self) -> Option<Message> {
+        if self.answer_ping {
+            self.answer_ping = false;
+            return Some(Message::Ack {
+                id: self.peer_nonce?,
+            });
+        }
+        if !self.established {
+            if self.tick < self.ping_due {
+                return None;
+            }
+            self.ping_due = self.tick + self.timeout;
+            return Some(Message::Ping { id: self.nonce });
+        }
+
+        if self.mode == DuplexMode::Half {
+            if !self.has_turn {
+                if self.turn_due.is_none_or(|due| self.tick < due) {
+                    return None;
+                }
+                // the hand-over got lost
+                self.has_turn = true;
+                self.turn_messages = 0;
+            }
+            if self.turn_messages >= self.window {
+                return Some(self.pass_turn());
+            }
+        }
+
+        // lost data first, then new data, until it is acknowledged
+        let due = self.tick + self.timeout;
+        if let Some(outgoing) = self.sender.next(self.window as u32, self.tick, due) {
+            if outgoing.retries > 0 {
+                self.stats.retransmitted += 1;
+            }
+            return Some(self.data(outgoing.seq, outgoing.data));
+        }
+        if let Some(message) = self.outbox.messages.pop_front() {
+            return Some(message);
+        }
+        if self.mode == DuplexMode::Half {
+            return Some(self.pass_turn());
+        }
+        None
+    }
+
+    // data with the acknowledgement of everything received in order
+    fn data(&mut self, seq: u32, data: Vec<u8>) -> Message {
+        self.turn_messages += 1;
+        Message::Data {
+            seq,
+            ack: Some(self.acknowledge()),
+            data,
+        }
+    }
+
+    fn pass_turn(&mut self) -> Message {
+        self.has_turn = false;
+        self.turn_messages = 0;
+        self.turn_due = Some(self.tick + self.timeout);
+        Message::Over {
+            ack: self.acknowledge(),
+        }
+    }
+
+    // acks in the outbox that go with the next message
+    fn acknowledge(&mut self) -> u32 {
+        let expected = self.receiver.expected();
+        let before = self.outbox.messages.len();
+        self.outbox
+            .messages
+            .retain(|message| !matches!(message, Message::Ack { id } if *id < expected));
+        self.stats.piggybacked += before - self.outbox.messages.len();
+        expected
+    }
+}
+
+/// Two ends in one process, the frames of each screen go through a
+/// simulated channel to the camera of the other.
+pub struct Loopback {
+    pub a: DuplexLink,
+    pub b: DuplexLink,
+    pub a_to_b: Channel,
+    pub b_to_a: Channel,
+    // frames on their way to the cameras
+    to_a: VecDeque<GrayImage>,
+    to_b: VecDeque<GrayImage>,
+    tick: u64,
+}
+
+impl Loopback {
+    /// The same impairments both ways, with different noise.
+    pub fn new(a: DuplexLink, b: DuplexLink, channel: Channel) -> Self {
+        Loopback {
+            a,
+            b,
+            b_to_a: Channel {
+                seed: channel.seed.wrapping_add(1),
+                ..channel.clone()
+            },
+            a_to_b: channel,
+            to_a: VecDeque::new(),
+            to_b: VecDeque::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn step(&mut self) {
+        let frame_a = self.a.tick(self.to_a.pop_front().as_ref());
+        let frame_b = self.b.tick(self.to_b.pop_front().as_ref());
+        self.to_b.extend(impair(&self.a_to_b, frame_a, self.tick));
+        self.to_a.extend(impair(&self.b_to_a, frame_b, self.tick));
+        self.tick += 1;
+    }
+
+    /// Step until `done` or `max_frames`, returns whether `done`.
+    pub fn run_until(&mut self, max_frames: u64, done: impl Fn(&Loopback) -> bool) -> bool {
+        for _ in 0..max_frames {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+}
+
+// a frame through the channel, with fresh noise every tick
+fn impair(channel: &Channel, frame: GrayImage, tick: u64) -> Vec<GrayImage> {
+    Channel {
+        seed: channel.seed.wrapping_add(tick.wrapping_mul(2)),
+        ..channel.clone()
+    }
+    .apply(&[frame])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // small frames, the whole frame blinks
+    fn ends(mode: DuplexMode) -> (DuplexLink, DuplexLink) {
+        let config = Config {
+            width: 16,
+            height: 16,
+            ..Config::default()
+        };
+        (
+            DuplexLink::with_nonce(config.clone(), mode, 1 << 31 | 1),
+            DuplexLink::with_nonce(config, mode, 1 << 31 | 2),
+        )
+    }
+
+    fn payload(factor: u8) -> Vec<u8> {
+        (0..200).map(|i: u8| i.wrapping_mul(factor)).collect()
+    }
+
+    fn received(end: &mut DuplexLink) -> Vec<u8> {
+        std::iter::from_fn(|| end.recv()).flatten().collect()
+    }
+
+    #[test]
+    fn handshake() {
+        let (a, b) = ends(DuplexMode::Full);
+        let mut loopback = Loopback::new(a, b, Channel::default());
+        assert!(!loopback.a.is_established());
+        assert!(loopback.run_until(5_000, |l| l.a.is_established() && l.b.is_established()));
+        assert!(loopback.a.is_idle() && loopback.b.is_idle());
+    }
+
+    #[test]
+    fn full_duplex_exchange() {
+        let (mut a, mut b) = ends(DuplexMode::Full);
+        a.send(&payload(3));
+        b.send(&payload(5));
+        let mut loopback = Loopback::new(a, b, Channel::default());
+        assert!(loopback.run_until(50_000, |l| l.a.is_idle() && l.b.is_idle()));
+        assert_eq!(received(&mut loopback.a), payload(5));
+        assert_eq!(received(&mut loopback.b), payload(3));
+        assert!(loopback.a.stats.piggybacked > 0);
+        assert!(loopback.b.stats.piggybacked > 0);
+    }
+
+    #[test]
+    fn half_duplex_lost_over() {
+        let (mut a, mut b) = ends(DuplexMode::Half);
+        a.send(&payload(3));
+        b.send(&payload(5));
+        // every frame reaches the other camera a tick later, except the
+        // frames of the first `Over` of a
+        let (mut to_a, mut to_b) = (None, None);
+        let mut lost = 0;
+        let mut losing = false;
+        for _ in 0..50_000 {
+            if a.is_idle() && b.is_idle() {
+                break;
+            }
+            let (had_turn, messages) = (a.has_turn, a.stats.messages);
+            let mut frame_a = a.tick(to_a.as_ref());
+            let frame_b = b.tick(to_b.as_ref());
+            if lost == 0 && had_turn && !a.has_turn && a.stats.messages > messages {
+                losing = true;
+            }
+            if losing {
+                frame_a = GrayImage::new(16, 16);
+                lost += 1;
+                losing = !a.frames.is_empty();
+            }
+            to_a = Some(frame_b);
+            to_b = Some(frame_a);
+        }
+        assert!(lost > 0);
+        assert!(a.is_idle() && b.is_idle());
+        assert_eq!(received(&mut a), payload(5));
+        assert_eq!(received(&mut b), payload(3));
+    }
+}
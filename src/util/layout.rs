@@ -0,0 +1,126 @@
+// Geometry of a transmitted frame.
+//
+// A frame carries `cols * rows` cells, one bit each. With fiducials enabled
+// the data area is surrounded by a white quiet zone with a finder pattern
+// (black, white, black nested squares, 7:5:3) in every corner, so a receiver
+// can find the corners of the data area in a camera image taken at an angle
+// and warp it back to this canonical grid.
+
+use crate::util::sampler::Roi;
+use image::{GrayImage, Luma};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameLayout {
+    pub cols: u32,
+    pub rows: u32,
+    pub fiducials: bool,
+}
+
+impl FrameLayout {
+    /// One bit per frame covering the whole frame, like the original videos.
+    pub fn single() -> Self {
+        FrameLayout {
+            cols: 1,
+            rows: 1,
+            fiducials: false,
+        }
+    }
+
+    pub fn grid(cols: u32, rows: u32) -> Self {
+        FrameLayout {
+            cols,
+            rows,
+            fiducials: true,
+        }
+    }
+
+    pub fn bits_per_frame(&self) -> usize {
+        (self.cols * self.rows) as usize
+    }
+
+    // width of the quiet zone around the data area, a multiple of 9 units
+    fn margin(&self, width: u32, height: u32) -> u32 {
+        if self.fiducials {
+            width.min(height) / 6 / 9 * 9
+        } else {
+            0
+        }
+    }
+
+    pub fn data_area(&self, width: u32, height: u32) -> Roi {
+        let margin = self.margin(width, height);
+        Roi::new(margin, margin, width - 2 * margin, height - 2 * margin)
+    }
+
+    pub fn cell(&self, index: usize, width: u32, height: u32) -> Roi {
+        let area = self.data_area(width, height);
+        let col = index as u32 % self.cols;
+        let row = index as u32 / self.cols;
+        let x0 = area.x + area.width * col / self.cols;
+        let x1 = area.x + area.width * (col + 1) / self.cols;
+        let y0 = area.y + area.height * row / self.rows;
+        let y1 = area.y + area.height * (row + 1) / self.rows;
+        Roi::new(x0, y0, x1 - x0, y1 - y0)
+    }
+
+    /// Centers of the finder patterns: top-left, top-right, bottom-right,
+    /// bottom-left.
+    pub fn fiducial_centers(&self, width: u32, height: u32) -> [(f32, f32); 4] {
+        let half = self.margin(width, height) as f32 / 2.0;
+        let (w, h) = (width as f32, height as f32);
+        [
+            (half, half),
+            (w - half, half),
+            (w - half, h - half),
+            (half, h - half),
+        ]
+    }
+
+    pub fn render(&self, bits: &[bool], width: u32, height: u32) -> GrayImage {
+        let margin = self.margin(width, height);
+        if self.fiducials && margin < 9 {
+            panic!("Frame too small for fiducials: {}x{}", width, height);
+        }
+
+        let mut img = GrayImage::from_pixel(width, height, Luma([255u8]));
+        for index in 0..self.bits_per_frame() {
+            // pad the last frame with zeros
+            let value = if bits.get(index).copied().unwrap_or(false) {
+                255u8
+            } else {
+                0u8
+            };
+            let cell = self.cell(index, width, height);
+            for y in cell.y..cell.y + cell.height {
+                for x in cell.x..cell.x + cell.width {
+                    img.put_pixel(x, y, Luma([value]));
+                }
+            }
+        }
+
+        if self.fiducials {
+            let unit = margin / 9;
+            for (cx, cy) in self.fiducial_centers(width, height) {
+                let x0 = (cx - 3.5 * unit as f32).round() as u32;
+                let y0 = (cy - 3.5 * unit as f32).round() as u32;
+                draw_finder(&mut img, x0, y0, unit);
+            }
+        }
+
+        img
+    }
+}
+
+// 7x7 units: black ring, white ring, 3x3 black center
+fn draw_finder(img: &mut GrayImage, x0: u32, y0: u32, unit: u32) {
+    for dy in 0..7 * unit {
+        for dx in 0..7 * unit {
+            let ring = (dx / unit)
+                .min(dy / unit)
+                .min(6 - dx / unit)
+                .min(6 - dy / unit);
+            let value = if ring == 1 { 255u8 } else { 0u8 };
+            img.put_pixel(x0 + dx, y0 + dy, Luma([value]));
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Link setup with a known calibration sequence.
+//
+// Before the data packages the transmitter shows
+//  1. a staircase of all 8 gray levels, each held for a few frames,
+//  2. a timing ramp of alternating black and white runs of 1 to 6 frames,
+//  3. a pseudo-random block (PRBS6, 63 frames) of black and white.
+// The receiver finds the sequence by correlation and measures the levels,
+// the noise, which runs survive the camera and the error rate, recommends
+// modulation parameters and decodes the data after it with the measured
+// threshold.
+
+use crate::util::modulation::Modulation;
+use crate::util::signal::decode_package;
+use bit_vec::BitVec;
+
+const STAIRCASE_LEVELS: usize = 8;
+const LEVEL_HOLD: usize = 4;
+const RAMP_MAX_RUN: usize = 6;
+const PRBS_LEN: usize = 63;
+
+// minimal distance between levels in units of noise for reliable decisions
+const LEVEL_MARGIN: f32 = 6.0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModulationParams {
+    pub modulation: Modulation,
+    pub frames_per_symbol: usize,
+    /// Measured decision thresholds for `modulation`
+    pub thresholds: Vec<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkReport {
+    /// Received value of each of the 8 gray levels
+    pub level_means: Vec<f32>,
+    /// Michelson contrast between black and white
+    pub contrast: f32,
+    /// Pooled standard deviation of the levels
+    pub noise: f32,
+    pub snr_db: f32,
+    /// Frame rate at which single frame runs still arrive
+    pub effective_frame_rate: f32,
+    /// Shortest run of the timing ramp received with its length
+    pub shortest_run: usize,
+    /// Errors in the pseudo-random block
+    pub bit_error_rate: f32,
+    pub recommended: ModulationParams,
+}
+
+/// Luminance of every frame of the calibration sequence.
+pub fn calibration_sequence() -> Vec<u8> {
+    let mut frames = Vec::new();
+    for value in Modulation::Pam8.level_values() {
+        frames.extend(std::iter::repeat_n(value, LEVEL_HOLD));
+    }
+    for (run, white) in ramp_runs() {
+        frames.extend(std::iter::repeat_n(if white { 255 } else { 0 }, run));
+    }
+    frames.extend(prbs().iter().map(|bit| if bit { 255u8 } else { 0u8 }));
+    frames
+}
+
+/// Calibration followed by the package, one bit per frame.
+pub fn link_sequence(package: &BitVec) -> Vec<u8> {
+    let mut frames = calibration_sequence();
+    frames.extend(Modulation::Ook.modulate(package));
+    frames
+}
+
+/// Measure the link and decode the package following the calibration.
+/// `values` are the analog samples of the received frames.
+pub fn receive(values: &[f32], nominal_fps: f32) -> (Option<LinkReport>, Option<BitVec>) {
+    match analyze(values, nominal_fps) {
+        Some((report, end)) => {
+            let threshold =
+                (report.level_means[0] + report.level_means[STAIRCASE_LEVELS - 1]) / 2.0;
+            let data = Modulation::Ook.demodulate(&values[end..], &[threshold]);
+            let package = decode_package(&data);
+            (Some(report), package)
+        }
+        None => {
+            println!("Calibration sequence not found");
+            let thresholds = Modulation::Ook.default_thresholds();
+            let package = decode_package(&Modulation::Ook.demodulate(values, &thresholds));
+            (None, package)
+        }
+    }
+}
+
+/// Link report and the index of the first sample after the calibration.
+pub fn analyze(values: &[f32], nominal_fps: f32) -> Option<(LinkReport, usize)> {
+    let expected: Vec<f32> = calibration_sequence().iter().map(|v| *v as f32).collect();
+    let start = find_sequence(values, &expected)?;
+    let end = start + expected.len();
+
+    // staircase, without the frames next to the level changes
+    let mut level_means = Vec::with_capacity(STAIRCASE_LEVELS);
+    let mut variance_sum = 0.0;
+    for level in 0..STAIRCASE_LEVELS {
+        let from = start + level * LEVEL_HOLD + 1;
+        let hold = &values[from..from + LEVEL_HOLD - 2];
+        let mean = hold.iter().sum::<f32>() / hold.len() as f32;
+        variance_sum += hold.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / hold.len() as f32;
+        level_means.push(mean);
+    }
+    let black = level_means[0];
+    let white = level_means[STAIRCASE_LEVELS - 1];
+    let threshold = (black + white) / 2.0;
+    let noise = (variance_sum / STAIRCASE_LEVELS as f32).sqrt();
+    let contrast = (white - black) / (white + black).max(1.0);
+    // synthetic videos are noise free, floor at the quantization noise
+    let snr_db = 20.0 * ((white - black).abs() / noise.max(0.5)).log10();
+
+    // timing ramp: a run is received if most of its frames are right
+    let mut position = start + STAIRCASE_LEVELS * LEVEL_HOLD;
+    let mut received_runs = [true; RAMP_MAX_RUN + 1];
+    let mut transitions = 0;
+    let mut previous = None;
+    for (run, white) in ramp_runs() {
+        let correct = values[position..position + run]
+            .iter()
+            .filter(|v| (**v > threshold) == white)
+            .count();
+        if correct * 2 <= run {
+            received_runs[run] = false;
+        }
+        for v in &values[position..position + run] {
+            let bit = *v > threshold;
+            if previous.is_some_and(|previous| previous != bit) {
+                transitions += 1;
+            }
+            previous = Some(bit);
+        }
+        position += run;
+    }
+    let expected_transitions = ramp_runs().len() - 1;
+    let effective_frame_rate =
+        nominal_fps * (transitions.min(expected_transitions) as f32 / expected_transitions as f32);
+    // shortest run with all longer runs received
+    let shortest_run = (1..=RAMP_MAX_RUN)
+        .rev()
+        .take_while(|run| received_runs[*run])
+        .last()
+        .unwrap_or(RAMP_MAX_RUN + 1);
+
+    // pseudo-random block
+    let errors = prbs()
+        .iter()
+        .zip(values[position..position + PRBS_LEN].iter())
+        .filter(|(bit, v)| (**v > threshold) != *bit)
+        .count();
+    let bit_error_rate = errors as f32 / PRBS_LEN as f32;
+
+    let recommended = recommend(&level_means, noise, shortest_run);
+    let report = LinkReport {
+        level_means,
+        contrast,
+        noise,
+        snr_db,
+        effective_frame_rate,
+        shortest_run,
+        bit_error_rate,
+        recommended,
+    };
+    Some((report, end))
+}
+
+// most levels whose measured distance is well above the noise
+fn recommend(level_means: &[f32], noise: f32, shortest_run: usize) -> ModulationParams {
+    let frames_per_symbol = shortest_run.min(RAMP_MAX_RUN);
+    let staircase = Modulation::Pam8.level_values();
+    // received value of any luminance, interpolated between the steps
+    let response = |value: u8| -> f32 {
+        let step = staircase
+            .windows(2)
+            .position(|pair| value <= pair[1])
+            .unwrap_or(STAIRCASE_LEVELS - 2);
+        let (low, high) = (staircase[step] as f32, staircase[step + 1] as f32);
+        let t = (value as f32 - low) / (high - low);
+        level_means[step] + t * (level_means[step + 1] - level_means[step])
+    };
+
+    for modulation in [Modulation::Pam8, Modulation::Pam4, Modulation::Ook] {
+        let means: Vec<f32> = modulation
+            .level_values()
+            .into_iter()
+            .map(response)
+            .collect();
+        let separated = means
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] > LEVEL_MARGIN * noise.max(0.5));
+        if separated || modulation == Modulation::Ook {
+            return ModulationParams {
+                modulation,
+                frames_per_symbol,
+                thresholds: means
+                    .windows(2)
+                    .map(|pair| (pair[0] + pair[1]) / 2.0)
+                    .collect(),
+            };
+        }
+    }
+    unreachable!()
+}
+
+// offset with the highest normalized correlation
+fn find_sequence(values: &[f32], expected: &[f32]) -> Option<usize> {
+    if values.len() < expected.len() {
+        return None;
+    }
+    let n = expected.len() as f32;
+    let expected_mean = expected.iter().sum::<f32>() / n;
+    let expected_centered: Vec<f32> = expected.iter().map(|e| e - expected_mean).collect();
+    let expected_norm = expected_centered.iter().map(|e| e * e).sum::<f32>().sqrt();
+
+    let mut best = (0, 0.0);
+    for offset in 0..=values.len() - expected.len() {
+        let window = &values[offset..offset + expected.len()];
+        let mean = window.iter().sum::<f32>() / n;
+        let norm = window
+            .iter()
+            .map(|v| (v - mean).powi(2))
+            .sum::<f32>()
+            .sqrt();
+        if norm == 0.0 {
+            continue;
+        }
+        let correlation = window
+            .iter()
+            .zip(expected_centered.iter())
+            .map(|(v, e)| (v - mean) * e)
+            .sum::<f32>()
+            / (norm * expected_norm);
+        if correlation > best.1 {
+            best = (offset, correlation);
+        }
+    }
+
+    if best.1 < 0.5 {
+        return None;
+    }
+    Some(best.0)
+}
+
+// (length, white) of the runs of the timing ramp
+fn ramp_runs() -> Vec<(usize, bool)> {
+    (1..=RAMP_MAX_RUN)
+        .flat_map(|run| [(run, true), (run, false)])
+        .collect()
+}
+
+// maximum length sequence of x^6 + x^5 + 1
+fn prbs() -> BitVec {
+    let mut state: u8 = 0b111111;
+    (0..PRBS_LEN)
+        .map(|_| {
+            let bit = ((state >> 5) ^ (state >> 4)) & 1;
+            state = ((state << 1) | bit) & 0b111111;
+            bit == 1
+        })
+        .collect()
+}
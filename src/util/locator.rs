@@ -0,0 +1,275 @@
+// Find the blinking transmitter in camera frames.
+//
+// The frame is divided into square blocks and the temporal variance of the
+// mean luminance of every block is tracked. The transmitter is the region
+// where the luminance changes the most: the block with the highest variance
+// grown by its neighbours that blink almost as strongly. After the warmup
+// the ROI keeps following the maximum if the camera moves slightly.
+
+use crate::util::sampler::{Roi, Sample, Sampler};
+use image::GrayImage;
+
+pub struct Locator {
+    block_size: u32,
+    warmup_frames: usize,
+    // frames after which old statistics have decayed
+    window_frames: usize,
+    // fraction of the maximal variance a neighbour block needs to be included
+    grow_ratio: f32,
+    // blocks the ROI may move per frame when tracking
+    max_shift: i32,
+
+    blocks_x: u32,
+    blocks_y: u32,
+    frames: usize,
+    means: Vec<f32>,
+    variances: Vec<f32>,
+    // ROI in blocks
+    roi: Option<Roi>,
+    // frames seen before the transmitter was located, see `push_and_sample`
+    pending: Vec<GrayImage>,
+}
+
+impl Locator {
+    pub fn new(block_size: u32, warmup_frames: usize) -> Self {
+        Locator {
+            block_size: block_size.max(1),
+            warmup_frames: warmup_frames.max(2),
+            window_frames: warmup_frames.max(2) * 2,
+            grow_ratio: 0.5,
+            max_shift: 1,
+            blocks_x: 0,
+            blocks_y: 0,
+            frames: 0,
+            means: Vec::new(),
+            variances: Vec::new(),
+            roi: None,
+            pending: Vec::new(),
+        }
+    }
+
+    // analyse the first `seconds` of a camera running at `fps`
+    pub fn for_camera(fps: u32, seconds: f32) -> Self {
+        Locator::new(16, (fps as f32 * seconds) as usize)
+    }
+
+    pub fn push_image(&mut self, frame: &GrayImage) -> Option<Roi> {
+        self.push_plane(
+            frame.as_raw(),
+            frame.width() as usize,
+            frame.width(),
+            frame.height(),
+        )
+    }
+
+    /// Add one luma plane. Returns the ROI once the warmup is done.
+    pub fn push_plane(
+        &mut self,
+        data: &[u8],
+        stride: usize,
+        width: u32,
+        height: u32,
+    ) -> Option<Roi> {
+        let blocks_x = (width / self.block_size).max(1);
+        let blocks_y = (height / self.block_size).max(1);
+        if blocks_x != self.blocks_x || blocks_y != self.blocks_y {
+            // resolution changed, start over
+            self.blocks_x = blocks_x;
+            self.blocks_y = blocks_y;
+            self.frames = 0;
+            self.means = vec![0.0; (blocks_x * blocks_y) as usize];
+            self.variances = vec![0.0; (blocks_x * blocks_y) as usize];
+            self.roi = None;
+        }
+
+        let block_means = self.block_means(data, stride, width, height);
+        self.frames += 1;
+        // cumulative statistics during the warmup, exponential decay afterwards
+        let alpha = 1.0 / self.frames.min(self.window_frames) as f32;
+        for (i, value) in block_means.iter().enumerate() {
+            // exponentially weighted variance (Welford style update)
+            let delta = value - self.means[i];
+            self.means[i] += alpha * delta;
+            self.variances[i] = (1.0 - alpha) * (self.variances[i] + alpha * delta * delta);
+        }
+
+        if self.frames < self.warmup_frames {
+            return None;
+        }
+        self.roi = Some(match self.roi {
+            None => self.find_roi(),
+            Some(roi) => self.track_roi(roi),
+        });
+        self.roi()
+    }
+
+    /// Add a frame and sample it in the located ROI. The frames before the
+    /// transmitter is located are kept and sampled all at once when it is,
+    /// so the bits of the warmup aren't lost.
+    pub fn push_and_sample(&mut self, frame: GrayImage) -> Vec<Sample> {
+        self.push_image(&frame);
+        self.pending.push(frame);
+        match self.sampler() {
+            Some(sampler) => self
+                .pending
+                .drain(..)
+                .map(|frame| sampler.sample_image(&frame))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.roi.is_some()
+    }
+
+    /// Located ROI in pixel coordinates.
+    pub fn roi(&self) -> Option<Roi> {
+        self.roi.map(|roi| {
+            Roi::new(
+                roi.x * self.block_size,
+                roi.y * self.block_size,
+                roi.width * self.block_size,
+                roi.height * self.block_size,
+            )
+        })
+    }
+
+    /// Sampler for the located ROI with the threshold set to the average
+    /// luminance of the ROI, i.e. between the on and off levels.
+    pub fn sampler(&self) -> Option<Sampler> {
+        let roi = self.roi?;
+        let mut sum = 0.0;
+        for by in roi.y..roi.y + roi.height {
+            for bx in roi.x..roi.x + roi.width {
+                sum += self.means[self.block_index(bx, by)];
+            }
+        }
+        let mut sampler = Sampler::with_roi(self.roi()?);
+        sampler.threshold = sum / (roi.width * roi.height) as f32;
+        // the ROI is already inside the transmitter
+        sampler.border = 0.0;
+        Some(sampler)
+    }
+
+    fn block_means(&self, data: &[u8], stride: usize, width: u32, height: u32) -> Vec<f32> {
+        let mut sums = vec![0u64; (self.blocks_x * self.blocks_y) as usize];
+        let mut counts = vec![0u64; sums.len()];
+        for y in 0..height {
+            let by = (y / self.block_size).min(self.blocks_y - 1);
+            let row = &data[y as usize * stride..];
+            for x in 0..width {
+                let bx = (x / self.block_size).min(self.blocks_x - 1);
+                let index = self.block_index(bx, by);
+                sums[index] += row[x as usize] as u64;
+                counts[index] += 1;
+            }
+        }
+        sums.iter()
+            .zip(counts.iter())
+            .map(|(sum, count)| *sum as f32 / (*count).max(1) as f32)
+            .collect()
+    }
+
+    // block with the highest variance, grown to the bounding box of the
+    // connected blocks above grow_ratio of the maximum
+    fn find_roi(&self) -> Roi {
+        let (max_index, max_variance) =
+            self.variances
+                .iter()
+                .enumerate()
+                .fold(
+                    (0, 0.0),
+                    |best, (i, v)| if *v > best.1 { (i, *v) } else { best },
+                );
+        let limit = max_variance * self.grow_ratio;
+
+        let start = (
+            max_index as u32 % self.blocks_x,
+            max_index as u32 / self.blocks_x,
+        );
+        let mut visited = vec![false; self.variances.len()];
+        let mut stack = vec![start];
+        visited[max_index] = true;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (start.0, start.1, start.0, start.1);
+        while let Some((x, y)) = stack.pop() {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            for (nx, ny) in neighbours {
+                if nx >= self.blocks_x || ny >= self.blocks_y {
+                    continue;
+                }
+                let index = self.block_index(nx, ny);
+                if !visited[index] && self.variances[index] >= limit {
+                    visited[index] = true;
+                    stack.push((nx, ny));
+                }
+            }
+        }
+
+        // drop the outer ring of large regions, edges are blurred by the camera
+        let (mut roi_x, mut roi_y) = (min_x, min_y);
+        let (mut roi_width, mut roi_height) = (max_x - min_x + 1, max_y - min_y + 1);
+        if roi_width > 2 {
+            roi_x += 1;
+            roi_width -= 2;
+        }
+        if roi_height > 2 {
+            roi_y += 1;
+            roi_height -= 2;
+        }
+        Roi::new(roi_x, roi_y, roi_width, roi_height)
+    }
+
+    // move the ROI to the neighbouring position with the highest variance
+    fn track_roi(&self, roi: Roi) -> Roi {
+        let current = self.roi_variance(roi);
+        let mut best = (roi, current);
+        for dy in -self.max_shift..=self.max_shift {
+            for dx in -self.max_shift..=self.max_shift {
+                let x = roi.x as i32 + dx;
+                let y = roi.y as i32 + dy;
+                if x < 0
+                    || y < 0
+                    || x as u32 + roi.width > self.blocks_x
+                    || y as u32 + roi.height > self.blocks_y
+                {
+                    continue;
+                }
+                let candidate = Roi::new(x as u32, y as u32, roi.width, roi.height);
+                let variance = self.roi_variance(candidate);
+                if variance > best.1 {
+                    best = (candidate, variance);
+                }
+            }
+        }
+        // hysteresis against jitter
+        if best.1 > current * 1.1 {
+            best.0
+        } else {
+            roi
+        }
+    }
+
+    fn roi_variance(&self, roi: Roi) -> f32 {
+        let mut sum = 0.0;
+        for by in roi.y..roi.y + roi.height {
+            for bx in roi.x..roi.x + roi.width {
+                sum += self.variances[self.block_index(bx, by)];
+            }
+        }
+        sum
+    }
+
+    fn block_index(&self, bx: u32, by: u32) -> usize {
+        (by * self.blocks_x + bx) as usize
+    }
+}
@@ -1,16 +1,309 @@
+// Messages on the channel.
+//
+// Every message starts with three bytes: the schema version, the kind of
+// the message and flags, followed by its fields in the format of the flags,
+// see util::codec. Receivers dispatch on the kind and reject versions and
+// kinds they don't know instead of guessing. With `FLAG_LZ4` the fields are
+// compressed with lz4, which is only done when it gets shorter, short texts
+// usually don't.
+
+use crate::config::Config;
+use crate::util::codec::Format;
+use crate::util::rate::LinkQuality;
+use crate::util::transfer::{FileChunk, FileManifest};
 use bit_vec::BitVec;
-use serde::{Deserialize, Serialize};
+use lz4_flex::block::{compress_prepend_size, decompress_size_prepended};
+use std::fmt;
+
+/// Schema version of the messages sent, the only one received
+pub const VERSION: u8 = 1;
+
+/// The fields are compressed with lz4, size prepended
+pub const FLAG_LZ4: u8 = 0b0000_0001;
+/// Bits of the flags with the tag of the format
+pub const FORMAT_MASK: u8 = 0b0000_1110;
+const FORMAT_SHIFT: u32 = 1;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub struct Message {
-    pub id: u32,
-    pub content: String,
+// version, kind and flags
+const HEADER_LEN: usize = 3;
+// larger sizes in a compressed message are corrupt, not worth allocating
+const MAX_DECOMPRESSED_LEN: usize = 1 << 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Text with an id
+    Text {
+        id: u32,
+        content: String,
+    },
+    Url(String),
+    Binary(Vec<u8>),
+    /// Start of a file transfer, see util::transfer
+    FileManifest(FileManifest),
+    FileChunk(FileChunk),
+    /// Receipt of the message with `id`
+    Ack {
+        id: u32,
+    },
+    /// The message with `id` is missing, see util::arq
+    Nack {
+        id: u32,
+    },
+    /// Asks the other end for an `Ack`
+    Ping {
+        id: u32,
+    },
+    /// Link parameters the transmitter uses from now on
+    Config(Config),
+    /// Numbered data of a reliable link, see util::arq. `ack` acknowledges
+    /// everything before it from the other direction, see util::duplex
+    Data {
+        seq: u32,
+        ack: Option<u32>,
+        data: Vec<u8>,
+    },
+    /// End of a turn on a half-duplex link, acknowledges everything before
+    /// `ack`
+    Over {
+        ack: u32,
+    },
+    /// Report of the receiver on the packages it got, see util::rate
+    LinkQuality(LinkQuality),
 }
 
+/// Tag of the message on the channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    Text = 1,
+    Url = 2,
+    Binary = 3,
+    FileManifest = 4,
+    FileChunk = 5,
+    Ack = 6,
+    Ping = 7,
+    Config = 8,
+    Nack = 9,
+    Data = 10,
+    Over = 11,
+    LinkQuality = 12,
+}
+
+impl MessageKind {
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(MessageKind::Text),
+            2 => Some(MessageKind::Url),
+            3 => Some(MessageKind::Binary),
+            4 => Some(MessageKind::FileManifest),
+            5 => Some(MessageKind::FileChunk),
+            6 => Some(MessageKind::Ack),
+            7 => Some(MessageKind::Ping),
+            8 => Some(MessageKind::Config),
+            9 => Some(MessageKind::Nack),
+            10 => Some(MessageKind::Data),
+            11 => Some(MessageKind::Over),
+            12 => Some(MessageKind::LinkQuality),
+            _ => None,
+        }
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Text { .. } => MessageKind::Text,
+            Message::Url(_) => MessageKind::Url,
+            Message::Binary(_) => MessageKind::Binary,
+            Message::FileManifest(_) => MessageKind::FileManifest,
+            Message::FileChunk(_) => MessageKind::FileChunk,
+            Message::Ack { .. } => MessageKind::Ack,
+            Message::Ping { .. } => MessageKind::Ping,
+            Message::Config(_) => MessageKind::Config,
+            Message::Nack { .. } => MessageKind::Nack,
+            Message::Data { .. } => MessageKind::Data,
+            Message::Over { .. } => MessageKind::Over,
+            Message::LinkQuality(_) => MessageKind::LinkQuality,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MessageError {
+    /// Shorter than the header, in bytes
+    TooShort(usize),
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    UnknownFlags(u8),
+    UnknownFormat(u8),
+    /// Fields don't match the kind
+    Malformed,
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::TooShort(len) => write!(f, "Message too short: {} bytes", len),
+            MessageError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported message version: {}", version)
+            }
+            MessageError::UnknownKind(kind) => write!(f, "Unknown message kind: {}", kind),
+            MessageError::UnknownFlags(flags) => write!(f, "Unknown message flags: {:#b}", flags),
+            MessageError::UnknownFormat(format) => write!(f, "Unknown message format: {}", format),
+            MessageError::Malformed => write!(f, "Malformed message"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// Message in the default format.
 pub fn encode_message(message: &Message) -> BitVec {
-    BitVec::from_bytes(&bincode::serialize(&message).expect("Failed to serialize"))
+    encode_message_as(message, Format::default())
 }
 
-pub fn decode_message(encoded: &BitVec) -> Message {
-    bincode::deserialize(&encoded.to_bytes()).expect("Failed to deserialize")
+pub fn encode_message_as(message: &Message, format: Format) -> BitVec {
+    let fields = format.codec().encode_fields(message);
+    let compressed = compress_prepend_size(&fields);
+
+    let mut bytes = vec![VERSION, message.kind() as u8];
+    let format_flags = format.tag() << FORMAT_SHIFT;
+    if compressed.len() < fields.len() {
+        bytes.push(format_flags | FLAG_LZ4);
+        bytes.extend(compressed);
+    } else {
+        bytes.push(format_flags);
+        bytes.extend(fields);
+    }
+    BitVec::from_bytes(&bytes)
+}
+
+/// Message in data from the channel, which may be anything.
+pub fn decode_message(encoded: &BitVec) -> Result<Message, MessageError> {
+    let bytes = encoded.to_bytes();
+    if bytes.len() < HEADER_LEN {
+        return Err(MessageError::TooShort(bytes.len()));
+    }
+    let (version, tag, flags) = (bytes[0], bytes[1], bytes[2]);
+    if version != VERSION {
+        return Err(MessageError::UnsupportedVersion(version));
+    }
+    let kind = MessageKind::from_tag(tag).ok_or(MessageError::UnknownKind(tag))?;
+    if flags & !(FLAG_LZ4 | FORMAT_MASK) != 0 {
+        return Err(MessageError::UnknownFlags(flags));
+    }
+    let format_tag = (flags & FORMAT_MASK) >> FORMAT_SHIFT;
+    let codec = Format::from_tag(format_tag)
+        .ok_or(MessageError::UnknownFormat(format_tag))?
+        .codec();
+
+    let body = &bytes[HEADER_LEN..];
+    if flags & FLAG_LZ4 == 0 {
+        return codec.decode_fields(kind, body);
+    }
+    let size = body
+        .get(..4)
+        .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+        .ok_or(MessageError::Malformed)?;
+    if size > MAX_DECOMPRESSED_LEN {
+        return Err(MessageError::Malformed);
+    }
+    let fields = decompress_size_prepended(body).map_err(|_| MessageError::Malformed)?;
+    codec.decode_fields(kind, &fields)
+}
+
+pub fn try_decode_message(encoded: &BitVec) -> Option<Message> {
+    decode_message(encoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Result<Message, MessageError> {
+        decode_message(&BitVec::from_bytes(bytes))
+    }
+
+    fn text(content: &str) -> Message {
+        Message::Text {
+            id: 1,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        for format in Format::ALL {
+            let message = text("Hello World!");
+            assert_eq!(
+                decode_message(&encode_message_as(&message, format)),
+                Ok(message)
+            );
+        }
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(decode(&[]), Err(MessageError::TooShort(0)));
+        assert_eq!(decode(&[VERSION, 1]), Err(MessageError::TooShort(2)));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = encode_message(&Message::Ack { id: 1 }).to_bytes();
+        bytes[0] = VERSION + 1;
+        assert_eq!(
+            decode(&bytes),
+            Err(MessageError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn unknown_kind() {
+        assert_eq!(decode(&[VERSION, 0, 0]), Err(MessageError::UnknownKind(0)));
+        assert_eq!(
+            decode(&[VERSION, 13, 0, 1]),
+            Err(MessageError::UnknownKind(13))
+        );
+    }
+
+    #[test]
+    fn unknown_flags_and_format() {
+        let kind = MessageKind::Ack as u8;
+        assert_eq!(
+            decode(&[VERSION, kind, 0b1_0000, 1]),
+            Err(MessageError::UnknownFlags(0b1_0000))
+        );
+        let flags = 5 << FORMAT_SHIFT;
+        assert_eq!(
+            decode(&[VERSION, kind, flags, 1]),
+            Err(MessageError::UnknownFormat(5))
+        );
+    }
+
+    #[test]
+    fn compressed() {
+        let message = text(&"light ".repeat(100));
+        let bytes = encode_message(&message).to_bytes();
+        assert_ne!(bytes[2] & FLAG_LZ4, 0);
+        assert!(bytes.len() < 100);
+        assert_eq!(decode(&bytes), Ok(message));
+
+        // short texts stay as they are
+        let bytes = encode_message(&text("hi")).to_bytes();
+        assert_eq!(bytes[2] & FLAG_LZ4, 0);
+    }
+
+    #[test]
+    fn compressed_size_too_large() {
+        let mut bytes = encode_message(&text(&"light ".repeat(100))).to_bytes();
+        let size = (MAX_DECOMPRESSED_LEN as u32 + 1).to_le_bytes();
+        bytes[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&size);
+        assert_eq!(decode(&bytes), Err(MessageError::Malformed));
+        // no room for the size
+        let flags = Format::Compact.tag() << FORMAT_SHIFT | FLAG_LZ4;
+        assert_eq!(
+            decode(&[VERSION, MessageKind::Ack as u8, flags, 1]),
+            Err(MessageError::Malformed)
+        );
+    }
 }
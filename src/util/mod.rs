@@ -1,3 +1,37 @@
+pub mod arq;
+// building blocks some of which neither the library nor the binaries use
+#[allow(dead_code)]
+pub mod back_channel;
+#[allow(dead_code)]
+pub mod bits;
+pub mod channel;
+pub mod codec;
+pub mod coding;
+pub mod complementary;
+pub mod duplex;
+pub mod layout;
+// the analysis of received video files needs util::video
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+pub mod link;
+pub mod locator;
 pub mod message;
+pub mod modulation;
+#[cfg(feature = "opencv")]
+pub mod perspective;
+#[cfg(feature = "ffmpeg")]
+pub(crate) mod pipeline;
+pub mod playback;
+pub mod rate;
+#[cfg_attr(not(feature = "ffmpeg"), allow(dead_code))]
+pub mod rolling_shutter;
+pub mod sampler;
 pub mod signal;
+pub mod sink;
+#[allow(dead_code)]
+pub mod source;
+#[cfg(feature = "ffmpeg")]
+pub mod timing;
+pub mod transfer;
+#[cfg(feature = "ffmpeg")]
 pub mod video;
+pub mod watermark;
@@ -0,0 +1,109 @@
+// Gray level modulation of the frames.
+//
+// On-off keying sends one bit per frame as black or white. Pulse amplitude
+// modulation uses 4 or 8 evenly spaced gray levels to send 2 or 3 bits per
+// frame, at the price of a smaller distance between the levels. Levels are
+// Gray coded so that confusing neighbouring levels flips a single bit.
+
+use bit_vec::BitVec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Modulation {
+    Ook,
+    Pam4,
+    Pam8,
+}
+
+impl Modulation {
+    pub fn levels(&self) -> usize {
+        1 << self.bits_per_symbol()
+    }
+
+    pub fn bits_per_symbol(&self) -> usize {
+        match self {
+            Modulation::Ook => 1,
+            Modulation::Pam4 => 2,
+            Modulation::Pam8 => 3,
+        }
+    }
+
+    /// "ook", "pam4" or "pam8"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ook" => Some(Modulation::Ook),
+            "pam4" => Some(Modulation::Pam4),
+            "pam8" => Some(Modulation::Pam8),
+            _ => None,
+        }
+    }
+
+    pub fn from_levels(levels: usize) -> Option<Self> {
+        match levels {
+            2 => Some(Modulation::Ook),
+            4 => Some(Modulation::Pam4),
+            8 => Some(Modulation::Pam8),
+            _ => None,
+        }
+    }
+
+    /// Luminance of every level, from black to white.
+    pub fn level_values(&self) -> Vec<u8> {
+        let levels = self.levels();
+        (0..levels)
+            .map(|level| (level * 255 / (levels - 1)) as u8)
+            .collect()
+    }
+
+    /// Decision thresholds between the nominal levels.
+    pub fn default_thresholds(&self) -> Vec<f32> {
+        let values = self.level_values();
+        values
+            .windows(2)
+            .map(|pair| (pair[0] as f32 + pair[1] as f32) / 2.0)
+            .collect()
+    }
+
+    /// Luminance of every symbol, the last symbol is padded with zeros.
+    pub fn modulate(&self, data: &BitVec) -> Vec<u8> {
+        let values = self.level_values();
+        let bits: Vec<bool> = data.iter().collect();
+        bits.chunks(self.bits_per_symbol())
+            .map(|chunk| {
+                let mut gray = 0;
+                for i in 0..self.bits_per_symbol() {
+                    gray = (gray << 1) | chunk.get(i).copied().unwrap_or(false) as usize;
+                }
+                values[gray_to_level(gray)]
+            })
+            .collect()
+    }
+
+    /// Bits of the received symbol values, `thresholds` are the
+    /// `levels() - 1` ascending boundaries between the levels.
+    pub fn demodulate(&self, values: &[f32], thresholds: &[f32]) -> BitVec {
+        let mut bits = BitVec::new();
+        for value in values {
+            let level = thresholds.iter().filter(|t| value > t).count();
+            let gray = level_to_gray(level);
+            for i in (0..self.bits_per_symbol()).rev() {
+                bits.push((gray >> i) & 1 == 1);
+            }
+        }
+        bits
+    }
+}
+
+fn level_to_gray(level: usize) -> usize {
+    level ^ (level >> 1)
+}
+
+fn gray_to_level(gray: usize) -> usize {
+    let mut level = gray;
+    let mut shift = gray >> 1;
+    while shift > 0 {
+        level ^= shift;
+        shift >>= 1;
+    }
+    level
+}
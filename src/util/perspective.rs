@@ -0,0 +1,217 @@
+// Undo the perspective of a handheld camera.
+//
+// Finds the four finder patterns drawn by `FrameLayout::render`, computes the
+// homography between their centers and the canonical ones and warps the
+// captured frame back to the transmitted grid before sampling the cells.
+
+use crate::util::layout::FrameLayout;
+use crate::util::sampler::{Roi, Sample, Sampler};
+use bit_vec::BitVec;
+use image::GrayImage;
+use opencv::core::{Mat, Point, Point2f, Size, Vec4i, Vector};
+use opencv::imgproc;
+use opencv::videoio::{VideoCapture, CAP_ANY};
+use opencv::{prelude::*, Result};
+
+pub struct Rectifier {
+    layout: FrameLayout,
+    // canonical size, the size of the transmitted frames
+    width: u32,
+    height: u32,
+}
+
+pub struct Rectified {
+    pub image: GrayImage,
+    // Otsu threshold of the captured frame
+    pub threshold: f32,
+}
+
+impl Rectifier {
+    pub fn new(layout: FrameLayout, width: u32, height: u32) -> Self {
+        Rectifier {
+            layout,
+            width,
+            height,
+        }
+    }
+
+    /// Warp a captured frame (BGR or grayscale) to the canonical grid.
+    /// Returns `None` if the four fiducials are not all visible.
+    pub fn rectify(&self, frame: &Mat) -> Result<Option<Rectified>> {
+        let gray = to_gray(frame)?;
+        let (corners, threshold) = match find_fiducials(&gray)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let canonical: Vector<Point2f> = self
+            .layout
+            .fiducial_centers(self.width, self.height)
+            .iter()
+            .map(|(x, y)| Point2f::new(*x, *y))
+            .collect();
+        let homography = imgproc::get_perspective_transform_def(&corners, &canonical)?;
+
+        let mut warped = Mat::default();
+        imgproc::warp_perspective_def(
+            &gray,
+            &mut warped,
+            &homography,
+            Size::new(self.width as i32, self.height as i32),
+        )?;
+        let image = GrayImage::from_raw(self.width, self.height, warped.data_bytes()?.to_vec())
+            .expect("Warped frame has unexpected size");
+
+        Ok(Some(Rectified {
+            image,
+            threshold: threshold as f32,
+        }))
+    }
+
+    /// Sample every cell of a rectified frame.
+    pub fn sample_cells(&self, rectified: &Rectified) -> Vec<Sample> {
+        (0..self.layout.bits_per_frame())
+            .map(|index| {
+                let cell = self.layout.cell(index, self.width, self.height);
+                // skip the cell edges, warping blurs them
+                let inset_x = cell.width / 5;
+                let inset_y = cell.height / 5;
+                let mut sampler = Sampler::with_roi(Roi::new(
+                    cell.x + inset_x,
+                    cell.y + inset_y,
+                    cell.width - 2 * inset_x,
+                    cell.height - 2 * inset_y,
+                ));
+                sampler.border = 0.0;
+                sampler.threshold = rectified.threshold;
+                sampler.sample_image(&rectified.image)
+            })
+            .collect()
+    }
+
+    pub fn read_frame(&self, frame: &Mat) -> Result<Option<Vec<Sample>>> {
+        Ok(self
+            .rectify(frame)?
+            .map(|rectified| self.sample_cells(&rectified)))
+    }
+}
+
+// read a video of grid frames, frames without fiducials are skipped
+pub fn read_video_grid(video_file: &str, rectifier: &Rectifier) -> Result<BitVec> {
+    let mut cam = VideoCapture::from_file(video_file, CAP_ANY)?;
+    let mut frame = Mat::default();
+    let mut data = BitVec::new();
+    let mut skipped = 0;
+    while cam.read(&mut frame)? {
+        match rectifier.read_frame(&frame)? {
+            Some(samples) => data.extend(samples.iter().map(|sample| sample.bit)),
+            None => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        println!("Skipped {} frames without fiducials", skipped);
+    }
+    Ok(data)
+}
+
+fn to_gray(frame: &Mat) -> Result<Mat> {
+    if frame.channels() == 1 {
+        return Ok(frame.clone());
+    }
+    let mut gray = Mat::default();
+    imgproc::cvt_color_def(frame, &mut gray, imgproc::COLOR_BGR2GRAY)?;
+    Ok(gray)
+}
+
+// Centers of the four finder patterns ordered top-left, top-right,
+// bottom-right, bottom-left, and the binarization threshold.
+fn find_fiducials(gray: &Mat) -> Result<Option<(Vector<Point2f>, f64)>> {
+    // dark pixels become foreground
+    let mut binary = Mat::default();
+    let threshold = imgproc::threshold(
+        gray,
+        &mut binary,
+        0.0,
+        255.0,
+        imgproc::THRESH_BINARY_INV | imgproc::THRESH_OTSU,
+    )?;
+
+    let mut contours = Vector::<Vector<Point>>::new();
+    // [next, previous, first child, parent]
+    let mut hierarchy = Vector::<Vec4i>::new();
+    imgproc::find_contours_with_hierarchy_def(
+        &binary,
+        &mut contours,
+        &mut hierarchy,
+        imgproc::RETR_TREE,
+        imgproc::CHAIN_APPROX_SIMPLE,
+    )?;
+
+    // a finder is a square with a hole with a square inside, areas 49:25:9
+    let mut candidates: Vec<(f64, Point2f)> = Vec::new();
+    for i in 0..contours.len() {
+        let child = hierarchy.get(i)?[2];
+        if child < 0 {
+            continue;
+        }
+        let grandchild = hierarchy.get(child as usize)?[2];
+        if grandchild < 0 {
+            continue;
+        }
+
+        let outer = contours.get(i)?;
+        let outer_area = imgproc::contour_area_def(&outer)?;
+        if outer_area < 49.0 {
+            continue;
+        }
+        let hole_ratio = imgproc::contour_area_def(&contours.get(child as usize)?)? / outer_area;
+        let center = contours.get(grandchild as usize)?;
+        let center_ratio = imgproc::contour_area_def(&center)? / outer_area;
+        if !(0.3..0.75).contains(&hole_ratio) || !(0.08..0.35).contains(&center_ratio) {
+            continue;
+        }
+
+        let mut approx = Vector::<Point>::new();
+        let epsilon = 0.05 * imgproc::arc_length(&outer, true)?;
+        imgproc::approx_poly_dp(&outer, &mut approx, epsilon, true)?;
+        if approx.len() != 4 {
+            continue;
+        }
+
+        let moments = imgproc::moments_def(&center)?;
+        if moments.m00 <= 0.0 {
+            continue;
+        }
+        let centroid = Point2f::new(
+            (moments.m10 / moments.m00) as f32,
+            (moments.m01 / moments.m00) as f32,
+        );
+        candidates.push((outer_area, centroid));
+    }
+
+    // the four largest finders of similar size
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    if candidates.len() < 4 || candidates[3].0 < candidates[0].0 / 4.0 {
+        return Ok(None);
+    }
+    let points: Vec<Point2f> = candidates.iter().take(4).map(|c| c.1).collect();
+
+    let by_key = |key: fn(&Point2f) -> f32, max: bool| -> Point2f {
+        let mut sorted = points.clone();
+        sorted.sort_by(|a, b| key(a).total_cmp(&key(b)));
+        if max {
+            sorted[3]
+        } else {
+            sorted[0]
+        }
+    };
+    let top_left = by_key(|p| p.x + p.y, false);
+    let top_right = by_key(|p| p.x - p.y, true);
+    let bottom_right = by_key(|p| p.x + p.y, true);
+    let bottom_left = by_key(|p| p.x - p.y, false);
+
+    Ok(Some((
+        Vector::from_slice(&[top_left, top_right, bottom_right, bottom_left]),
+        threshold,
+    )))
+}
@@ -0,0 +1,98 @@
+// Frames rendered on all cores, written in order.
+//
+// A frame only depends on its own symbols, so batches of frames are
+// rendered and converted with rayon on one thread while the sink writes the
+// batch before on another. At most `queued` rendered batches wait for the
+// sink, the memory stays the same however many frames a transmission has.
+// Sinks that can encode several frames at once, like the PNG files for
+// ffmpeg, get the whole batch.
+
+use crate::util::sink::{FrameSink, SinkResult};
+use crate::util::source::Frame;
+use image::DynamicImage;
+use rayon::prelude::*;
+use std::io::{self, Write};
+use std::sync::mpsc::sync_channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct FramePipeline {
+    /// Frames rendered at once
+    pub batch: usize,
+    /// Rendered batches waiting for the sink
+    pub queued: usize,
+    /// Print the frames written and the frame rate to stderr, off by default
+    pub progress: bool,
+}
+
+impl FramePipeline {
+    pub fn new() -> Self {
+        FramePipeline {
+            batch: 64,
+            queued: 2,
+            progress: false,
+        }
+    }
+
+    /// Writes the frames 0 to `count` - 1 of `render` to `sink`, in order.
+    /// Returns `false` when the sink stopped before the last one.
+    pub fn run<I, F>(&self, count: usize, render: F, sink: &mut dyn FrameSink) -> SinkResult<bool>
+    where
+        I: Into<DynamicImage> + Send,
+        F: Fn(usize) -> I + Sync,
+    {
+        let batch = self.batch.max(1);
+        let started = Instant::now();
+        thread::scope(|scope| {
+            // dropped when the sink stops or fails, the renderer stops with it
+            let (sender, receiver) = sync_channel::<Vec<Frame>>(self.queued);
+            let render = &render;
+            scope.spawn(move || {
+                for start in (0..count).step_by(batch) {
+                    let frames = (start..(start + batch).min(count))
+                        .into_par_iter()
+                        .map(|index| Frame {
+                            image: render(index).into().to_rgb8(),
+                            timestamp: Duration::ZERO,
+                        })
+                        .collect();
+                    if sender.send(frames).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let mut written = 0;
+            for frames in receiver {
+                if !sink.write_frames(&frames)? {
+                    return Ok(false);
+                }
+                written += frames.len();
+                if self.progress {
+                    report(written, count, started);
+                    if written == count {
+                        eprintln!();
+                    }
+                }
+            }
+            Ok(true)
+        })
+    }
+}
+
+impl Default for FramePipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn report(written: usize, count: usize, started: Instant) {
+    eprint!(
+        "\rFrames: {}/{} ({:.0}%) {:.0} fps",
+        written,
+        count,
+        written as f64 * 100.0 / count as f64,
+        written as f64 / started.elapsed().as_secs_f64()
+    );
+    io::stderr().flush().ok();
+}
@@ -0,0 +1,123 @@
+// Show rendered frames on screen in real time.
+//
+// The frames are rendered up front, so the playback loop only has to show
+// them at the right instants. The `Pacer` keeps an absolute schedule (no
+// drift from accumulated sleep errors), sleeps until shortly before every
+// deadline and spins the rest. When the display refresh rate is known the
+// frame period is rounded to a whole number of refresh intervals, so every
+// frame stays on screen for the same number of vsyncs.
+
+use crate::util::sink::{FrameSink, SinkResult};
+use crate::util::source::Frame;
+use image::{DynamicImage, GrayImage};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// sleep granularity of the OS, the rest is spent spinning
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+pub struct Pacer {
+    period: Duration,
+    // don't sleep, only advance the schedule (headless rendering)
+    realtime: bool,
+    start: Option<Instant>,
+    frame: u32,
+}
+
+impl Pacer {
+    /// `refresh_rate` of the display in Hz, if known. Both have to be
+    /// positive.
+    pub fn new(fps: f64, refresh_rate: Option<f64>) -> Self {
+        assert!(fps.is_finite() && fps > 0.0, "Invalid frame rate: {}", fps);
+        let mut period = Duration::from_secs_f64(1.0 / fps);
+        if let Some(refresh_rate) = refresh_rate {
+            assert!(
+                refresh_rate.is_finite() && refresh_rate > 0.0,
+                "Invalid refresh rate: {}",
+                refresh_rate
+            );
+            let refreshes = (refresh_rate / fps).round().max(1.0);
+            if (refresh_rate / fps - refreshes).abs() > 0.01 {
+                println!(
+                    "Warning: {} fps is not a divisor of {} Hz, using {:.2} fps",
+                    fps,
+                    refresh_rate,
+                    refresh_rate / refreshes
+                );
+            }
+            period = Duration::from_secs_f64(refreshes / refresh_rate);
+        }
+        Pacer {
+            period,
+            realtime: true,
+            start: None,
+            frame: 0,
+        }
+    }
+
+    pub fn headless(fps: f64) -> Self {
+        Pacer {
+            realtime: false,
+            ..Pacer::new(fps, None)
+        }
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Wait for the deadline of the next frame. Returns the nominal time of
+    /// the frame since the start and how late the deadline was met.
+    pub fn wait(&mut self) -> (Duration, Duration) {
+        let nominal = self.period * self.frame;
+        self.frame += 1;
+        if !self.realtime {
+            return (nominal, Duration::ZERO);
+        }
+
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let deadline = start + nominal;
+        let now = Instant::now();
+        if deadline > now + SPIN_MARGIN {
+            thread::sleep(deadline - now - SPIN_MARGIN);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+        (nominal, Instant::now() - deadline)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PlaybackStats {
+    pub frames: usize,
+    /// Frames shown more than a quarter period after their deadline
+    pub late: usize,
+    pub max_lateness: Duration,
+    pub duration: Duration,
+}
+
+/// Show the frames at the pace of `pacer` on `sink`, until the sink stops.
+pub fn play(
+    frames: &[GrayImage],
+    pacer: &mut Pacer,
+    sink: &mut dyn FrameSink,
+) -> SinkResult<PlaybackStats> {
+    let mut stats = PlaybackStats::default();
+    let started = Instant::now();
+    for frame in frames {
+        // convert before the deadline
+        let image = DynamicImage::ImageLuma8(frame.clone()).to_rgb8();
+        let (timestamp, lateness) = pacer.wait();
+        if lateness > pacer.period() / 4 {
+            stats.late += 1;
+        }
+        stats.max_lateness = stats.max_lateness.max(lateness);
+        if !sink.write_frame(&Frame { image, timestamp })? {
+            break;
+        }
+        stats.frames += 1;
+    }
+    stats.duration = started.elapsed();
+    Ok(stats)
+}
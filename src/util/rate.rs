@@ -0,0 +1,465 @@
+// Adaptive rate selection.
+//
+// A `RateMode` is a modulation, a coding and the number of frames every
+// symbol is held. Every package says in its header in which mode its data
+// follows; the header itself always goes on-off keyed, uncoded and one
+// frame per bit, so the receiver reads it whatever the mode. From the
+// packages it receives the receiver measures the `LinkQuality`: the SNR of
+// the frames, the blocks the coding corrected and the packages that failed
+// their CRC. The `RateController` of the transmitter steps along `LADDER`
+// with these reports: down right away when packages get lost or the SNR
+// drops below what the mode needs, up only after a few clean reports with
+// margin to spare.
+
+use crate::config::Config;
+use crate::package::Package;
+use crate::receiver::reference_levels;
+use crate::transmitter::{level_frames, PADDING_SYMBOLS, REFERENCE_FRAMES};
+use crate::util::bits::{BitReader, BitWriter};
+use crate::util::coding::Coding;
+use crate::util::modulation::Modulation;
+use crate::util::signal::{
+    decode_body, decode_header, encode_body, encode_header, HEADER_LEN, PREAMBLE, PREAMBLE_LEN,
+    TRAILER_LEN,
+};
+use bit_vec::BitVec;
+use image::GrayImage;
+use serde::{Deserialize, Serialize};
+use std::iter::repeat_n;
+
+const MAX_SYMBOL_FRAMES: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateMode {
+    pub modulation: Modulation,
+    pub coding: Coding,
+    /// Frames every symbol is held, 1 to 8, the receiver averages them
+    pub symbol_frames: usize,
+}
+
+/// Modes from the most robust to the fastest, every one needs a better SNR
+/// than the one before.
+pub const LADDER: [RateMode; 8] = [
+    RateMode::new(Modulation::Ook, Coding::Repetition3, 4),
+    RateMode::new(Modulation::Ook, Coding::Repetition3, 2),
+    RateMode::new(Modulation::Ook, Coding::Repetition3, 1),
+    RateMode::new(Modulation::Ook, Coding::Hamming74, 1),
+    RateMode::new(Modulation::Ook, Coding::None, 1),
+    RateMode::new(Modulation::Pam4, Coding::Hamming74, 1),
+    RateMode::new(Modulation::Pam4, Coding::None, 1),
+    RateMode::new(Modulation::Pam8, Coding::None, 1),
+];
+
+impl RateMode {
+    pub const fn new(modulation: Modulation, coding: Coding, symbol_frames: usize) -> Self {
+        RateMode {
+            modulation,
+            coding,
+            symbol_frames,
+        }
+    }
+
+    /// One frame per symbol in the modulation and coding of `config`.
+    pub fn of_config(config: &Config) -> Self {
+        RateMode::new(config.modulation, config.coding, 1)
+    }
+
+    /// Mode byte of the package header: modulation, coding and held frames.
+    pub fn tag(&self) -> u8 {
+        assert!(
+            (1..=MAX_SYMBOL_FRAMES).contains(&self.symbol_frames),
+            "Invalid symbol frames: {}",
+            self.symbol_frames
+        );
+        let modulation = match self.modulation {
+            Modulation::Ook => 0,
+            Modulation::Pam4 => 1,
+            Modulation::Pam8 => 2,
+        };
+        let coding = match self.coding {
+            Coding::None => 0,
+            Coding::Repetition3 => 1,
+            Coding::Hamming74 => 2,
+        };
+        modulation << 5 | coding << 3 | (self.symbol_frames - 1) as u8
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        let modulation = match tag >> 5 {
+            0 => Modulation::Ook,
+            1 => Modulation::Pam4,
+            2 => Modulation::Pam8,
+            _ => return None,
+        };
+        let coding = match tag >> 3 & 0b11 {
+            0 => Coding::None,
+            1 => Coding::Repetition3,
+            2 => Coding::Hamming74,
+            _ => return None,
+        };
+        Some(RateMode::new(
+            modulation,
+            coding,
+            (tag & 0b111) as usize + 1,
+        ))
+    }
+
+    /// Data bits per frame, without the headers.
+    pub fn bits_per_frame(&self) -> f32 {
+        self.modulation.bits_per_symbol() as f32 * self.coding.rate() / self.symbol_frames as f32
+    }
+
+    /// SNR of the frames in dB the mode needs to get through mostly
+    /// without errors. A level is decided wrong when the noise exceeds half
+    /// the distance to its neighbour, the coding and averaging the held
+    /// frames win back a few dB.
+    pub fn min_snr(&self) -> f32 {
+        let coding_gain = match self.coding {
+            Coding::None => 0.0,
+            Coding::Repetition3 => 3.0,
+            Coding::Hamming74 => 2.0,
+        };
+        let distances = (self.modulation.levels() - 1) as f32;
+        20.0 * (7.4 * distances).log10() - coding_gain - 10.0 * (self.symbol_frames as f32).log10()
+    }
+
+    // frames of the data and CRC of a package with `size` bytes
+    fn body_frames(&self, size: usize) -> usize {
+        let coded =
+            (size * 8 + TRAILER_LEN).div_ceil(self.coding.data_len()) * self.coding.block_len();
+        coded.div_ceil(self.modulation.bits_per_symbol()) * self.symbol_frames
+    }
+}
+
+/// Gray levels of the packages in `mode`: the black and white references,
+/// then every package with its header on-off keyed and the rest in the mode.
+pub fn levels(packages: &[Package], mode: RateMode) -> Vec<u8> {
+    let mut levels = vec![0; REFERENCE_FRAMES];
+    levels.extend(repeat_n(255, REFERENCE_FRAMES));
+    for package in packages {
+        let package = package.clone().with_mode(mode);
+        let header = package.header();
+        levels.extend(repeat_n(0, PADDING_SYMBOLS));
+        let header_bits =
+            encode_header(header, package.data().len() as u8).expect("Packages have valid flags");
+        levels.extend(Modulation::Ook.modulate(&header_bits));
+        let body = encode_body(&BitVec::from_bytes(package.data()), header)
+            .expect("Packages have valid flags");
+        for level in mode.modulation.modulate(&mode.coding.encode(&body)) {
+            levels.extend(repeat_n(level, mode.symbol_frames));
+        }
+    }
+    levels.extend(repeat_n(0, PADDING_SYMBOLS));
+    levels
+}
+
+/// Frames of the packages in `mode`, in the size of `config`.
+pub fn frames(packages: &[Package], mode: RateMode, config: &Config) -> Vec<GrayImage> {
+    level_frames(&levels(packages, mode), config.width, config.height)
+}
+
+/// What the receiver reports back about a stretch of frames, see
+/// `Message::LinkQuality`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkQuality {
+    pub packages: usize,
+    /// Packages with a readable header that failed their CRC
+    pub crc_failures: usize,
+    /// Code blocks with a corrected error
+    pub corrections: usize,
+    /// Code blocks received in packages
+    pub blocks: usize,
+    /// Signal to noise ratio of the frames in dB, `None` without packages
+    pub snr: Option<f32>,
+}
+
+/// Packages in the sampled value of every frame, each in the mode of its
+/// header, and the quality of the link.
+pub fn decode_values(values: &[f32]) -> (Vec<Package>, LinkQuality) {
+    let mut quality = LinkQuality::default();
+    if values.is_empty() {
+        return (Vec::new(), quality);
+    }
+    let (black, white) = reference_levels(values);
+    let range = (white - black).max(f32::EPSILON);
+    let mut headers = BitWriter::with_capacity(values.len());
+    for value in values {
+        headers.push(*value > (black + white) / 2.0);
+    }
+    let headers = headers.reader();
+
+    // squared distance of the frames to the levels decided for them
+    let level = |value: f32| black + value / 255.0 * range;
+    let header_noise = |start: usize| -> f32 {
+        values[start..start + HEADER_LEN]
+            .iter()
+            .map(|v| {
+                let nominal = if *v > (black + white) / 2.0 {
+                    white
+                } else {
+                    black
+                };
+                (v - nominal).powi(2)
+            })
+            .sum()
+    };
+    let (mut noise, mut noise_frames) = (0.0, 0);
+
+    let mut packages = Vec::new();
+    let mut decoded = Vec::new();
+    let mut failed = Vec::new();
+    let mut i = 0;
+    while let Some(start) = headers.find(PREAMBLE as u64, PREAMBLE_LEN, i) {
+        i = start;
+        if i + HEADER_LEN > values.len() {
+            break;
+        }
+        // a corrupt header can't be trusted with the size
+        let (header, size) = match decode_header(&headers, i + PREAMBLE_LEN) {
+            Ok(header) => header,
+            Err(_) => {
+                i += 1;
+                continue;
+            }
+        };
+        let body_start = i + HEADER_LEN;
+        let (mode, end) = match RateMode::from_tag(header.mode) {
+            Some(mode) if body_start + mode.body_frames(size) <= values.len() => {
+                (mode, body_start + mode.body_frames(size))
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let symbols: Vec<f32> = values[body_start..end]
+            .chunks(mode.symbol_frames)
+            .map(|frames| frames.iter().sum::<f32>() / frames.len() as f32)
+            .collect();
+        let thresholds: Vec<f32> = mode
+            .modulation
+            .default_thresholds()
+            .iter()
+            .map(|t| level(*t))
+            .collect();
+        let coded = mode.modulation.demodulate(&symbols, &thresholds);
+        let (body, corrected) = mode.coding.decode_counted(&coded);
+        let body_bytes = body.to_bytes();
+        match decode_body(&BitReader::new(&body_bytes, body.len()), &header, size) {
+            Ok(data) => {
+                quality.corrections += corrected;
+                quality.blocks += coded.len() / mode.coding.block_len();
+                noise += header_noise(i);
+                let level_values = mode.modulation.level_values();
+                for (symbol, frames) in symbols
+                    .iter()
+                    .zip(values[body_start..end].chunks(mode.symbol_frames))
+                {
+                    let decided = thresholds.iter().filter(|t| symbol > t).count();
+                    let nominal = level(level_values[decided] as f32);
+                    noise += frames.iter().map(|v| (v - nominal).powi(2)).sum::<f32>();
+                }
+                noise_frames += end - i;
+                packages.push(Package::with_header(header, data.to_bytes().into_owned()));
+                decoded.push((i, end));
+                i = end;
+            }
+            Err(_) => {
+                failed.push((i, end));
+                i += 1;
+            }
+        }
+    }
+
+    // a lost package counts once, not again for every header its data
+    // happens to contain
+    let mut failed_end = 0;
+    for (start, end) in failed {
+        if start >= failed_end && !decoded.iter().any(|(s, e)| start < *e && *s < end) {
+            quality.crc_failures += 1;
+            noise += header_noise(start);
+            noise_frames += HEADER_LEN;
+            failed_end = end;
+        }
+    }
+    quality.packages = packages.len();
+    if noise_frames > 0 {
+        let rms = (noise / noise_frames as f32).sqrt().max(1e-3);
+        quality.snr = Some(20.0 * (range / rms).log10());
+    }
+    (packages, quality)
+}
+
+/// Picks the mode of the transmitter from the reports of the receiver.
+pub struct RateController {
+    /// Clean reports in a row before stepping up
+    pub up_after: usize,
+    /// dB above what the next mode needs before stepping up
+    pub margin: f32,
+    // in LADDER
+    index: usize,
+    clean: usize,
+}
+
+impl RateController {
+    /// Starts with the most robust mode.
+    pub fn new() -> Self {
+        RateController {
+            up_after: 2,
+            margin: 3.0,
+            index: 0,
+            clean: 0,
+        }
+    }
+
+    pub fn mode(&self) -> RateMode {
+        LADDER[self.index]
+    }
+
+    /// Mode of the next packages after a report.
+    pub fn update(&mut self, quality: &LinkQuality) -> RateMode {
+        let snr = quality.snr.unwrap_or(f32::NEG_INFINITY);
+        let lost = quality.packages == 0 || quality.crc_failures > 0;
+        if lost || snr < self.mode().min_snr() {
+            // the fastest mode the SNR allows, at least one step down
+            let fits = LADDER
+                .iter()
+                .rposition(|mode| mode.min_snr() <= snr)
+                .unwrap_or(0);
+            self.index = fits.min(self.index.saturating_sub(1));
+            self.clean = 0;
+        } else if quality.corrections * 100 > quality.blocks {
+            // errors show up, but the coding still copes
+            self.clean = 0;
+        } else {
+            self.clean += 1;
+            let next_fits = LADDER
+                .get(self.index + 1)
+                .is_some_and(|next| snr >= next.min_snr() + self.margin);
+            if self.clean >= self.up_after && next_fits {
+                self.index += 1;
+                self.clean = 0;
+            }
+        }
+        self.mode()
+    }
+}
+
+impl Default for RateController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::sampler::Sampler;
+    use crate::util::signal::Address;
+
+    fn quality(packages: usize, crc_failures: usize, snr: Option<f32>) -> LinkQuality {
+        LinkQuality {
+            packages,
+            crc_failures,
+            corrections: 0,
+            blocks: 100,
+            snr,
+        }
+    }
+
+    #[test]
+    fn tag_roundtrip() {
+        for mode in LADDER {
+            assert_eq!(RateMode::from_tag(mode.tag()), Some(mode));
+        }
+        for symbol_frames in 1..=MAX_SYMBOL_FRAMES {
+            let mode = RateMode::new(Modulation::Pam8, Coding::Hamming74, symbol_frames);
+            assert_eq!(RateMode::from_tag(mode.tag()), Some(mode));
+        }
+        // no modulation 3, no coding 3
+        assert_eq!(RateMode::from_tag(3 << 5), None);
+        assert_eq!(RateMode::from_tag(3 << 3), None);
+    }
+
+    #[test]
+    fn controller_steps_up_after_clean_reports() {
+        let mut controller = RateController::new();
+        assert_eq!(controller.mode(), LADDER[0]);
+        let clean = quality(4, 0, Some(40.0));
+        assert_eq!(controller.update(&clean), LADDER[0]);
+        assert_eq!(controller.update(&clean), LADDER[1]);
+        for _ in 0..2 * LADDER.len() {
+            controller.update(&clean);
+        }
+        assert_eq!(controller.mode(), LADDER[LADDER.len() - 1]);
+
+        // corrected errors hold the mode without counting as clean
+        let mut controller = RateController::new();
+        let corrected = LinkQuality {
+            corrections: 5,
+            ..clean.clone()
+        };
+        controller.update(&clean);
+        controller.update(&corrected);
+        assert_eq!(controller.update(&clean), LADDER[0]);
+        assert_eq!(controller.update(&clean), LADDER[1]);
+
+        // not without margin to the next mode
+        let mut controller = RateController::new();
+        let snr = LADDER[1].min_snr() + 1.0;
+        for _ in 0..4 {
+            controller.update(&quality(4, 0, Some(snr)));
+        }
+        assert_eq!(controller.mode(), LADDER[0]);
+    }
+
+    #[test]
+    fn controller_steps_down() {
+        let mut controller = RateController::new();
+        let clean = quality(4, 0, Some(40.0));
+        for _ in 0..2 * LADDER.len() {
+            controller.update(&clean);
+        }
+        let top = LADDER.len() - 1;
+
+        // a lost package steps down once, even with a good SNR
+        assert_eq!(
+            controller.update(&quality(3, 1, Some(40.0))),
+            LADDER[top - 1]
+        );
+        // a low SNR drops to the fastest mode it allows
+        let snr = LADDER[4].min_snr() + 0.5;
+        assert_eq!(controller.update(&quality(4, 0, Some(snr))), LADDER[4]);
+        // nothing received
+        assert_eq!(controller.update(&quality(0, 0, None)), LADDER[0]);
+        assert_eq!(controller.update(&quality(0, 0, None)), LADDER[0]);
+    }
+
+    #[test]
+    fn decode_values_in_every_mode() {
+        let config = Config::default();
+        let sent: Vec<Package> = Package::split(&[0x5a; 300])
+            .unwrap()
+            .into_iter()
+            .map(|package| package.addressed(Address(2), Address(3)))
+            .collect();
+        for mode in LADDER {
+            let values: Vec<f32> = frames(&sent, mode, &config)
+                .iter()
+                .map(|frame| Sampler::default().sample_image(frame).value)
+                .collect();
+            let (received, quality) = decode_values(&values);
+            let expected: Vec<Package> = sent
+                .iter()
+                .map(|package| package.clone().with_mode(mode))
+                .collect();
+            assert_eq!(received, expected, "{:?}", mode);
+            assert_eq!(quality.packages, 2);
+            assert_eq!(quality.crc_failures, 0);
+            assert_eq!(quality.corrections, 0);
+            assert!(quality.snr.unwrap() > LADDER[LADDER.len() - 1].min_snr());
+        }
+        assert_eq!(decode_values(&[]), (Vec::new(), LinkQuality::default()));
+    }
+}
@@ -0,0 +1,215 @@
+// Decode several bits per frame using the rolling shutter of CMOS cameras.
+//
+// Rows of a CMOS sensor are read out one after another, `row_time` apart.
+// A light source switching faster than the frame rate therefore shows up
+// as horizontal stripes: every symbol covers `rows_per_symbol` rows. The
+// receiver averages every row, finds the symbol phase from the stripe edges
+// and samples the center of every band. Rows during the vertical blanking
+// are never exposed, so the transmitter repeats its packages and every
+// frame is decoded on its own.
+
+use bit_vec::BitVec;
+use image::{GrayImage, Luma};
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RollingShutter {
+    /// Symbols per second of the transmitter
+    pub symbol_rate: f64,
+    /// Seconds between the readout of two consecutive rows
+    pub row_time: f64,
+}
+
+impl RollingShutter {
+    pub fn new(symbol_rate: f64, row_time: f64) -> Self {
+        RollingShutter {
+            symbol_rate,
+            row_time,
+        }
+    }
+
+    // first guess before calibration: readout spans the whole frame time
+    pub fn estimate(symbol_rate: f64, fps: u32, height: u32) -> Self {
+        RollingShutter::new(symbol_rate, 1.0 / (fps as f64 * height as f64))
+    }
+
+    /// Calibrate the row readout time from frames showing the
+    /// `calibration_pattern` at `symbol_rate`, the median of the frames
+    /// with stripes.
+    pub fn calibrate(symbol_rate: f64, frames_rows: &[Vec<f32>]) -> Option<Self> {
+        let mut estimates: Vec<f64> = frames_rows
+            .iter()
+            .filter_map(|rows| stripe_period(rows))
+            .collect();
+        if estimates.is_empty() {
+            return None;
+        }
+        estimates.sort_by(|a, b| a.total_cmp(b));
+        let rows_per_symbol = estimates[estimates.len() / 2];
+        Some(RollingShutter::new(
+            symbol_rate,
+            1.0 / (symbol_rate * rows_per_symbol),
+        ))
+    }
+
+    pub fn rows_per_symbol(&self) -> f64 {
+        1.0 / (self.symbol_rate * self.row_time)
+    }
+
+    // complete symbols visible in a frame of the given height
+    pub fn bits_per_frame(&self, height: u32) -> usize {
+        (height as f64 / self.rows_per_symbol()) as usize
+    }
+
+    /// Decode the bits of one frame from its row means. No bits with less
+    /// than 2 rows per symbol, the bands can't be told apart.
+    pub fn decode_rows(&self, rows: &[f32]) -> BitVec {
+        let rows_per_symbol = self.rows_per_symbol();
+        let mut bits = BitVec::new();
+        if rows_per_symbol < 2.0 || rows.is_empty() {
+            return bits;
+        }
+
+        let threshold = midpoint(rows);
+        let phase = symbol_phase(rows, threshold, rows_per_symbol);
+
+        // only complete symbols, sampled in the middle half of their band
+        let mut start = phase;
+        while start + rows_per_symbol <= rows.len() as f64 {
+            let from = (start + rows_per_symbol * 0.25).round() as usize;
+            let to = ((start + rows_per_symbol * 0.75).round() as usize).max(from + 1);
+            let band = &rows[from..to.min(rows.len())];
+            let value = band.iter().sum::<f32>() / band.len() as f32;
+            bits.push(value > threshold);
+            start += rows_per_symbol;
+        }
+        bits
+    }
+
+    /// Frames a rolling shutter camera at `fps` records of a light source
+    /// looping over `data`, none without data. Used to simulate the
+    /// transmitter.
+    pub fn render_capture(
+        &self,
+        data: &BitVec,
+        fps: u32,
+        width: u32,
+        height: u32,
+        frame_count: usize,
+    ) -> Vec<GrayImage> {
+        if data.is_empty() {
+            return Vec::new();
+        }
+        (0..frame_count)
+            .map(|n| {
+                let frame_start = n as f64 / fps as f64;
+                GrayImage::from_fn(width, height, |_x, y| {
+                    let t = frame_start + y as f64 * self.row_time;
+                    let symbol = (t * self.symbol_rate) as usize % data.len();
+                    if data[symbol] {
+                        Luma([255u8])
+                    } else {
+                        Luma([0u8])
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+// alternating symbols, stripes of equal width for calibration
+pub fn calibration_pattern(len: usize) -> BitVec {
+    (0..len).map(|i| i % 2 == 0).collect()
+}
+
+/// Mean luminance of every row of an 8-bit plane.
+pub fn row_means(data: &[u8], stride: usize, width: u32, height: u32) -> Vec<f32> {
+    (0..height as usize)
+        .map(|y| {
+            let row = &data[y * stride..y * stride + width as usize];
+            row.iter().map(|v| *v as u32).sum::<u32>() as f32 / width as f32
+        })
+        .collect()
+}
+
+// between the dark and bright rows, robust against a few outliers
+fn midpoint(rows: &[f32]) -> f32 {
+    let mut sorted = rows.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let low = sorted[sorted.len() / 10];
+    let high = sorted[sorted.len() * 9 / 10];
+    (low + high) / 2.0
+}
+
+// rows between the stripe edges
+fn edges(rows: &[f32], threshold: f32) -> Vec<f64> {
+    rows.windows(2)
+        .enumerate()
+        .filter(|(_, pair)| (pair[0] > threshold) != (pair[1] > threshold))
+        .map(|(i, _)| i as f64 + 1.0)
+        .collect()
+}
+
+// offset of the first symbol boundary in rows, circular mean of all edges
+fn symbol_phase(rows: &[f32], threshold: f32, rows_per_symbol: f64) -> f64 {
+    let edges = edges(rows, threshold);
+    if edges.is_empty() {
+        return 0.0;
+    }
+    let (sin, cos) = edges.iter().fold((0.0, 0.0), |(sin, cos), edge| {
+        let angle = 2.0 * PI * edge / rows_per_symbol;
+        (sin + angle.sin(), cos + angle.cos())
+    });
+    let phase = sin.atan2(cos) / (2.0 * PI) * rows_per_symbol;
+    phase.rem_euclid(rows_per_symbol)
+}
+
+// average stripe width of an alternating pattern
+fn stripe_period(rows: &[f32]) -> Option<f64> {
+    let edges = edges(rows, midpoint(rows));
+    if edges.len() < 3 {
+        return None;
+    }
+    Some((edges[edges.len() - 1] - edges[0]) / (edges.len() - 1) as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(frames: &[GrayImage]) -> Vec<Vec<f32>> {
+        frames
+            .iter()
+            .map(|frame| row_means(frame.as_raw(), 8, 8, frame.height()))
+            .collect()
+    }
+
+    #[test]
+    fn calibrate_and_decode() {
+        let camera = RollingShutter::new(1000.0, 1.0 / 4000.0);
+        let calibration = camera.render_capture(&calibration_pattern(64), 30, 8, 120, 3);
+        let shutter = RollingShutter::calibrate(1000.0, &rows(&calibration)).unwrap();
+        assert!((shutter.rows_per_symbol() - 4.0).abs() < 0.1);
+
+        let data = BitVec::from_bytes(&[0b1100_1010]);
+        let frames = camera.render_capture(&data, 30, 8, 120, 1);
+        let bits = shutter.decode_rows(&rows(&frames)[0]);
+        // 30 symbols in the frame, the first or last one may be cut off
+        assert!(bits.len() >= 29);
+        // the data looped over, from whichever symbol was the first complete
+        let looped = |k: usize| (0..bits.len()).all(|i| bits[i] == data[(i + k) % data.len()]);
+        assert!((0..data.len()).any(looped));
+    }
+
+    #[test]
+    fn nothing_to_decode() {
+        assert_eq!(RollingShutter::calibrate(1000.0, &[vec![0.0; 16]]), None);
+        let blurred = RollingShutter::new(1000.0, 1.0 / 1000.0);
+        assert!(blurred.decode_rows(&[0.0, 255.0, 0.0, 255.0]).is_empty());
+        let camera = RollingShutter::new(1000.0, 1.0 / 4000.0);
+        assert!(camera.decode_rows(&[]).is_empty());
+        assert!(camera
+            .render_capture(&BitVec::new(), 30, 8, 8, 2)
+            .is_empty());
+    }
+}
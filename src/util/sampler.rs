@@ -0,0 +1,243 @@
+// Turn a video plane into an analog sample and a bit.
+//
+// Reading a single pixel is the noisiest possible sample and breaks as soon
+// as the video is scaled or slightly misaligned, so the sampler aggregates
+// a whole region (rectangle or mask) and ignores the plane borders, where
+// codecs and cameras produce the most artifacts.
+
+use image::GrayImage;
+
+/// Rectangle in pixel coordinates of a plane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Roi {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Roi {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // clip to a plane of the given size
+    fn clip(&self, width: u32, height: u32) -> Roi {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        Roi {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Region {
+    /// Whole plane (minus the border)
+    Full,
+    Rect(Roi),
+    /// Boolean mask, scaled to the plane if the sizes differ
+    Mask {
+        width: u32,
+        height: u32,
+        mask: Vec<bool>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Statistic {
+    Mean,
+    Median,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    pub region: Region,
+    pub statistic: Statistic,
+    /// Fraction of width/height ignored on each side of the plane
+    pub border: f32,
+    /// Analog value above which a sample is read as `1`
+    pub threshold: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub value: f32,
+    pub bit: bool,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Sampler {
+            region: Region::Full,
+            statistic: Statistic::Mean,
+            border: 0.1,
+            threshold: 128.0,
+        }
+    }
+}
+
+impl Sampler {
+    pub fn with_roi(roi: Roi) -> Self {
+        Sampler {
+            region: Region::Rect(roi),
+            ..Default::default()
+        }
+    }
+
+    /// Sample a single 8-bit plane. `stride` is the length of a row in
+    /// bytes (ffmpeg linesize), which may be larger than `width`.
+    pub fn sample_plane(&self, data: &[u8], stride: usize, width: u32, height: u32) -> Sample {
+        let value = self.value_of_plane(data, stride, width, height);
+        Sample {
+            value,
+            bit: value > self.threshold,
+        }
+    }
+
+    pub fn sample_image(&self, image: &GrayImage) -> Sample {
+        self.sample_plane(
+            image.as_raw(),
+            image.width() as usize,
+            image.width(),
+            image.height(),
+        )
+    }
+
+    fn value_of_plane(&self, data: &[u8], stride: usize, width: u32, height: u32) -> f32 {
+        let area = self.sampling_area(width, height);
+        let mut histogram = [0u64; 256];
+        let mut count = 0u64;
+
+        for y in area.y..area.y + area.height {
+            let row = &data[y as usize * stride..];
+            for x in area.x..area.x + area.width {
+                if !self.in_mask(x, y, width, height) {
+                    continue;
+                }
+                histogram[row[x as usize] as usize] += 1;
+                count += 1;
+            }
+        }
+
+        if count == 0 {
+            // nothing selected, fall back to the first pixel of the area,
+            // black for an empty plane
+            return data
+                .get(area.y as usize * stride + area.x as usize)
+                .map_or(0.0, |value| *value as f32);
+        }
+
+        match self.statistic {
+            Statistic::Mean => {
+                let sum: u64 = histogram
+                    .iter()
+                    .enumerate()
+                    .map(|(value, n)| value as u64 * n)
+                    .sum();
+                sum as f32 / count as f32
+            }
+            Statistic::Median => {
+                let half = count.div_ceil(2);
+                let mut seen = 0;
+                for (value, n) in histogram.iter().enumerate() {
+                    seen += n;
+                    if seen >= half {
+                        return value as f32;
+                    }
+                }
+                255.0
+            }
+        }
+    }
+
+    // region intersected with the plane without its border
+    fn sampling_area(&self, width: u32, height: u32) -> Roi {
+        let border_x = (width as f32 * self.border) as u32;
+        let border_y = (height as f32 * self.border) as u32;
+        let inner = if border_x * 2 < width && border_y * 2 < height {
+            Roi::new(
+                border_x,
+                border_y,
+                width - 2 * border_x,
+                height - 2 * border_y,
+            )
+        } else {
+            Roi::new(0, 0, width, height)
+        };
+
+        let area = match &self.region {
+            Region::Rect(roi) => {
+                let roi = roi.clip(width, height);
+                let x = roi.x.max(inner.x);
+                let y = roi.y.max(inner.y);
+                let right = (roi.x + roi.width).min(inner.x + inner.width);
+                let bottom = (roi.y + roi.height).min(inner.y + inner.height);
+                if right > x && bottom > y {
+                    Roi::new(x, y, right - x, bottom - y)
+                } else {
+                    // ROI lies entirely in the border, trust the ROI
+                    roi
+                }
+            }
+            _ => inner,
+        };
+
+        if area.width == 0 || area.height == 0 {
+            Roi::new(0, 0, width.max(1), height.max(1)).clip(width, height)
+        } else {
+            area
+        }
+    }
+
+    fn in_mask(&self, x: u32, y: u32, width: u32, height: u32) -> bool {
+        match &self.region {
+            Region::Mask {
+                width: mask_width,
+                height: mask_height,
+                mask,
+            } => {
+                let mx = (x as u64 * *mask_width as u64 / width as u64) as usize;
+                let my = (y as u64 * *mask_height as u64 / height as u64) as usize;
+                mask.get(my * *mask_width as usize + mx)
+                    .copied()
+                    .unwrap_or(false)
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_plane_is_black() {
+        let sampler = Sampler::default();
+        assert_eq!(sampler.sample_plane(&[], 0, 0, 0).value, 0.0);
+        assert_eq!(sampler.sample_image(&GrayImage::new(0, 0)).value, 0.0);
+    }
+
+    #[test]
+    fn empty_mask_falls_back_to_first_pixel() {
+        let sampler = Sampler {
+            region: Region::Mask {
+                width: 2,
+                height: 2,
+                mask: vec![false; 4],
+            },
+            ..Default::default()
+        };
+        let image = GrayImage::from_pixel(4, 4, image::Luma([200]));
+        assert_eq!(sampler.sample_image(&image).value, 200.0);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::util::bits::{BitReader, BitWriter};
 use bit_vec::BitVec;
 use crc::{Crc, CRC_8_BLUETOOTH};
+use std::fmt;
 
 // 16-bit vs 8-bit headers, 30fps
 //
@@ -28,117 +30,678 @@ use crc::{Crc, CRC_8_BLUETOOTH};
 
 // overhead = 8 bits preamble + 8 bits size + 8 bits CRC = 24 bits
 // => 24 bits / 30 fps = 0.8 seconds
+// with destination and source address and mode 48 bits => 1.6 seconds
+// with version and flags, sequence number and header CRC 72 bits => 2.4 seconds
 // 1 byte at 30 fps = 0.266 ms
 // 1 byte at 60 fps = 0.133 ms
 
-const PREABLE_LEN: usize = 8;
+// The header after the preamble:
+//
+//   version (4 bits) | flags (4 bits) | dst | src | mode | seq | size | CRC
+//
+// The header has its own CRC, so a flipped bit in the size is noticed
+// before reading a wrong span of data. Receivers reject other versions, a
+// new version can change everything after the version. Flags they don't
+// know are ignored, new flags only add information.
+
+/// Version of the packages sent, the only one received
+pub const PACKAGE_VERSION: u8 = 1;
+/// Last package of a payload
+pub const FLAG_LAST: u8 = 0b0001;
+
+/// Sync word in front of every package
+pub const PREAMBLE: u8 = 0b10101010;
+pub const PREAMBLE_LEN: usize = 8;
+const VERSION_LEN: usize = 8;
+const ADDRESS_LEN: usize = 8;
+const MODE_LEN: usize = 8;
+const SEQ_LEN: usize = 8;
 const SIZE_LEN: usize = 8;
 const CRC_LEN: usize = 8;
+// after the preamble, before the data
+const FIELDS_LEN: usize = VERSION_LEN + 2 * ADDRESS_LEN + MODE_LEN + SEQ_LEN + SIZE_LEN + CRC_LEN;
+/// Bits from the preamble to the header CRC
+pub const HEADER_LEN: usize = PREAMBLE_LEN + FIELDS_LEN;
+/// Bits after the data
+pub const TRAILER_LEN: usize = CRC_LEN;
 const CRC: Crc<u8> = Crc::<u8>::new(&CRC_8_BLUETOOTH); // 8-bit CRC
 
-pub fn get_preamble() -> BitVec {
-    BitVec::from_bytes(&[0b10101010])
+/// Link-layer address of a transmitter, a receiver or a group of receivers.
+/// 1 to 127 name single devices, 128 to 254 groups and 255 everyone. 0 is
+/// reserved for transmitters without an address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address(pub u8);
+
+impl Address {
+    /// Every receiver accepts it
+    pub const BROADCAST: Address = Address(u8::MAX);
+    /// Source of transmitters without an address of their own
+    pub const UNASSIGNED: Address = Address(0);
+    const GROUP_START: u8 = 0x80;
+    /// Groups available
+    pub const GROUPS: u8 = u8::MAX - Address::GROUP_START;
+
+    /// Address of the group `n`, below `GROUPS`.
+    pub fn group(n: u8) -> Self {
+        assert!(n < Address::GROUPS, "Group out of range: {}", n);
+        Address(Address::GROUP_START + n)
+    }
+
+    pub fn is_broadcast(self) -> bool {
+        self == Address::BROADCAST
+    }
+
+    pub fn is_group(self) -> bool {
+        self.0 >= Address::GROUP_START && !self.is_broadcast()
+    }
+
+    /// "broadcast", a group "g3" or a device "12", `None` for "0".
+    pub fn from_name(name: &str) -> Option<Self> {
+        if name == "broadcast" {
+            return Some(Address::BROADCAST);
+        }
+        if let Some(group) = name.strip_prefix('g') {
+            return group
+                .parse()
+                .ok()
+                .filter(|n| *n < Address::GROUPS)
+                .map(Address::group);
+        }
+        name.parse()
+            .ok()
+            .filter(|n| (1..Address::GROUP_START).contains(n))
+            .map(Address)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_broadcast() {
+            write!(f, "broadcast")
+        } else if self.is_group() {
+            write!(f, "g{}", self.0 - Address::GROUP_START)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Fields of a package besides its data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// `FLAG_LAST`, 4 bits
+    pub flags: u8,
+    pub src: Address,
+    pub dst: Address,
+    /// Tag of the `RateMode` of the data, see util::rate
+    pub mode: u8,
+    /// Number of the package in its payload, a payload has at most
+    /// `Package::MAX_COUNT` packages
+    pub seq: u8,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            flags: 0,
+            src: Address::UNASSIGNED,
+            dst: Address::BROADCAST,
+            mode: 0,
+            seq: 0,
+        }
+    }
+}
+
+impl Header {
+    pub fn is_last(&self) -> bool {
+        self.flags & FLAG_LAST != 0
+    }
+
+    // the fields in the order on the channel, without the CRC
+    fn fields(&self, size: u8) -> Result<[u8; 6], PackageError> {
+        if self.flags > 0b1111 {
+            return Err(PackageError::InvalidFlags(self.flags));
+        }
+        Ok([
+            PACKAGE_VERSION << 4 | self.flags,
+            self.dst.0,
+            self.src.0,
+            self.mode,
+            self.seq,
+            size,
+        ])
+    }
+}
+
+/// Destinations a receiver accepts, always its own and broadcasts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AddressFilter {
+    /// `None` accepts every package, e.g. to listen in
+    pub address: Option<Address>,
+    /// Groups joined
+    pub groups: Vec<Address>,
 }
 
-pub fn encode_package(data: &BitVec) -> BitVec {
-    let mut package = BitVec::new();
+impl AddressFilter {
+    pub fn new(address: Address) -> Self {
+        AddressFilter {
+            address: Some(address),
+            groups: Vec::new(),
+        }
+    }
 
-    // Add preamble
-    package.extend(get_preamble().iter());
+    pub fn join(&mut self, group: Address) {
+        if !self.groups.contains(&group) {
+            self.groups.push(group);
+        }
+    }
 
+    pub fn accepts(&self, dst: Address) -> bool {
+        match self.address {
+            None => true,
+            Some(address) => dst == address || dst.is_broadcast() || self.groups.contains(&dst),
+        }
+    }
+}
+
+pub fn get_preamble() -> BitVec {
+    BitVec::from_bytes(&[PREAMBLE])
+}
+
+/// Package to everyone, see `encode_package_with`.
+pub fn encode_package(data: &BitVec) -> Result<BitVec, PackageError> {
+    encode_package_with(data, &Header::default())
+}
+
+pub fn encode_package_with(data: &BitVec, header: &Header) -> Result<BitVec, PackageError> {
     // data to bytes
     let data_bytes = data.to_bytes();
-
-    // add size in bytes
     if data_bytes.len() > u8::MAX as usize {
-        panic!("Data too large for size field");
+        return Err(PackageError::TooLarge(data_bytes.len()));
     }
-    let size = data_bytes.len() as u8;
-    package.extend(BitVec::from_bytes(&size.to_be_bytes()).iter());
 
-    // Add data
-    package.extend(data.iter());
+    let mut package = BitWriter::with_capacity(HEADER_LEN + data_bytes.len() * 8 + TRAILER_LEN);
+    write_header(&mut package, header, data_bytes.len() as u8)?;
+    write_body(&mut package, &data_bytes, header)?;
+    Ok(package.into_bitvec())
+}
+
+/// Preamble, the header fields with the size of the data in bytes and
+/// their CRC.
+pub fn encode_header(header: &Header, size: u8) -> Result<BitVec, PackageError> {
+    let mut bits = BitWriter::with_capacity(HEADER_LEN);
+    write_header(&mut bits, header, size)?;
+    Ok(bits.into_bitvec())
+}
+
+/// Data and CRC.
+pub fn encode_body(data: &BitVec, header: &Header) -> Result<BitVec, PackageError> {
+    let data_bytes = data.to_bytes();
+    let mut bits = BitWriter::with_capacity(data_bytes.len() * 8 + TRAILER_LEN);
+    write_body(&mut bits, &data_bytes, header)?;
+    Ok(bits.into_bitvec())
+}
 
-    // add CRC of data
-    let crc_value = CRC.checksum(&data_bytes);
-    package.extend(BitVec::from_bytes(&crc_value.to_be_bytes()).iter());
+fn write_header(bits: &mut BitWriter, header: &Header, size: u8) -> Result<(), PackageError> {
+    let fields = header.fields(size)?;
+    bits.push_bits(PREAMBLE as u64, PREAMBLE_LEN);
+    bits.push_bytes(&fields);
+    bits.push_bits(CRC.checksum(&fields) as u64, CRC_LEN);
+    Ok(())
+}
 
-    package
+fn write_body(
+    bits: &mut BitWriter,
+    data_bytes: &[u8],
+    header: &Header,
+) -> Result<(), PackageError> {
+    bits.push_bytes(data_bytes);
+    bits.push_bits(checksum(header, data_bytes)? as u64, CRC_LEN);
+    Ok(())
+}
+
+// CRC of the header fields and the data, what slips through the header
+// CRC still fails here
+fn checksum(header: &Header, data_bytes: &[u8]) -> Result<u8, PackageError> {
+    let mut digest = CRC.digest();
+    digest.update(&header.fields(data_bytes.len() as u8)?);
+    digest.update(data_bytes);
+    Ok(digest.finalize())
 }
 
-// BitVec doesn't support slice access
 pub fn decode_package(package_bits: &BitVec) -> Option<BitVec> {
-    // find preamble
-    let preamble_bits = get_preamble();
-    for i in 0..package_bits.len() {
-        let window = package_bits.iter().skip(i).take(PREABLE_LEN);
-        if window.eq(preamble_bits.iter()) {
-            let preamble_index = i;
-            let package = decode_package_at_index(&package_bits, preamble_index + PREABLE_LEN);
-            if package.is_some() {
-                return package;
+    decode_package_with(package_bits).map(|(_header, package)| package)
+}
+
+/// First package with its header, whatever the destination.
+pub fn decode_package_with(package_bits: &BitVec) -> Option<(Header, BitVec)> {
+    // BitVec doesn't support slice access, its bits are read as bytes
+    let bytes = package_bits.to_bytes();
+    let bits = BitReader::new(&bytes, package_bits.len());
+    scan(&bits, 0, true).map(|(header, package, _end)| (header, package.to_bitvec()))
+}
+
+// all packages of a recording, in order
+pub fn decode_packages(bits: &BitVec) -> Vec<(Header, BitVec)> {
+    let bytes = bits.to_bytes();
+    let bits = BitReader::new(&bytes, bits.len());
+    let mut packages = Vec::new();
+    let mut from = 0;
+    while let Some((header, package, end)) = find_package(&bits, from) {
+        packages.push((header, package.to_bitvec()));
+        from = end;
+    }
+    packages
+}
+
+/// First package at or after `from` with its data, borrowed from `bits`,
+/// and the index after its CRC.
+pub fn find_package<'a>(
+    bits: &BitReader<'a>,
+    from: usize,
+) -> Option<(Header, BitReader<'a>, usize)> {
+    scan(bits, from, false)
+}
+
+fn scan<'a>(
+    bits: &BitReader<'a>,
+    from: usize,
+    verbose: bool,
+) -> Option<(Header, BitReader<'a>, usize)> {
+    let mut from = from;
+    while let Some(preamble_index) = bits.find(PREAMBLE as u64, PREAMBLE_LEN, from) {
+        match decode_package_at_index(bits, preamble_index + PREAMBLE_LEN) {
+            Ok(package) => return Some(package),
+            Err(error) if verbose => println!("{}", error),
+            Err(_) => {}
+        }
+        from = preamble_index + 1;
+    }
+
+    None
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PackageError {
+    /// Data longer than the size field, in bytes
+    TooLarge(usize),
+    /// Payload needing more packages than there are sequence numbers, in
+    /// bytes
+    TooManyPackages(usize),
+    /// Flags wider than their 4 bits
+    InvalidFlags(u8),
+    /// Sequence number of the first package of a payload not received
+    MissingPackage(usize),
+    SizeOutOfBounds,
+    DataOutOfBounds,
+    UnsupportedVersion(u8),
+    HeaderCrcMismatch(u8, u8),
+    CrcMismatch(u8, u8),
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackageError::TooLarge(len) => write!(f, "Data too large: {} bytes", len),
+            PackageError::TooManyPackages(len) => {
+                write!(
+                    f,
+                    "Payload too large for the sequence numbers: {} bytes",
+                    len
+                )
+            }
+            PackageError::InvalidFlags(flags) => write!(f, "Invalid flags: {:#b}", flags),
+            PackageError::MissingPackage(seq) => write!(f, "Package {} missing", seq),
+            PackageError::SizeOutOfBounds => write!(f, "Size out of bounds"),
+            PackageError::DataOutOfBounds => write!(f, "Data out of bounds"),
+            PackageError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported package version: {}", version)
+            }
+            PackageError::HeaderCrcMismatch(crc, crc_value) => {
+                write!(f, "Header CRC mismatch: {} != {}", crc, crc_value)
+            }
+            PackageError::CrcMismatch(crc, crc_value) => {
+                write!(f, "CRC mismatch: {} != {}", crc, crc_value)
             }
         }
     }
+}
 
-    return None;
+impl std::error::Error for PackageError {}
+
+/// Header fields after the preamble at `start_index` and the size of the
+/// data in bytes, if the header CRC matches.
+pub fn decode_header(
+    package_bits: &BitReader,
+    start_index: usize,
+) -> Result<(Header, usize), PackageError> {
+    let mut fields = [0u8; FIELDS_LEN / 8];
+    for (i, field) in fields.iter_mut().enumerate() {
+        *field = package_bits
+            .read_u8(start_index + i * 8)
+            .ok_or(PackageError::SizeOutOfBounds)?;
+    }
+    let (crc, fields) = fields.split_last().unwrap();
+    let crc_value = CRC.checksum(fields);
+    if *crc != crc_value {
+        return Err(PackageError::HeaderCrcMismatch(*crc, crc_value));
+    }
+    let version = fields[0] >> 4;
+    if version != PACKAGE_VERSION {
+        return Err(PackageError::UnsupportedVersion(version));
+    }
+    let header = Header {
+        flags: fields[0] & 0b1111,
+        dst: Address(fields[1]),
+        src: Address(fields[2]),
+        mode: fields[3],
+        seq: fields[4],
+    };
+    Ok((header, fields[5] as usize))
+}
+
+/// Data of `size` bytes from the start of `body`, if the CRC after it
+/// matches. The data is borrowed from `body`.
+pub fn decode_body<'a>(
+    body: &BitReader<'a>,
+    header: &Header,
+    size: usize,
+) -> Result<BitReader<'a>, PackageError> {
+    let crc_index = size * 8;
+    let crc = body
+        .read_u8(crc_index)
+        .ok_or(PackageError::DataOutOfBounds)?;
+    let data = body.slice(0, crc_index);
+    let crc_value = checksum(header, &data.to_bytes())?;
+    if crc != crc_value {
+        return Err(PackageError::CrcMismatch(crc, crc_value));
+    }
+    Ok(data)
 }
 
-fn decode_package_at_index(package_bits: &BitVec, start_index: usize) -> Option<BitVec> {
-    // read size
-    let size_index = start_index;
-    if !check_within_bounds(&package_bits, size_index, SIZE_LEN) {
-        println!("Size out of bounds");
-        return None;
+fn decode_package_at_index<'a>(
+    package_bits: &BitReader<'a>,
+    start_index: usize,
+) -> Result<(Header, BitReader<'a>, usize), PackageError> {
+    let (header, size) = decode_header(package_bits, start_index)?;
+    let data_index = start_index + FIELDS_LEN;
+    let end = data_index + size * 8 + CRC_LEN;
+    if end > package_bits.len() {
+        return Err(PackageError::DataOutOfBounds);
     }
-    let size = read_one_byte(&package_bits, size_index);
-    let data_index = size_index + SIZE_LEN;
-    let crc_index = data_index + size as usize * 8;
+    let body = package_bits.slice(data_index, end - data_index);
+    let data = decode_body(&body, &header, size)?;
+    Ok((header, data, end))
+}
+
+// Decodes packages from a live stream of bits. Every preamble that comes in
+// starts a candidate, its header is decoded once the header is in and its
+// data once the data is in, so every bit costs the same however long the
+// buffer. Bits before the oldest candidate are dropped. Packages the filter
+// doesn't accept are skipped.
+pub struct StreamDecoder {
+    pub filter: AddressFilter,
+    bits: BitWriter,
+    // the last bits pushed, to spot the preamble
+    window: u8,
+    candidates: Vec<Candidate>,
+    // header of the last package returned
+    header: Option<Header>,
+}
+
+// a preamble in the buffer, with the header and data size once read
+struct Candidate {
+    start: usize,
+    header: Option<(Header, usize)>,
+}
 
-    if !check_within_bounds(&package_bits, crc_index, CRC_LEN) {
-        println!("Data out of bounds");
-        return None;
+const MAX_PACKAGE_LEN: usize = HEADER_LEN + u8::MAX as usize * 8 + CRC_LEN;
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        StreamDecoder::with_filter(AddressFilter::default())
     }
 
-    // read data
-    let data: BitVec = package_bits
-        .iter()
-        .skip(data_index)
-        .take(size as usize * 8)
-        .collect();
+    pub fn with_filter(filter: AddressFilter) -> Self {
+        StreamDecoder {
+            filter,
+            bits: BitWriter::with_capacity(2 * MAX_PACKAGE_LEN),
+            window: 0,
+            candidates: Vec::new(),
+            header: None,
+        }
+    }
 
-    // read CRC
-    let crc = read_one_byte(&package_bits, crc_index);
-    // check CRC
-    let data_bytes = data.to_bytes();
-    let crc_value = CRC.checksum(&data_bytes);
-    if crc != crc_value {
-        println!("CRC mismatch: {} != {}", crc, crc_value);
-        return None;
+    /// Header of the package `push` returned last.
+    pub fn header(&self) -> Option<Header> {
+        self.header
     }
 
-    // debug
-    //println!("preamble_index: {}", preamble_bits_index.unwrap());
-    //println!("size_index: {}", size_index);
-    //println!("data_index_start: {}", data_index);
-    //println!("decode size: {}", size);
-    //println!("CRC: {} == {}", crc, crc_value);
+    pub fn push(&mut self, bit: bool) -> Option<BitVec> {
+        self.bits.push(bit);
+        self.window = self.window << 1 | bit as u8;
+        let len = self.bits.len();
+        if len >= PREAMBLE_LEN && self.window == PREAMBLE {
+            self.candidates.push(Candidate {
+                start: len - PREAMBLE_LEN,
+                header: None,
+            });
+        }
+
+        let bits = self.bits.reader();
+        let mut found = None;
+        let mut i = 0;
+        while i < self.candidates.len() {
+            let start = self.candidates[i].start;
+            if self.candidates[i].header.is_none() && len == start + HEADER_LEN {
+                match decode_header(&bits, start + PREAMBLE_LEN) {
+                    Ok(header) => self.candidates[i].header = Some(header),
+                    Err(_) => {
+                        self.candidates.remove(i);
+                        continue;
+                    }
+                }
+            }
+            if let Some((header, size)) = self.candidates[i].header {
+                let body_len = size * 8 + CRC_LEN;
+                if len == start + HEADER_LEN + body_len {
+                    match decode_body(&bits.slice(start + HEADER_LEN, body_len), &header, size) {
+                        Ok(data) => {
+                            found = Some((header, data.to_bitvec()));
+                            break;
+                        }
+                        Err(_) => {
+                            self.candidates.remove(i);
+                            continue;
+                        }
+                    }
+                }
+            }
+            i += 1;
+        }
+
+        if let Some((header, package)) = found {
+            // the package and everything before it is used up
+            self.bits.clear();
+            self.window = 0;
+            self.candidates.clear();
+            if !self.filter.accepts(header.dst) {
+                return None;
+            }
+            self.header = Some(header);
+            return Some(package);
+        }
 
-    Some(data)
+        // keep the oldest candidate and the bits a preamble may start in
+        let keep = self
+            .candidates
+            .first()
+            .map_or(len.saturating_sub(PREAMBLE_LEN), |candidate| {
+                candidate.start
+            });
+        if keep >= MAX_PACKAGE_LEN {
+            let dropped = keep / 8;
+            self.bits.discard_bytes(dropped);
+            for candidate in &mut self.candidates {
+                candidate.start -= dropped * 8;
+            }
+        }
+        None
+    }
 }
 
-fn read_one_byte(data: &BitVec, start: usize) -> u8 {
-    let bytes: [u8; 1] = data
-        .iter()
-        .skip(start)
-        .take(8)
-        .collect::<BitVec>()
-        .to_bytes()
-        .try_into()
-        .unwrap();
-    bytes[0]
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-fn check_within_bounds(data: &BitVec, start: usize, len: usize) -> bool {
-    start + len <= data.len()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            flags: FLAG_LAST,
+            src: Address(7),
+            dst: Address::group(2),
+            mode: 3,
+            seq: 42,
+        }
+    }
+
+    fn bytes(bits: &BitVec) -> (Vec<u8>, usize) {
+        (bits.to_bytes(), bits.len())
+    }
+
+    #[test]
+    fn header_encode_decode() {
+        let (bytes, len) = bytes(&encode_header(&header(), 200).unwrap());
+        assert_eq!(len, HEADER_LEN);
+        let bits = BitReader::new(&bytes, len);
+        assert_eq!(bits.read_u8(0), Some(PREAMBLE));
+        assert_eq!(decode_header(&bits, PREAMBLE_LEN), Ok((header(), 200)));
+    }
+
+    #[test]
+    fn header_crc_mismatch() {
+        let (mut bytes, len) = bytes(&encode_header(&header(), 200).unwrap());
+        // a flipped bit in the size
+        bytes[6] ^= 0b100;
+        let bits = BitReader::new(&bytes, len);
+        assert!(matches!(
+            decode_header(&bits, PREAMBLE_LEN),
+            Err(PackageError::HeaderCrcMismatch(_, _))
+        ));
+    }
+
+    #[test]
+    fn invalid_flags() {
+        let header = Header {
+            flags: 0b10000,
+            ..Header::default()
+        };
+        assert_eq!(
+            encode_header(&header, 0),
+            Err(PackageError::InvalidFlags(0b10000))
+        );
+        assert_eq!(
+            encode_package_with(&BitVec::new(), &header),
+            Err(PackageError::InvalidFlags(0b10000))
+        );
+    }
+
+    #[test]
+    fn package_encode_decode() {
+        let data = BitVec::from_bytes(b"Hello World!");
+        let package = encode_package_with(&data, &header()).unwrap();
+        assert_eq!(decode_package_with(&package), Some((header(), data)));
+        assert_eq!(
+            encode_package(&BitVec::from_elem(256 * 8, true)),
+            Err(PackageError::TooLarge(256))
+        );
+    }
+
+    // the packages with noise before and after each
+    fn stream(packages: &[(Header, &[u8])]) -> BitVec {
+        let mut bits = BitVec::from_bytes(&[0x5a, 0xaa, 0x3c]);
+        for (header, data) in packages {
+            bits.extend(
+                encode_package_with(&BitVec::from_bytes(data), header)
+                    .unwrap()
+                    .iter(),
+            );
+            bits.extend(BitVec::from_bytes(&[0xaa, 0x13]).iter());
+        }
+        bits
+    }
+
+    #[test]
+    fn stream_decoder() {
+        let second = Header {
+            seq: 1,
+            ..Header::default()
+        };
+        let bits = stream(&[(header(), b"first"), (second, b"second")]);
+        let mut decoder = StreamDecoder::new();
+        let mut received = Vec::new();
+        for bit in bits.iter() {
+            if let Some(data) = decoder.push(bit) {
+                received.push((decoder.header().unwrap(), data.to_bytes()));
+            }
+        }
+        assert_eq!(
+            received,
+            [(header(), b"first".to_vec()), (second, b"second".to_vec())]
+        );
+        assert_eq!(decode_packages(&bits).len(), 2);
+    }
+
+    #[test]
+    fn address_names() {
+        assert_eq!(Address::from_name("12"), Some(Address(12)));
+        assert_eq!(Address::from_name("g3"), Some(Address::group(3)));
+        assert_eq!(Address::from_name("broadcast"), Some(Address::BROADCAST));
+        // reserved for the source of transmitters without an address
+        assert_eq!(Address::from_name("0"), None);
+        assert_eq!(Address::from_name("128"), None);
+        assert_eq!(Address::from_name("g127"), None);
+        for address in [Address(12), Address::group(3), Address::BROADCAST] {
+            assert_eq!(Address::from_name(&address.to_string()), Some(address));
+        }
+    }
+
+    #[test]
+    fn stream_decoder_filter() {
+        let to_other = Header {
+            dst: Address(9),
+            ..Header::default()
+        };
+        let bits = stream(&[(to_other, b"other"), (header(), b"mine")]);
+        let mut filter = AddressFilter::new(Address(1));
+        filter.join(Address::group(2));
+        let mut decoder = StreamDecoder::with_filter(filter);
+        let received: Vec<_> = bits.iter().filter_map(|bit| decoder.push(bit)).collect();
+        assert_eq!(received, [BitVec::from_bytes(b"mine")]);
+    }
+
+    #[test]
+    fn stream_decoder_corrupt_package() {
+        let mut bits = stream(&[(header(), b"lost")]);
+        // a flipped data bit fails the data CRC
+        let index = 24 + HEADER_LEN + 3;
+        bits.set(index, !bits[index]);
+        let mut decoder = StreamDecoder::new();
+        assert!(bits.iter().all(|bit| decoder.push(bit).is_none()));
+    }
+
+    #[test]
+    fn stream_decoder_long_noise() {
+        // more noise than the decoder buffers before a package
+        let mut bits: BitVec = (0..5 * MAX_PACKAGE_LEN).map(|i| i % 3 == 0).collect();
+        bits.extend(stream(&[(header(), b"late")]).iter());
+        let mut decoder = StreamDecoder::new();
+        let received: Vec<_> = bits.iter().filter_map(|bit| decoder.push(bit)).collect();
+        assert_eq!(received, [BitVec::from_bytes(b"late")]);
+    }
 }
@@ -0,0 +1,346 @@
+// Frames to any backend, the mirror of `source`.
+//
+// The transmitter shows its frames in a window or streams them over HTTP,
+// recorders write PNG sequences or video files, and tests keep them in
+// memory. Every sink takes the same RGB frames with timestamps.
+
+use crate::util::source::Frame;
+use image::codecs::jpeg::JpegEncoder;
+use image::RgbImage;
+#[cfg(feature = "opencv")]
+use opencv::core::Mat;
+#[cfg(feature = "opencv")]
+use opencv::prelude::*;
+#[cfg(feature = "opencv")]
+use opencv::{highgui, imgproc};
+use rayon::prelude::*;
+use std::error::Error;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs};
+
+pub type SinkResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const MJPEG_RESPONSE: &[u8] =
+    b"HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary=frame\r\n\r\n";
+
+pub trait FrameSink {
+    /// Write one frame. Returns `false` when the sink wants to stop, e.g.
+    /// the window was closed.
+    fn write_frame(&mut self, frame: &Frame) -> SinkResult<bool>;
+
+    /// Write consecutive frames, one after the other unless the sink can
+    /// encode them at once.
+    fn write_frames(&mut self, frames: &[Frame]) -> SinkResult<bool> {
+        for frame in frames {
+            if !self.write_frame(frame)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Flush everything, called once after the last frame.
+    fn finish(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+
+    /// Whether frames are shown live and have to be paced.
+    fn realtime(&self) -> bool {
+        false
+    }
+}
+
+/// Numbered PNG files `frame_0000.png`, ... in a new or empty directory.
+pub struct PngSink {
+    pub dir: String,
+    index: usize,
+}
+
+impl PngSink {
+    /// Fails if `dir` exists and isn't empty, nothing is overwritten.
+    pub fn new(dir: &str) -> SinkResult<Self> {
+        if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(format!("{} exists and is not empty", dir).into());
+        }
+        fs::create_dir_all(dir)?;
+        Ok(PngSink {
+            dir: dir.to_string(),
+            index: 0,
+        })
+    }
+
+    /// ffmpeg pattern of the file names
+    pub fn pattern(&self) -> String {
+        format!("{}/frame_%04d.png", self.dir)
+    }
+
+    fn frame_path(&self, index: usize) -> String {
+        format!("{}/frame_{:04}.png", self.dir, index)
+    }
+}
+
+impl FrameSink for PngSink {
+    fn write_frame(&mut self, frame: &Frame) -> SinkResult<bool> {
+        frame.image.save(self.frame_path(self.index))?;
+        self.index += 1;
+        Ok(true)
+    }
+
+    // every file has its own name, so they are compressed in parallel
+    fn write_frames(&mut self, frames: &[Frame]) -> SinkResult<bool> {
+        frames
+            .par_iter()
+            .enumerate()
+            .try_for_each(|(i, frame)| frame.image.save(self.frame_path(self.index + i)))?;
+        self.index += frames.len();
+        Ok(true)
+    }
+}
+
+/// H.264 video written by the ffmpeg command line tool at a constant frame
+/// rate. The frames are collected as PNGs in a temporary directory of their
+/// own and encoded on `finish`.
+pub struct FfmpegSink {
+    pub output: String,
+    /// "30" or "30000/1001"
+    pub frame_rate: String,
+    /// Copy the audio of this file, if it has any
+    pub audio_from: Option<String>,
+    frames: PngSink,
+}
+
+impl FfmpegSink {
+    pub fn new(output: &str, frame_rate: &str) -> SinkResult<Self> {
+        Ok(FfmpegSink {
+            output: output.to_string(),
+            frame_rate: frame_rate.to_string(),
+            audio_from: None,
+            frames: PngSink::new(&frames_dir())?,
+        })
+    }
+}
+
+// a directory no other sink of any process uses
+fn frames_dir() -> String {
+    static SINKS: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "lightchannel_frames_{}_{}",
+        process::id(),
+        SINKS.fetch_add(1, Ordering::Relaxed)
+    );
+    env::temp_dir().join(name).to_string_lossy().into_owned()
+}
+
+impl FrameSink for FfmpegSink {
+    fn write_frame(&mut self, frame: &Frame) -> SinkResult<bool> {
+        self.frames.write_frame(frame)
+    }
+
+    fn write_frames(&mut self, frames: &[Frame]) -> SinkResult<bool> {
+        self.frames.write_frames(frames)
+    }
+
+    fn finish(&mut self) -> SinkResult<()> {
+        let frames_pattern = self.frames.pattern();
+        let mut args = vec![
+            "-framerate",
+            self.frame_rate.as_str(),
+            "-i",
+            frames_pattern.as_str(),
+        ];
+        if let Some(audio_input) = &self.audio_from {
+            args.extend([
+                "-i",
+                audio_input.as_str(),
+                "-map",
+                "0:v",
+                "-map",
+                "1:a?",
+                "-c:a",
+                "copy",
+            ]);
+        }
+        args.extend([
+            "-c:v",
+            "libx264",
+            "-pix_fmt",
+            "yuv420p",
+            "-y",
+            "-hide_banner",
+            "-loglevel",
+            "error",
+            self.output.as_str(),
+        ]);
+        let ffmpeg_status = Command::new("ffmpeg").args(&args).status()?;
+        fs::remove_dir_all(&self.frames.dir).ok();
+
+        if !ffmpeg_status.success() {
+            return Err(format!("Failed to generate {}", self.output).into());
+        }
+        println!("Video saved as {}", self.output);
+        Ok(())
+    }
+}
+
+impl Drop for FfmpegSink {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.frames.dir).ok();
+    }
+}
+
+/// Fullscreen (or resizable) opencv window, escape stops.
+#[cfg(feature = "opencv")]
+pub struct WindowSink {
+    name: String,
+}
+
+#[cfg(feature = "opencv")]
+impl WindowSink {
+    pub fn new(name: &str, fullscreen: bool) -> SinkResult<Self> {
+        highgui::named_window(name, highgui::WINDOW_NORMAL)?;
+        if fullscreen {
+            highgui::set_window_property(
+                name,
+                highgui::WND_PROP_FULLSCREEN,
+                highgui::WINDOW_FULLSCREEN as f64,
+            )?;
+        }
+        Ok(WindowSink {
+            name: name.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "opencv")]
+impl FrameSink for WindowSink {
+    fn write_frame(&mut self, frame: &Frame) -> SinkResult<bool> {
+        let data = Mat::from_slice(frame.image.as_raw())?;
+        let rgb = data.reshape(3, frame.image.height() as i32)?;
+        let mut bgr = Mat::default();
+        imgproc::cvt_color_def(&*rgb, &mut bgr, imgproc::COLOR_RGB2BGR)?;
+        highgui::imshow(&self.name, &bgr)?;
+        // pump the window events, escape stops
+        Ok(highgui::poll_key()? != 27)
+    }
+
+    fn realtime(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "opencv")]
+impl Drop for WindowSink {
+    fn drop(&mut self) {
+        highgui::destroy_window(&self.name).ok();
+    }
+}
+
+/// MJPEG stream over HTTP, viewable in any browser.
+pub struct MjpegSink {
+    stream: TcpStream,
+    pub quality: u8,
+}
+
+impl MjpegSink {
+    pub fn new(mut stream: TcpStream) -> SinkResult<Self> {
+        stream.write_all(MJPEG_RESPONSE)?;
+        Ok(MjpegSink {
+            stream,
+            quality: 80,
+        })
+    }
+
+    /// Listen on `address` and wait for the first client.
+    pub fn accept(address: &str) -> SinkResult<Self> {
+        let listener = TcpListener::bind(address)?;
+        println!("Listening for connections at {}", address);
+        let (stream, client) = listener.accept()?;
+        println!("Client connected: {}", client);
+        MjpegSink::new(stream)
+    }
+}
+
+impl FrameSink for MjpegSink {
+    fn write_frame(&mut self, frame: &Frame) -> SinkResult<bool> {
+        let jpeg_bytes = jpeg_bytes(&frame.image, self.quality)?;
+        let header = format!(
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg_bytes.len()
+        );
+        self.stream
+            .write_all(&[header.as_bytes(), jpeg_bytes.as_slice()].concat())?;
+        Ok(true)
+    }
+
+    fn realtime(&self) -> bool {
+        true
+    }
+}
+
+/// Frames kept in memory, e.g. of a headless transmitter.
+#[derive(Default)]
+pub struct MemorySink {
+    pub frames: Vec<Frame>,
+}
+
+impl FrameSink for MemorySink {
+    fn write_frame(&mut self, frame: &Frame) -> SinkResult<bool> {
+        self.frames.push(frame.clone());
+        Ok(true)
+    }
+}
+
+/// Sink selected on the command line: "window", "http://ADDRESS", a video
+/// file (mp4, mkv, avi, mov) or a new or empty directory for PNG frames.
+pub fn open_sink(name: &str, fps: f64) -> SinkResult<Box<dyn FrameSink>> {
+    if name == "window" {
+        #[cfg(feature = "opencv")]
+        return Ok(Box::new(WindowSink::new("lightchannel", true)?));
+        #[cfg(not(feature = "opencv"))]
+        return Err("A window needs the opencv feature".into());
+    }
+    if let Some(address) = name.strip_prefix("http://") {
+        return Ok(Box::new(MjpegSink::accept(address)?));
+    }
+    let is_video = name
+        .rsplit_once('.')
+        .is_some_and(|(_, ext)| ["mp4", "mkv", "avi", "mov"].contains(&ext));
+    if is_video {
+        return Ok(Box::new(FfmpegSink::new(name, &fps.to_string())?));
+    }
+    Ok(Box::new(PngSink::new(name)?))
+}
+
+fn jpeg_bytes(image: &RgbImage, quality: u8) -> SinkResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    JpegEncoder::new_with_quality(&mut bytes, quality).encode(
+        image,
+        image.width(),
+        image.height(),
+        image::ExtendedColorType::Rgb8,
+    )?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_sink_keeps_existing_files() {
+        let dir = frames_dir();
+        PngSink::new(&dir).unwrap();
+        // empty, so it can be used again
+        PngSink::new(&dir).unwrap();
+        fs::write(format!("{}/notes.txt", dir), "keep").unwrap();
+        assert!(PngSink::new(&dir).is_err());
+        assert_eq!(
+            fs::read_to_string(format!("{}/notes.txt", dir)).unwrap(),
+            "keep"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+// Frames from any backend.
+//
+// Cameras are read with nokhwa or opencv, files with ffmpeg, and recorded
+// or synthetic frames are replayed from memory or a directory of PNGs. All
+// of them yield RGB frames with a timestamp since the start of the source,
+// so the receiver, the motion effects and the recorder don't care where the
+// frames come from.
+
+#[cfg(feature = "ffmpeg")]
+use crate::util::video::RgbConverter;
+#[cfg(feature = "ffmpeg")]
+use ffmpeg_next::{codec, format, frame, media};
+use image::{imageops, GrayImage, RgbImage};
+#[cfg(feature = "opencv")]
+use mat2image::ToImage;
+#[cfg(feature = "camera")]
+use nokhwa::{
+    pixel_format::RgbFormat,
+    utils::{
+        CameraFormat, CameraIndex, FrameFormat, RequestedFormat, RequestedFormatType, Resolution,
+    },
+    CallbackCamera,
+};
+#[cfg(feature = "opencv")]
+use opencv::core::Mat;
+#[cfg(feature = "opencv")]
+use opencv::prelude::*;
+#[cfg(feature = "opencv")]
+use opencv::videoio::{VideoCapture, CAP_ANY, CAP_PROP_FPS, CAP_PROP_POS_MSEC};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+#[cfg(feature = "camera")]
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+#[cfg(any(feature = "opencv", feature = "camera"))]
+use std::time::Instant;
+
+pub type SourceResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+#[derive(Clone)]
+pub struct Frame {
+    pub image: RgbImage,
+    /// Time since the first frame of the source
+    pub timestamp: Duration,
+}
+
+impl Frame {
+    pub fn luma(&self) -> GrayImage {
+        imageops::grayscale(&self.image)
+    }
+}
+
+pub trait FrameSource {
+    /// Next frame, `None` once the source is exhausted.
+    fn next_frame(&mut self) -> SourceResult<Option<Frame>>;
+
+    /// Nominal frame rate, if the backend knows it.
+    fn fps(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// Camera or video file read with opencv.
+#[cfg(feature = "opencv")]
+pub struct OpenCvSource {
+    cam: VideoCapture,
+    is_file: bool,
+    start: Option<Instant>,
+    mat: Mat,
+}
+
+#[cfg(feature = "opencv")]
+impl OpenCvSource {
+    pub fn camera(index: i32) -> SourceResult<Self> {
+        let cam = VideoCapture::new(index, CAP_ANY)?;
+        if !cam.is_opened()? {
+            return Err(format!("Unable to open camera {}", index).into());
+        }
+        Ok(OpenCvSource {
+            cam,
+            is_file: false,
+            start: None,
+            mat: Mat::default(),
+        })
+    }
+
+    pub fn file(path: &str) -> SourceResult<Self> {
+        let cam = VideoCapture::from_file(path, CAP_ANY)?;
+        if !cam.is_opened()? {
+            return Err(format!("Unable to open {}", path).into());
+        }
+        Ok(OpenCvSource {
+            cam,
+            is_file: true,
+            start: None,
+            mat: Mat::default(),
+        })
+    }
+
+    /// The last frame as opencv Mat (BGR), to avoid a conversion.
+    pub fn mat(&self) -> &Mat {
+        &self.mat
+    }
+}
+
+#[cfg(feature = "opencv")]
+impl FrameSource for OpenCvSource {
+    fn next_frame(&mut self) -> SourceResult<Option<Frame>> {
+        loop {
+            if !self.cam.read(&mut self.mat)? {
+                return Ok(None);
+            }
+            if self.mat.size()?.width > 0 {
+                break;
+            }
+        }
+        let timestamp = if self.is_file {
+            Duration::from_secs_f64(self.cam.get(CAP_PROP_POS_MSEC)?.max(0.0) / 1000.0)
+        } else {
+            self.start.get_or_insert_with(Instant::now).elapsed()
+        };
+        let image = self.mat.to_image()?.to_rgb8();
+        Ok(Some(Frame { image, timestamp }))
+    }
+
+    fn fps(&self) -> Option<f64> {
+        self.cam.get(CAP_PROP_FPS).ok().filter(|fps| *fps > 0.0)
+    }
+}
+
+/// Camera read with nokhwa, frames arrive on the callback thread.
+#[cfg(feature = "camera")]
+pub struct NokhwaSource {
+    camera: CallbackCamera,
+    frames: Receiver<Frame>,
+    fps: u32,
+}
+
+#[cfg(feature = "camera")]
+impl NokhwaSource {
+    pub fn new(index: u32, width: u32, height: u32, fps: u32) -> SourceResult<Self> {
+        let camera_format =
+            CameraFormat::new(Resolution::new(width, height), FrameFormat::NV12, fps);
+        let requested =
+            RequestedFormat::new::<RgbFormat>(RequestedFormatType::Exact(camera_format));
+
+        let (sender, frames) = channel();
+        let start = Instant::now();
+        let mut camera = CallbackCamera::new(CameraIndex::Index(index), requested, move |frame| {
+            let timestamp = start.elapsed();
+            let image = match frame.decode_image::<RgbFormat>() {
+                Ok(image) if image.width() > 0 && image.height() > 0 => image,
+                _ => {
+                    println!("Error decoding frame");
+                    return;
+                }
+            };
+            // the receiver is gone once the source is dropped
+            sender.send(Frame { image, timestamp }).ok();
+        })?;
+        camera.open_stream()?;
+
+        Ok(NokhwaSource {
+            camera,
+            frames,
+            fps,
+        })
+    }
+}
+
+#[cfg(feature = "camera")]
+impl FrameSource for NokhwaSource {
+    fn next_frame(&mut self) -> SourceResult<Option<Frame>> {
+        // a camera never ends, but it may stop delivering
+        match self.frames.recv_timeout(Duration::from_secs(5)) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(_) => Err("Camera stopped delivering frames".into()),
+        }
+    }
+
+    fn fps(&self) -> Option<f64> {
+        Some(self.fps as f64)
+    }
+}
+
+#[cfg(feature = "camera")]
+impl Drop for NokhwaSource {
+    fn drop(&mut self) {
+        self.camera.stop_stream().ok();
+    }
+}
+
+/// Video file decoded with ffmpeg, timestamps from the stream.
+#[cfg(feature = "ffmpeg")]
+pub struct FfmpegSource {
+    input: format::context::Input,
+    decoder: ffmpeg_next::decoder::Video,
+    stream_index: usize,
+    time_base: f64,
+    fps: f64,
+    converter: RgbConverter,
+    decoded: frame::Video,
+    first_time: Option<f64>,
+    eof: bool,
+}
+
+#[cfg(feature = "ffmpeg")]
+impl FfmpegSource {
+    pub fn new(path: &str) -> SourceResult<Self> {
+        ffmpeg_next::init()?;
+        let input = format::input(&path)?;
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or("No video stream")?;
+        let stream_index = stream.index();
+        let time_base = f64::from(stream.time_base());
+        let fps = f64::from(stream.avg_frame_rate());
+        let decoder = codec::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+
+        Ok(FfmpegSource {
+            input,
+            decoder,
+            stream_index,
+            time_base,
+            fps,
+            converter: RgbConverter::new(),
+            decoded: frame::Video::empty(),
+            first_time: None,
+            eof: false,
+        })
+    }
+}
+
+#[cfg(feature = "ffmpeg")]
+impl FrameSource for FfmpegSource {
+    fn next_frame(&mut self) -> SourceResult<Option<Frame>> {
+        loop {
+            if self.decoder.receive_frame(&mut self.decoded).is_ok() {
+                let time = self
+                    .decoded
+                    .timestamp()
+                    .map(|timestamp| timestamp as f64 * self.time_base)
+                    .unwrap_or(0.0);
+                let first_time = *self.first_time.get_or_insert(time);
+                return Ok(Some(Frame {
+                    image: self.converter.convert(&self.decoded),
+                    timestamp: Duration::from_secs_f64((time - first_time).max(0.0)),
+                }));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+
+            // feed the next packet of the video stream
+            match self.input.packets().next() {
+                Some((stream, packet)) => {
+                    if stream.index() == self.stream_index {
+                        self.decoder.send_packet(&packet)?;
+                    }
+                }
+                None => {
+                    self.decoder.send_eof()?;
+                    self.eof = true;
+                }
+            }
+        }
+    }
+
+    fn fps(&self) -> Option<f64> {
+        Some(self.fps).filter(|fps| *fps > 0.0)
+    }
+}
+
+/// Frames from memory, e.g. rendered by the transmitter or a recording.
+pub struct ReplaySource {
+    frames: VecDeque<RgbImage>,
+    fps: f64,
+    index: u32,
+}
+
+impl ReplaySource {
+    pub fn new(frames: Vec<RgbImage>, fps: f64) -> Self {
+        ReplaySource {
+            frames: frames.into(),
+            fps,
+            index: 0,
+        }
+    }
+
+    pub fn from_gray(frames: &[GrayImage], fps: f64) -> Self {
+        let frames = frames
+            .iter()
+            .map(|frame| image::DynamicImage::ImageLuma8(frame.clone()).to_rgb8())
+            .collect();
+        ReplaySource::new(frames, fps)
+    }
+
+    /// PNG files of a directory in name order, like the recordings of
+    /// `stream_nokhwa`.
+    pub fn from_dir(dir: &str, fps: f64) -> SourceResult<Self> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "png"))
+            .collect();
+        paths.sort();
+        let mut frames = Vec::with_capacity(paths.len());
+        for path in paths {
+            frames.push(image::open(path)?.to_rgb8());
+        }
+        Ok(ReplaySource::new(frames, fps))
+    }
+}
+
+impl FrameSource for ReplaySource {
+    fn next_frame(&mut self) -> SourceResult<Option<Frame>> {
+        let timestamp = Duration::from_secs_f64(self.index as f64 / self.fps);
+        self.index += 1;
+        Ok(self
+            .frames
+            .pop_front()
+            .map(|image| Frame { image, timestamp }))
+    }
+
+    fn fps(&self) -> Option<f64> {
+        Some(self.fps)
+    }
+}
+
+/// Source selected on the command line: "camera", "camera:N",
+/// "nokhwa", "nokhwa:N", a directory of PNGs or a video file.
+pub fn open_source(name: &str) -> SourceResult<Box<dyn FrameSource>> {
+    // only the camera kinds take an index, paths may contain a ':'
+    let (kind, index) = match name.split_once(':') {
+        Some((kind @ ("camera" | "nokhwa"), index)) => (
+            kind,
+            index
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid camera index: {}", index))?,
+        ),
+        _ => (name, 0),
+    };
+    Ok(match kind {
+        #[cfg(feature = "opencv")]
+        "camera" => Box::new(OpenCvSource::camera(index as i32)?),
+        #[cfg(not(feature = "opencv"))]
+        "camera" => return Err(format!("Camera {} needs the opencv feature", index).into()),
+        #[cfg(feature = "camera")]
+        "nokhwa" => Box::new(NokhwaSource::new(index, 1280, 720, 30)?),
+        #[cfg(not(feature = "camera"))]
+        "nokhwa" => return Err(format!("Camera {} needs the camera feature", index).into()),
+        _ if fs::metadata(name).is_ok_and(|meta| meta.is_dir()) => {
+            Box::new(ReplaySource::from_dir(name, 30.0)?)
+        }
+        #[cfg(feature = "ffmpeg")]
+        _ => Box::new(FfmpegSource::new(name)?),
+        #[cfg(not(feature = "ffmpeg"))]
+        _ => return Err("Video files need the ffmpeg feature".into()),
+    })
+}
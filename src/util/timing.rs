@@ -0,0 +1,194 @@
+// Sample at the symbol instants instead of once per decoded frame.
+//
+// Phone recordings and screen captures have a variable frame rate: frames
+// are dropped, repeated or arrive late. Using the presentation timestamps
+// every frame is assigned to the symbol slot it was shown in and every
+// symbol k is read from the frame closest to t0 + (k + 0.5) / symbol_rate.
+// Extra frames in a slot are duplicates, slots without a frame are missing
+// and repeat the previous frame. A timestamp far past the others is
+// corrupt and treated like a missing one, the slots stay a few per frame.
+
+use crate::util::sampler::Sample;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TimingReport {
+    pub frames: usize,
+    pub symbols: usize,
+    /// Frames sharing a symbol slot with an earlier frame
+    pub duplicated: usize,
+    /// Symbol slots without a frame of their own
+    pub missing: usize,
+    /// Frames without a finite timestamp or with one far past the first
+    /// frame, placed at their nominal time
+    pub untimed: usize,
+    /// Average time between frames in seconds
+    pub frame_interval: f64,
+}
+
+// symbol slots per frame at most, e.g. three of four frames dropped
+const MAX_SYMBOLS_PER_FRAME: usize = 4;
+
+/// Resample `(seconds, sample)` pairs to one sample per symbol.
+pub fn resample(frames: &[(Option<f64>, Sample)], symbol_rate: f64) -> (Vec<Sample>, TimingReport) {
+    assert!(
+        symbol_rate.is_finite() && symbol_rate > 0.0,
+        "Invalid symbol rate: {}",
+        symbol_rate
+    );
+    let mut report = TimingReport {
+        frames: frames.len(),
+        ..Default::default()
+    };
+    if frames.is_empty() {
+        return (Vec::new(), report);
+    }
+
+    let period = 1.0 / symbol_rate;
+    let max_span = (frames.len() * MAX_SYMBOLS_PER_FRAME) as f64 * period;
+    let mut times: Vec<f64> = Vec::with_capacity(frames.len());
+    for (i, (time, _)) in frames.iter().enumerate() {
+        let in_span = |time: f64| times.first().is_none_or(|first| time - first < max_span);
+        let time = match time {
+            Some(time) if time.is_finite() && in_span(*time) => *time,
+            _ => {
+                report.untimed += 1;
+                // continue from the previous frame
+                times
+                    .last()
+                    .map(|last| last + period)
+                    .unwrap_or(i as f64 * period)
+            }
+        };
+        times.push(time);
+    }
+
+    // timestamps may go backwards, the slots have to reach the latest one
+    let start = times[0];
+    let end = times.iter().copied().fold(start, f64::max);
+    // symbol shown at a time, tolerating timestamps slightly too early
+    let slot_of = |time: f64| ((time - start) / period + 0.1).floor().max(0.0) as usize;
+    report.symbols = slot_of(end) + 1;
+    if times.len() > 1 {
+        report.frame_interval = (end - start) / (times.len() - 1) as f64;
+    }
+
+    // per slot the frame closest to the symbol center
+    let mut slots: Vec<Option<(f64, Sample)>> = vec![None; report.symbols];
+    for (time, (_, sample)) in times.iter().zip(frames.iter()) {
+        let slot = slot_of(*time);
+        let distance = (time - (start + (slot as f64 + 0.5) * period)).abs();
+        match slots[slot] {
+            Some((best, _)) => {
+                report.duplicated += 1;
+                if distance < best {
+                    slots[slot] = Some((distance, *sample));
+                }
+            }
+            None => slots[slot] = Some((distance, *sample)),
+        }
+    }
+
+    // missing slots repeat the frame still on screen
+    let mut samples = Vec::with_capacity(report.symbols);
+    let mut previous = frames[0].1;
+    for slot in slots {
+        match slot {
+            Some((_, sample)) => {
+                previous = sample;
+                samples.push(sample);
+            }
+            None => {
+                report.missing += 1;
+                samples.push(previous);
+            }
+        }
+    }
+
+    (samples, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(value: f32) -> Sample {
+        Sample {
+            value,
+            bit: value > 0.5,
+        }
+    }
+
+    fn values(samples: &[Sample]) -> Vec<f32> {
+        samples.iter().map(|sample| sample.value).collect()
+    }
+
+    #[test]
+    fn one_frame_per_symbol() {
+        let frames: Vec<_> = (0..4)
+            .map(|i| (Some(i as f64 * 0.1), sample(i as f32)))
+            .collect();
+        let (samples, report) = resample(&frames, 10.0);
+        assert_eq!(values(&samples), [0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(report.symbols, 4);
+        assert_eq!(report.duplicated, 0);
+        assert_eq!(report.missing, 0);
+    }
+
+    #[test]
+    fn duplicated_and_missing() {
+        // two frames in the first slot, none in the third
+        let frames = [
+            (Some(0.0), sample(1.0)),
+            (Some(0.04), sample(2.0)),
+            (Some(0.1), sample(3.0)),
+            (Some(0.3), sample(4.0)),
+        ];
+        let (samples, report) = resample(&frames, 10.0);
+        assert_eq!(values(&samples), [2.0, 3.0, 3.0, 4.0]);
+        assert_eq!(report.duplicated, 1);
+        assert_eq!(report.missing, 1);
+    }
+
+    #[test]
+    fn untimed_frames() {
+        let frames = [
+            (Some(0.0), sample(1.0)),
+            (None, sample(2.0)),
+            (Some(f64::NAN), sample(3.0)),
+        ];
+        let (samples, report) = resample(&frames, 10.0);
+        assert_eq!(values(&samples), [1.0, 2.0, 3.0]);
+        assert_eq!(report.untimed, 2);
+    }
+
+    #[test]
+    fn corrupt_timestamp() {
+        let frames = [
+            (Some(0.0), sample(1.0)),
+            (Some(1e9), sample(2.0)),
+            (Some(0.2), sample(3.0)),
+        ];
+        let (samples, report) = resample(&frames, 10.0);
+        assert_eq!(report.symbols, 3);
+        assert_eq!(report.untimed, 1);
+        assert_eq!(values(&samples), [1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid symbol rate")]
+    fn zero_symbol_rate() {
+        resample(&[(Some(0.0), sample(1.0))], 0.0);
+    }
+
+    #[test]
+    fn timestamps_going_backwards() {
+        let frames = [
+            (Some(0.0), sample(1.0)),
+            (Some(0.3), sample(2.0)),
+            (Some(0.1), sample(3.0)),
+        ];
+        let (samples, report) = resample(&frames, 10.0);
+        assert_eq!(report.symbols, 4);
+        assert_eq!(values(&samples), [1.0, 3.0, 3.0, 2.0]);
+    }
+}
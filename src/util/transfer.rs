@@ -0,0 +1,453 @@
+// Files over the light channel.
+//
+// A manifest with the name, size and SHA-256 of the file is followed by
+// chunks with their offset, see util::message, every message fits into one
+// package. The receiver writes the chunks into a partial file next to a
+// small state file listing the received chunks, so a replayed video only
+// has to fill the gaps. The file gets its name once all chunks are there
+// and the hash matches.
+
+use crate::util::message::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Chunk data per message, so that a chunk fits into a package of 255 bytes
+pub const DEFAULT_CHUNK_SIZE: u32 = 224;
+/// Largest file a receiver accepts unless told otherwise, in bytes
+pub const DEFAULT_MAX_SIZE: u64 = 64 << 20;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileManifest {
+    /// Links the chunks to the manifest, from the hash
+    pub id: u32,
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+    pub chunk_size: u32,
+    pub chunk_count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileChunk {
+    pub id: u32,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Manifest followed by the chunks of the file.
+pub fn file_messages(path: &Path, chunk_size: u32) -> io::Result<Vec<Message>> {
+    let data = fs::read(path)?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("file")
+        .to_string();
+    let sha256: [u8; 32] = Sha256::digest(&data).into();
+    let id = u32::from_be_bytes(sha256[..4].try_into().unwrap());
+
+    let chunks = data.chunks(chunk_size as usize);
+    let mut messages = vec![Message::FileManifest(FileManifest {
+        id,
+        name,
+        size: data.len() as u64,
+        sha256,
+        chunk_size,
+        chunk_count: chunks.len() as u32,
+    })];
+    for (index, chunk) in chunks.enumerate() {
+        messages.push(Message::FileChunk(FileChunk {
+            id,
+            offset: index as u64 * chunk_size as u64,
+            data: chunk.to_vec(),
+        }));
+    }
+    Ok(messages)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransferEvent {
+    /// Not a file message, a chunk of another file, one already received
+    /// or one outside the file
+    Ignored,
+    /// New manifest, `received` chunks are already in the partial file
+    Started {
+        name: String,
+        received: u32,
+    },
+    Chunk {
+        received: u32,
+        total: u32,
+    },
+    /// All chunks received and the hash matches
+    Completed(PathBuf),
+    /// All chunks received but the hash doesn't match, starts over
+    HashMismatch,
+}
+
+// chunks received so far, saved next to the partial file
+#[derive(Serialize, Deserialize)]
+struct TransferState {
+    sha256: [u8; 32],
+    received: Vec<bool>,
+}
+
+pub struct FileReceiver {
+    /// Manifests of larger files are rejected
+    pub max_size: u64,
+    dir: PathBuf,
+    manifest: Option<FileManifest>,
+    received: Vec<bool>,
+    file: Option<File>,
+    // chunks seen before their manifest
+    pending: Vec<FileChunk>,
+}
+
+// pending chunks kept while waiting for a manifest
+const MAX_PENDING: usize = 1024;
+
+impl FileReceiver {
+    pub fn new(dir: &Path) -> Self {
+        FileReceiver {
+            max_size: DEFAULT_MAX_SIZE,
+            dir: dir.to_path_buf(),
+            manifest: None,
+            received: Vec::new(),
+            file: None,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn manifest(&self) -> Option<&FileManifest> {
+        self.manifest.as_ref()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.manifest.is_some() && self.received.iter().all(|received| *received)
+    }
+
+    /// Byte ranges still missing of the current file.
+    pub fn missing_ranges(&self) -> Vec<Range<u64>> {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            None => return Vec::new(),
+        };
+        let chunk_size = manifest.chunk_size as u64;
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for (index, _) in self.received.iter().enumerate().filter(|(_, r)| !**r) {
+            let start = index as u64 * chunk_size;
+            let end = (start + chunk_size).min(manifest.size);
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        }
+        ranges
+    }
+
+    pub fn push(&mut self, message: Message) -> io::Result<TransferEvent> {
+        match message {
+            Message::FileManifest(manifest) => self.start(manifest),
+            Message::FileChunk(chunk) => self.write_chunk(chunk),
+            _ => Ok(TransferEvent::Ignored),
+        }
+    }
+
+    // an invalid manifest is an `InvalidData` error, nothing is allocated
+    // or created for it
+    fn start(&mut self, manifest: FileManifest) -> io::Result<TransferEvent> {
+        if self.manifest.as_ref() == Some(&manifest) {
+            return Ok(TransferEvent::Ignored);
+        }
+        check_manifest(&manifest, self.max_size)?;
+
+        // received before, e.g. by an earlier run
+        let path = self.dir.join(file_name(&manifest.name));
+        if fs::read(&path).is_ok_and(|data| Sha256::digest(data)[..] == manifest.sha256) {
+            self.received = vec![true; manifest.chunk_count as usize];
+            self.manifest = Some(manifest);
+            self.file = None;
+            return Ok(TransferEvent::Completed(path));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let part_path = self.part_path(&manifest.name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part_path)?;
+        file.set_len(manifest.size)?;
+
+        // resume if the state belongs to the same file
+        let state = fs::read(self.state_path(&manifest.name))
+            .ok()
+            .and_then(|bytes| bincode::deserialize::<TransferState>(&bytes).ok())
+            .filter(|state| {
+                state.sha256 == manifest.sha256
+                    && state.received.len() == manifest.chunk_count as usize
+            });
+        self.received = match state {
+            Some(state) => state.received,
+            None => vec![false; manifest.chunk_count as usize],
+        };
+        let event = TransferEvent::Started {
+            name: manifest.name.clone(),
+            received: self.received_count(),
+        };
+        self.manifest = Some(manifest);
+        self.file = Some(file);
+
+        for chunk in std::mem::take(&mut self.pending) {
+            let chunk_event = self.write_chunk(chunk)?;
+            if matches!(
+                chunk_event,
+                TransferEvent::Completed(_) | TransferEvent::HashMismatch
+            ) {
+                return Ok(chunk_event);
+            }
+        }
+        // nothing left to receive, e.g. an empty file
+        if self.is_complete() {
+            return self.finish();
+        }
+        Ok(event)
+    }
+
+    fn write_chunk(&mut self, chunk: FileChunk) -> io::Result<TransferEvent> {
+        let manifest = match &self.manifest {
+            Some(manifest) if manifest.id == chunk.id => manifest,
+            _ => {
+                if self.pending.len() < MAX_PENDING {
+                    self.pending.push(chunk);
+                }
+                return Ok(TransferEvent::Ignored);
+            }
+        };
+        // the file is closed once complete
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(TransferEvent::Ignored),
+        };
+
+        let chunk_size = manifest.chunk_size as u64;
+        let index = (chunk.offset / chunk_size) as usize;
+        let in_bounds = chunk.offset.is_multiple_of(chunk_size)
+            && chunk.data.len() as u64 <= chunk_size
+            && chunk
+                .offset
+                .checked_add(chunk.data.len() as u64)
+                .is_some_and(|end| end <= manifest.size);
+        if !in_bounds || index >= self.received.len() || self.received[index] {
+            return Ok(TransferEvent::Ignored);
+        }
+
+        file.seek(SeekFrom::Start(chunk.offset))?;
+        file.write_all(&chunk.data)?;
+        self.received[index] = true;
+        let state = TransferState {
+            sha256: manifest.sha256,
+            received: self.received.clone(),
+        };
+        fs::write(
+            self.state_path(&manifest.name),
+            bincode::serialize(&state).expect("Failed to serialize"),
+        )?;
+
+        if self.is_complete() {
+            return self.finish();
+        }
+        Ok(TransferEvent::Chunk {
+            received: self.received_count(),
+            total: manifest.chunk_count,
+        })
+    }
+
+    // verify the hash and give the file its name
+    fn finish(&mut self) -> io::Result<TransferEvent> {
+        let manifest = self.manifest.clone().expect("No manifest");
+        let file = self.file.as_mut().expect("No file");
+        file.flush()?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        file.read_to_end(&mut data)?;
+
+        let sha256: [u8; 32] = Sha256::digest(&data).into();
+        if sha256 != manifest.sha256 {
+            self.received
+                .iter_mut()
+                .for_each(|received| *received = false);
+            fs::remove_file(self.state_path(&manifest.name)).ok();
+            return Ok(TransferEvent::HashMismatch);
+        }
+
+        let path = self.dir.join(file_name(&manifest.name));
+        self.file = None;
+        fs::rename(self.part_path(&manifest.name), &path)?;
+        fs::remove_file(self.state_path(&manifest.name)).ok();
+        Ok(TransferEvent::Completed(path))
+    }
+
+    fn received_count(&self) -> u32 {
+        self.received.iter().filter(|received| **received).count() as u32
+    }
+
+    // names are taken from the manifest, keep them inside `dir`
+    fn part_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", file_name(name)))
+    }
+
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.part.state", file_name(name)))
+    }
+}
+
+// the manifest arrives over the light channel like everything else
+fn check_manifest(manifest: &FileManifest, max_size: u64) -> io::Result<()> {
+    let invalid = |reason: String| Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+    if manifest.chunk_size == 0 {
+        return invalid("Manifest with a chunk size of 0".to_string());
+    }
+    if manifest.size > max_size {
+        return invalid(format!(
+            "File of {} bytes, at most {} are accepted",
+            manifest.size, max_size
+        ));
+    }
+    let chunk_count = manifest.size.div_ceil(manifest.chunk_size as u64);
+    if manifest.chunk_count as u64 != chunk_count {
+        return invalid(format!(
+            "Manifest with {} chunks for {} bytes, expected {}",
+            manifest.chunk_count, manifest.size, chunk_count
+        ));
+    }
+    Ok(())
+}
+
+fn file_name(name: &str) -> &str {
+    Path::new(name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // a directory of its own for every test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lightchannel_transfer_{}_{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn messages(dir: &Path, data: &[u8]) -> Vec<Message> {
+        let path = dir.join("sent.bin");
+        fs::write(&path, data).unwrap();
+        file_messages(&path, 16).unwrap()
+    }
+
+    fn manifest(messages: &[Message]) -> FileManifest {
+        match &messages[0] {
+            Message::FileManifest(manifest) => manifest.clone(),
+            message => panic!("Not a manifest: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn transfer_out_of_order() {
+        let dir = test_dir("order");
+        let data: Vec<u8> = (0..100).collect();
+        let mut messages = messages(&dir, &data);
+        let received_dir = dir.join("received");
+        let mut files = FileReceiver::new(&received_dir);
+        // a chunk before the manifest and the others backwards
+        let first_chunk = messages.remove(1);
+        assert_eq!(files.push(first_chunk).unwrap(), TransferEvent::Ignored);
+        let mut events: Vec<_> = messages
+            .into_iter()
+            .rev()
+            .map(|message| files.push(message).unwrap())
+            .collect();
+        assert_eq!(
+            events.pop(),
+            Some(TransferEvent::Completed(received_dir.join("sent.bin")))
+        );
+        assert_eq!(fs::read(received_dir.join("sent.bin")).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_manifests() {
+        let dir = test_dir("manifests");
+        let manifest = manifest(&messages(&dir, &[1; 100]));
+        let received_dir = dir.join("received");
+        let mut files = FileReceiver::new(&received_dir);
+        files.max_size = 1000;
+        let invalid = [
+            FileManifest {
+                chunk_size: 0,
+                ..manifest.clone()
+            },
+            FileManifest {
+                size: 1001,
+                chunk_count: 63,
+                ..manifest.clone()
+            },
+            FileManifest {
+                size: u64::MAX,
+                ..manifest.clone()
+            },
+            FileManifest {
+                chunk_count: u32::MAX,
+                ..manifest.clone()
+            },
+        ];
+        for manifest in invalid {
+            let error = files.push(Message::FileManifest(manifest)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(files.manifest().is_none());
+        assert!(!received_dir.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chunks_outside_the_file() {
+        let dir = test_dir("chunks");
+        let manifest = manifest(&messages(&dir, &[1; 100]));
+        let mut files = FileReceiver::new(&dir.join("received"));
+        files.push(Message::FileManifest(manifest.clone())).unwrap();
+        let chunk = |offset: u64, len: usize| {
+            Message::FileChunk(FileChunk {
+                id: manifest.id,
+                offset,
+                data: vec![1; len],
+            })
+        };
+        for outside in [
+            chunk(96, 16),
+            chunk(0, 17),
+            chunk(8, 8),
+            chunk(u64::MAX - 15, 16),
+        ] {
+            assert_eq!(files.push(outside).unwrap(), TransferEvent::Ignored);
+        }
+        assert_eq!(
+            files.push(chunk(96, 4)).unwrap(),
+            TransferEvent::Chunk {
+                received: 1,
+                total: 7
+            }
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,135 +1,446 @@
+use crate::util::complementary::Complementary;
+use crate::util::layout::FrameLayout;
+use crate::util::link::{receive, LinkReport};
+use crate::util::pipeline::FramePipeline;
+use crate::util::rolling_shutter::{row_means, RollingShutter};
+use crate::util::sampler::{Sample, Sampler};
+use crate::util::sink::{FfmpegSink, FrameSink, SinkResult};
+use crate::util::source::Frame;
+use crate::util::timing::{resample, TimingReport};
+use crate::util::watermark::Watermark;
 use bit_vec::BitVec;
-use image::{ImageBuffer, Luma};
-use std::fs;
-use std::process::Command;
+use image::{DynamicImage, GrayImage, Luma, RgbImage};
+use std::time::Duration;
 
 pub fn write_video(data: &BitVec, fps: u32, width: u32, height: u32) {
-    let total_frames = data.len();
+    write_video_with_layout(data, fps, width, height, &FrameLayout::single());
+}
+
+// multiple cells per frame, optionally framed by fiducials
+pub fn write_video_with_layout(
+    data: &BitVec,
+    fps: u32,
+    width: u32,
+    height: u32,
+    layout: &FrameLayout,
+) {
+    let bits: Vec<bool> = data.iter().collect();
+    let total_frames = bits.len().div_ceil(layout.bits_per_frame());
     let duration_seconds = total_frames as f64 / fps as f64;
     println!("frames: {} duration: {}s", total_frames, duration_seconds);
 
-    // Create a directory for the frames
-    let frames_dir = "frames";
-    fs::remove_dir_all(frames_dir).ok();
-    fs::create_dir_all(frames_dir).expect("Failed to create output directory");
-
-    // Generate frames and save them as images
-    for (i, value) in data.iter().enumerate() {
-        // Create a new grayscale image (Luma)
-        let img = ImageBuffer::from_fn(width, height, |_x, _y| {
-            if value {
-                Luma([255u8]) // White pixel
-            } else {
-                Luma([0u8]) // Black pixel
-            }
-        });
+    let bits_per_frame = layout.bits_per_frame();
+    let render = |index: usize| {
+        let start = index * bits_per_frame;
+        let end = (start + bits_per_frame).min(bits.len());
+        layout.render(&bits[start..end], width, height)
+    };
+    encode_rendered(total_frames, render, &fps.to_string(), "output.mp4", true)
+        .expect("Failed to write video");
+}
+
+// flat frames of the given luminance, e.g. a modulated or calibration sequence
+pub fn write_levels(levels: &[u8], fps: u32, width: u32, height: u32) {
+    println!(
+        "frames: {} duration: {}s",
+        levels.len(),
+        levels.len() as f64 / fps as f64
+    );
+    encode_levels(levels, &fps.to_string(), width, height, "output.mp4", true)
+        .expect("Failed to write video");
+}
+
+// flat frames of the given luminance as video, rendered while encoding
+pub fn encode_levels(
+    levels: &[u8],
+    frame_rate: &str,
+    width: u32,
+    height: u32,
+    output_video: &str,
+    progress: bool,
+) -> SinkResult<()> {
+    let render = |index: usize| GrayImage::from_pixel(width, height, Luma([levels[index]]));
+    encode_rendered(levels.len(), render, frame_rate, output_video, progress)
+}
+
+// `count` frames of `render`, rendered on all cores while the frames
+// before are encoded, without keeping them all in memory. With `progress`
+// the frames written so far are shown on stderr.
+pub fn encode_rendered<I, F>(
+    count: usize,
+    render: F,
+    frame_rate: &str,
+    output_video: &str,
+    progress: bool,
+) -> SinkResult<()>
+where
+    I: Into<DynamicImage> + Send,
+    F: Fn(usize) -> I + Sync,
+{
+    let mut sink = FfmpegSink::new(output_video, frame_rate)?;
+    let pipeline = FramePipeline {
+        progress,
+        ..FramePipeline::new()
+    };
+    pipeline.run(count, render, &mut sink)?;
+    sink.finish()
+}
+
+// encode already rendered frames as output.mp4
+pub fn write_frames<I: Into<DynamicImage>>(frames: impl IntoIterator<Item = I>, fps: u32) {
+    encode_frames(frames, &fps.to_string(), None, "output.mp4");
+}
 
-        // Save the image as a PNG file
-        let frame_path = format!("{}/frame_{:04}.png", frames_dir, i);
-        img.save(frame_path).expect("Failed to save frame");
+// `frame_rate` may be a fraction like "30000/1001", the audio of
+// `audio_from` is copied if it has any
+pub fn encode_frames<I: Into<DynamicImage>>(
+    frames: impl IntoIterator<Item = I>,
+    frame_rate: &str,
+    audio_from: Option<&str>,
+    output_video: &str,
+) {
+    let mut sink = FfmpegSink::new(output_video, frame_rate).expect("Failed to create frames");
+    sink.audio_from = audio_from.map(String::from);
+    let mut batches = Batches::new(&mut sink);
+    for img in frames {
+        let img: DynamicImage = img.into();
+        batches.push(img.to_rgb8());
     }
+    batches.flush();
+    if let Err(e) = sink.finish() {
+        eprintln!("Error: {}", e);
+    }
+}
 
-    // Use ffmpeg to combine the images into a video
-    let output_video = "output.mp4";
-    let ffmpeg_status = Command::new("ffmpeg")
-        .args(&[
-            "-framerate",
-            &fps.to_string(),
-            "-i",
-            &format!("{}/frame_%04d.png", frames_dir),
-            "-c:v",
-            "libx264",
-            "-pix_fmt",
-            "yuv420p",
-            //"-t",
-            //&duration_seconds.to_string(),
-            "-y",
-            "-hide_banner",
-            "-loglevel",
-            "error",
-            output_video,
-        ])
-        .status()
-        .expect("Failed to run ffmpeg");
-
-    if ffmpeg_status.success() {
-        println!("Video saved as {}", output_video);
-    } else {
-        eprintln!("Error: Failed to generate video");
+// frames to a sink a batch at a time, so it can save them in parallel
+// while only a batch is in memory
+struct Batches<'a> {
+    sink: &'a mut dyn FrameSink,
+    frames: Vec<Frame>,
+    len: usize,
+}
+
+impl<'a> Batches<'a> {
+    fn new(sink: &'a mut dyn FrameSink) -> Self {
+        let len = FramePipeline::new().batch;
+        Batches {
+            sink,
+            frames: Vec::with_capacity(len),
+            len,
+        }
     }
 
-    fs::remove_dir_all(frames_dir).ok();
+    fn push(&mut self, image: RgbImage) {
+        self.frames.push(Frame {
+            image,
+            timestamp: Duration::ZERO,
+        });
+        if self.frames.len() == self.len {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.sink
+            .write_frames(&self.frames)
+            .expect("Failed to save frame");
+        self.frames.clear();
+    }
 }
 
-//use ffmpeg_next::software::scaling;
+use ffmpeg_next::software::scaling;
 use ffmpeg_next::{codec, format, frame, media};
 
-pub fn read_video() -> BitVec {
-    let video_file = "output.mp4";
-    let mut data = BitVec::new();
+// output.mp4 sampled at the symbol instants of the transmitter
+pub fn read_video(symbol_rate: f64) -> BitVec {
+    read_video_timed("output.mp4", &Sampler::default(), symbol_rate)
+        .expect("Failed to read video")
+        .0
+        .iter()
+        .map(|sample| sample.bit)
+        .collect()
+}
+
+// one sample per decoded frame
+pub fn read_video_samples(video_file: &str, sampler: &Sampler) -> Vec<Sample> {
+    let mut data = Vec::new();
+    for_each_frame(video_file, |frame| data.push(sample_frame(frame, sampler)));
+    data
+}
+
+// one sample per symbol of `symbol_rate`, placed by the frame timestamps
+pub fn read_video_timed(
+    video_file: &str,
+    sampler: &Sampler,
+    symbol_rate: f64,
+) -> Result<(Vec<Sample>, TimingReport), ffmpeg_next::Error> {
+    let mut frames = Vec::new();
+    for_each_timed_frame(video_file, |frame, time| {
+        frames.push((time, sample_frame(frame, sampler)))
+    })?;
+    let (samples, report) = resample(&frames, symbol_rate);
+    if report.duplicated > 0 || report.missing > 0 {
+        println!(
+            "Frame timing: {} duplicated, {} missing of {} symbols, interval: {:.4}s",
+            report.duplicated, report.missing, report.symbols, report.frame_interval
+        );
+    }
+    Ok((samples, report))
+}
+
+// calibration sequence followed by a package
+pub fn read_video_link(
+    video_file: &str,
+    sampler: &Sampler,
+    fps: u32,
+) -> (Option<LinkReport>, Option<BitVec>) {
+    let values: Vec<f32> = read_video_samples(video_file, sampler)
+        .iter()
+        .map(|sample| sample.value)
+        .collect();
+    receive(&values, fps as f32)
+}
+
+// bits hidden in complementary frame pairs
+pub fn read_video_complementary(
+    video_file: &str,
+    sampler: &Sampler,
+    modulation: &Complementary,
+) -> BitVec {
+    let values: Vec<f32> = read_video_samples(video_file, sampler)
+        .iter()
+        .map(|sample| sample.value)
+        .collect();
+    modulation.demodulate(&values)
+}
+
+// bits of every frame, decoded from the rows of a rolling shutter camera
+pub fn read_video_rolling_shutter(video_file: &str, shutter: &RollingShutter) -> Vec<BitVec> {
+    read_video_rows(video_file)
+        .iter()
+        .map(|rows| shutter.decode_rows(rows))
+        .collect()
+}
+
+// mean luminance of every row of every frame
+pub fn read_video_rows(video_file: &str) -> Vec<Vec<f32>> {
+    let mut data = Vec::new();
+    for_each_frame(video_file, |frame| {
+        data.push(row_means(
+            frame.data(0),
+            frame.stride(0),
+            frame.width(),
+            frame.height(),
+        ))
+    });
+    data
+}
+
+// modulate `data` into an existing video, everything else is left
+// untouched. An error if the video has fewer frames than the data needs,
+// before encoding when the container tells the number of frames.
+pub fn embed_video(
+    input_video: &str,
+    output_video: &str,
+    data: &BitVec,
+    watermark: &Watermark,
+) -> SinkResult<()> {
+    let needed = watermark.frames_needed(data);
+    let too_short = |frames: usize| {
+        format!(
+            "{} has {} frames, the data needs {}",
+            input_video, frames, needed
+        )
+    };
+    if let Some(frames) = video_frame_count(input_video).filter(|frames| *frames < needed) {
+        return Err(too_short(frames).into());
+    }
+
+    let frame_rate = video_frame_rate(input_video);
+    let mut sink = FfmpegSink::new(output_video, &frame_rate)?;
+    sink.audio_from = Some(input_video.to_string());
+
+    // every frame is marked and saved as it is decoded
+    let mut batches = Batches::new(&mut sink);
+    let mut frames = 0;
+    for_each_rgb_frame(input_video, |mut img| {
+        watermark.embed_frame(&mut img, data, frames);
+        batches.push(img);
+        frames += 1;
+    });
+    batches.flush();
+
+    // the frames saved so far are dropped with the sink
+    if frames < needed {
+        return Err(too_short(frames).into());
+    }
+    println!(
+        "frames: {} carrying data: {} frame rate: {}",
+        frames, needed, frame_rate
+    );
+    sink.finish()
+}
+
+// recover the data embedded by `embed_video`, subtracting the original
+// video if it is available
+pub fn read_video_watermark(
+    video_file: &str,
+    original_video: Option<&str>,
+    watermark: &Watermark,
+) -> BitVec {
+    let mut values = watermark_values(video_file, watermark);
+    if let Some(original) = original_video {
+        let original_values = watermark_values(original, watermark);
+        values = values
+            .iter()
+            .zip(original_values.iter())
+            .map(|(marked, original)| marked - original)
+            .collect();
+    }
+    watermark.modulation.demodulate(&values)
+}
+
+fn watermark_values(video_file: &str, watermark: &Watermark) -> Vec<f32> {
+    let mut values = Vec::new();
+    for_each_frame(video_file, |frame| {
+        values.push(watermark.frame_value(
+            frame.data(0),
+            frame.stride(0),
+            frame.width(),
+            frame.height(),
+        ))
+    });
+    values
+}
+
+// average frame rate as a fraction for ffmpeg
+pub fn video_frame_rate(video_file: &str) -> String {
+    ffmpeg_next::init().unwrap();
+    let ictx = format::input(&video_file).expect("Failed to open input file");
+    let rate = ictx
+        .streams()
+        .best(media::Type::Video)
+        .expect("Failed to find video stream")
+        .avg_frame_rate();
+    format!("{}/{}", rate.numerator(), rate.denominator())
+}
 
+// frames in the video stream, `None` if the container doesn't say
+fn video_frame_count(video_file: &str) -> Option<usize> {
     ffmpeg_next::init().unwrap();
+    let ictx = format::input(&video_file).expect("Failed to open input file");
+    let frames = ictx.streams().best(media::Type::Video)?.frames();
+    usize::try_from(frames).ok().filter(|frames| *frames > 0)
+}
 
-    let mut ictx = format::input(&video_file).expect("Failed to open input file");
+pub fn for_each_rgb_frame(video_file: &str, mut on_frame: impl FnMut(RgbImage)) {
+    let mut converter = RgbConverter::new();
+    for_each_frame(video_file, |frame| on_frame(converter.convert(frame)));
+}
+
+// decoded frames of any pixel format to RgbImage
+pub struct RgbConverter {
+    scaler: Option<scaling::Context>,
+    rgb_frame: frame::Video,
+}
+
+impl RgbConverter {
+    pub fn new() -> Self {
+        RgbConverter {
+            scaler: None,
+            rgb_frame: frame::Video::empty(),
+        }
+    }
+
+    pub fn convert(&mut self, frame: &frame::Video) -> RgbImage {
+        let scaler = self.scaler.get_or_insert_with(|| {
+            scaling::Context::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                format::Pixel::RGB24,
+                frame.width(),
+                frame.height(),
+                scaling::Flags::BILINEAR,
+            )
+            .expect("Failed to create scaler")
+        });
+        scaler
+            .run(frame, &mut self.rgb_frame)
+            .expect("Failed to scale frame");
+
+        // copy the rows without the line padding
+        let rgb_frame = &self.rgb_frame;
+        let (width, height) = (rgb_frame.width() as usize, rgb_frame.height() as usize);
+        let stride = rgb_frame.stride(0);
+        let mut buffer = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            buffer.extend_from_slice(&rgb_frame.data(0)[y * stride..y * stride + width * 3]);
+        }
+        RgbImage::from_raw(width as u32, height as u32, buffer).expect("Failed to convert frame")
+    }
+}
+
+impl Default for RgbConverter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn for_each_frame(video_file: &str, mut on_frame: impl FnMut(&frame::Video)) {
+    for_each_timed_frame(video_file, |frame, _time| on_frame(frame)).expect("Failed to read video");
+}
+
+// frames with their presentation time in seconds, if the file has one
+pub fn for_each_timed_frame(
+    video_file: &str,
+    mut on_frame: impl FnMut(&frame::Video, Option<f64>),
+) -> Result<(), ffmpeg_next::Error> {
+    ffmpeg_next::init()?;
+
+    let mut ictx = format::input(&video_file)?;
 
     let input_stream = ictx
         .streams()
         .best(media::Type::Video)
-        .expect("Failed to find video stream");
+        .ok_or(ffmpeg_next::Error::StreamNotFound)?;
     let video_stream_index = input_stream.index();
+    let time_base = f64::from(input_stream.time_base());
+    let seconds = |frame: &frame::Video| {
+        frame
+            .timestamp()
+            .map(|timestamp| timestamp as f64 * time_base)
+    };
 
-    let codec =
-        codec::Context::from_parameters(input_stream.parameters()).expect("Failed to open codec");
-
-    let mut video_decoder = codec
-        .decoder()
-        .video()
-        .expect("Failed to get video decoder");
-
-    //let mut scaler = scaling::Context::get(
-    //    video_decoder.format(),
-    //    video_decoder.width(),
-    //    video_decoder.height(),
-    //    format::Pixel::YUV420P,
-    //    video_decoder.width(),
-    //    video_decoder.height(),
-    //    scaling::Flags::BILINEAR,
-    //)
-    //.expect("Failed to create scaler");
+    let codec = codec::Context::from_parameters(input_stream.parameters())?;
+    let mut video_decoder = codec.decoder().video()?;
 
     let mut frame = frame::Video::empty();
 
     for (stream, packet) in ictx.packets() {
         if stream.index() == video_stream_index {
-            video_decoder
-                .send_packet(&packet)
-                .expect("Failed to send packet to decoder");
+            video_decoder.send_packet(&packet)?;
 
             while video_decoder.receive_frame(&mut frame).is_ok() {
-                data.push(get_bit_from_frame(&frame));
-
-                //let mut grayscale_frame = frame::Video::empty();
-                //scaler
-                //    .run(&frame, &mut grayscale_frame)
-                //    .expect("Failed to scale frame");
-                //
-                //////println!("frame format: {:?}", frame.format());
-                //println!("frame data: {:?}", grayscale_frame.data(0)[0]);
+                on_frame(&frame, seconds(&frame));
             }
         }
     }
 
     // drain
-    video_decoder
-        .send_eof()
-        .expect("Failed to send EOF to decoder");
+    video_decoder.send_eof()?;
     while video_decoder.receive_frame(&mut frame).is_ok() {
-        data.push(get_bit_from_frame(&frame));
+        on_frame(&frame, seconds(&frame));
     }
-
-    data
+    Ok(())
 }
 
-fn get_bit_from_frame(frame: &frame::Video) -> bool {
-    frame.data(0)[0] > 128
+// plane 0 is luma for the yuv formats we write and read
+fn sample_frame(frame: &frame::Video, sampler: &Sampler) -> Sample {
+    sampler.sample_plane(
+        frame.data(0),
+        frame.stride(0),
+        frame.width(),
+        frame.height(),
+    )
 }
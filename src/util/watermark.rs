@@ -0,0 +1,91 @@
+// Hide data in an existing video.
+//
+// Uses the complementary modulation on a region of every frame: the
+// luminance of the region is raised and lowered by a few levels in
+// alternating frames, which is invisible at normal frame rates. Without the
+// original video the scene itself is noise, so the region is compared to
+// the rest of the frame (cancelling fades and exposure changes) and several
+// frame pairs are averaged per bit.
+
+use crate::util::complementary::Complementary;
+use crate::util::sampler::{Roi, Sampler};
+use bit_vec::BitVec;
+use image::RgbImage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watermark {
+    /// Modulated region, the whole frame if `None`
+    pub region: Option<Roi>,
+    pub modulation: Complementary,
+}
+
+impl Default for Watermark {
+    fn default() -> Self {
+        Watermark {
+            region: None,
+            modulation: Complementary {
+                delta: 4,
+                pairs_per_bit: 3,
+            },
+        }
+    }
+}
+
+impl Watermark {
+    pub fn frames_needed(&self, data: &BitVec) -> usize {
+        data.len() * self.modulation.frames_per_bit()
+    }
+
+    /// Modulate frame `index` of the video, frames after the data are kept.
+    pub fn embed_frame(&self, img: &mut RgbImage, data: &BitVec, index: usize) {
+        let sign = match self.modulation.frame_sign(data, index) {
+            Some(sign) => sign,
+            None => return,
+        };
+        let delta = sign * self.modulation.delta as i16;
+        let area = self.area(img.width(), img.height());
+        for y in area.y..area.y + area.height {
+            for x in area.x..area.x + area.width {
+                let pixel = img.get_pixel_mut(x, y);
+                // same offset on all channels shifts the luminance only
+                for channel in pixel.0.iter_mut() {
+                    *channel = (*channel as i16 + delta).clamp(0, 255) as u8;
+                }
+            }
+        }
+    }
+
+    /// Luminance of the region relative to the rest of the frame.
+    pub fn frame_value(&self, data: &[u8], stride: usize, width: u32, height: u32) -> f32 {
+        let full = Sampler {
+            border: 0.0,
+            ..Default::default()
+        };
+        let full_mean = full.sample_plane(data, stride, width, height).value;
+
+        let area = self.area(width, height);
+        let area_pixels = (area.width * area.height) as f32;
+        let total_pixels = (width * height) as f32;
+        if area_pixels >= total_pixels {
+            return full_mean;
+        }
+
+        let mut inside = Sampler::with_roi(area);
+        inside.border = 0.0;
+        let inside_mean = inside.sample_plane(data, stride, width, height).value;
+        let outside_mean =
+            (full_mean * total_pixels - inside_mean * area_pixels) / (total_pixels - area_pixels);
+        inside_mean - outside_mean
+    }
+
+    fn area(&self, width: u32, height: u32) -> Roi {
+        match self.region {
+            Some(roi) => {
+                let x = roi.x.min(width);
+                let y = roi.y.min(height);
+                Roi::new(x, y, roi.width.min(width - x), roi.height.min(height - y))
+            }
+            None => Roi::new(0, 0, width, height),
+        }
+    }
+}
@@ -1,13 +1,631 @@
+/// Send and receive data using video.
+/// Usage: video_signal <send|receive|roundtrip|formats|arq|duplex|adaptive|examples> [INPUT] [OPTIONS]
+///   send [FILE | --text TEXT]       render the payload as video
+///   receive VIDEO                   decode a video and write the payload
+///   roundtrip [FILE | --text TEXT]  send, impair, receive and compare
+///   formats [FILE | --text TEXT]    compare the message size of the formats
+///   arq [FILE | --text TEXT]        reliable transfer over a simulated link
+///   duplex [FILE | --text TEXT]     both ends of a simulated duplex link
+///   adaptive [FILE | --text TEXT]   adaptive rate over a changing channel
+///   examples                        run the built-in examples
+/// Options: --fps N, --size WxH, --modulation ook|pam4|pam8,
+/// --coding none|rep3|hamming74, --output PATH, and the impairments of the
+/// roundtrip --contrast, --brightness, --noise, --flicker, --drop, --repeat,
+/// --seed. --src and --dst address the packages to a device (1 to 127), a
+/// group (g0 to g126) or broadcast (default), receive keeps the packages to
+/// its --address, its groups (--group, repeatable) and broadcasts only.
+/// With --transfer send sends FILE with its name and hash as
+/// messages in the --format (compact, bincode, bincode-varint, postcard or
+/// cbor), receive writes it to the directory PATH and resumes a partial file
+/// of an earlier run. arq loses --drop of the messages in both directions
+/// and sends --window payloads at a time, 1 is stop and wait. duplex sends
+/// the payload one way and reversed the other way through the impairments
+/// of the roundtrip, with --half the ends take turns. adaptive sends in
+/// rounds while the --flicker swings between none and the given value, the
+/// mode of every round follows the link quality of the round before. Exits
+/// with 1 when decoding fails and 2 on invalid arguments.
 use bit_vec::BitVec;
-use util::message::{decode_message, encode_message, Message};
-use util::signal::{decode_package, encode_package};
-use util::video::{read_video, write_video};
-mod util;
+use image::{Rgb, RgbImage};
+use lightchannel::tools::arq::{ArqMode, ArqReceiver, ArqSender};
+use lightchannel::tools::back_channel::{memory_pair, BackChannel, Lossy};
+use lightchannel::tools::channel::Channel;
+use lightchannel::tools::complementary::Complementary;
+use lightchannel::tools::duplex::{DuplexLink, DuplexMode, DuplexStats, Loopback};
+use lightchannel::tools::layout::FrameLayout;
+use lightchannel::tools::link::link_sequence;
+use lightchannel::tools::message::{encode_message_as, try_decode_message};
+use lightchannel::tools::perspective::{read_video_grid, Rectifier};
+use lightchannel::tools::rate::{self, RateController};
+use lightchannel::tools::rolling_shutter::{calibration_pattern, RollingShutter};
+use lightchannel::tools::signal::{decode_package, encode_package};
+use lightchannel::tools::transfer::{
+    file_messages, FileReceiver, TransferEvent, DEFAULT_CHUNK_SIZE,
+};
+use lightchannel::tools::video::{
+    embed_video, encode_frames, encode_levels, read_video, read_video_complementary,
+    read_video_link, read_video_rolling_shutter, read_video_rows, read_video_watermark,
+    write_frames, write_levels, write_video, write_video_with_layout,
+};
+use lightchannel::tools::watermark::Watermark;
+use lightchannel::{
+    decode_message, encode_message, Address, AddressFilter, Coding, Config, Format, Message,
+    Modulation, Package, Receiver, Roi, Sampler, Transmitter,
+};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::exit;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 const FPS: u32 = 30;
+// payload of a Data message, so that it fits into a package
+const ARQ_PAYLOAD_LEN: usize = 240;
+// packages per round of the adaptive simulation and their payload
+const ADAPTIVE_PACKAGES: usize = 4;
+const ADAPTIVE_PAYLOAD_LEN: usize = 64;
+// rounds of the flicker to swing up and down again
+const ADAPTIVE_PERIOD: f32 = 20.0;
+
+struct Options {
+    input: Option<String>,
+    text: Option<String>,
+    output: Option<String>,
+    /// Send a file with manifest and chunks, see util::transfer
+    transfer: bool,
+    /// Payloads in flight of the arq simulation
+    window: u32,
+    /// Half-duplex in the duplex simulation
+    half: bool,
+    /// Source and destination of the packages sent
+    src: Address,
+    dst: Address,
+    /// Destinations received
+    filter: AddressFilter,
+    config: Config,
+    channel: Channel,
+}
 
-// send and receive data using video
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+    let options = match parse_options(&args[args.len().min(2)..]) {
+        Ok(options) => options,
+        Err(e) => usage(&e),
+    };
+
+    match command {
+        "send" => {
+            let output = options.output.as_deref().unwrap_or("output.mp4");
+            let transmitter = transmitter(&options);
+            let packages = if options.transfer {
+                file_packages(&options)
+            } else {
+                transmitter
+                    .packages(&payload(&options))
+                    .unwrap_or_else(|e| usage(&e.to_string()))
+            };
+            let levels = transmitter.levels(&packages);
+            let config = &options.config;
+            println!(
+                "frames: {} duration: {:.3}s",
+                levels.len(),
+                levels.len() as f32 / config.fps as f32
+            );
+            if let Err(e) = encode_levels(
+                &levels,
+                &config.fps.to_string(),
+                config.width,
+                config.height,
+                output,
+                true,
+            ) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        "receive" => {
+            let video = options
+                .input
+                .as_deref()
+                .unwrap_or_else(|| usage("Missing video"));
+            if options.transfer {
+                let output = options.output.as_deref().unwrap_or("received");
+                receive_file(video, output, receiver(&options));
+                return;
+            }
+            let output = options.output.as_deref().unwrap_or("received.bin");
+            match receiver(&options).receive_video(video) {
+                Ok(payload) => {
+                    fs::write(output, &payload).expect("Failed to write payload");
+                    println!("Received {} bytes, saved as {}", payload.len(), output);
+                    if let Ok(text) = String::from_utf8(payload) {
+                        println!("Decoded: {:?}", text);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: {} in {}", e, video);
+                    exit(1);
+                }
+            }
+        }
+        "roundtrip" => {
+            let output = options.output.as_deref().unwrap_or("output.mp4");
+            let sent = payload(&options);
+            let config = &options.config;
+            let frames = transmitter(&options)
+                .frames(&sent)
+                .unwrap_or_else(|e| usage(&e.to_string()));
+            let frames = options.channel.apply(&frames);
+            encode_frames(frames, &config.fps.to_string(), None, output);
+
+            let received = receiver(&options).receive_video(output);
+            println!(
+                "Size payload: {} bytes, {:?} {:?} at {} fps",
+                sent.len(),
+                config.modulation,
+                config.coding,
+                config.fps
+            );
+            match received {
+                Ok(received) if received == sent => println!("Roundtrip ok"),
+                Ok(received) => {
+                    eprintln!(
+                        "Error: received {} bytes differ from the payload",
+                        received.len()
+                    );
+                    exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        "formats" => formats(&options),
+        "arq" => arq(&options),
+        "duplex" => duplex(&options),
+        "adaptive" => adaptive(&options),
+        "examples" => examples(),
+        _ => usage("Missing command"),
+    }
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("Error: {}", error);
+    eprintln!(
+        "Usage: video_signal <send|receive|roundtrip|formats|arq|duplex|adaptive|examples> [FILE | --text TEXT] [OPTIONS]"
+    );
+    exit(2);
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        input: None,
+        text: None,
+        output: None,
+        transfer: false,
+        window: 1,
+        half: false,
+        src: Address::UNASSIGNED,
+        dst: Address::BROADCAST,
+        filter: AddressFilter::default(),
+        config: Config::default(),
+        channel: Channel::default(),
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if !flag.starts_with("--") || flag == "--transfer" || flag == "--half" {
+            match flag {
+                "--transfer" => options.transfer = true,
+                "--half" => options.half = true,
+                input => options.input = Some(input.to_string()),
+            }
+            i += 1;
+            continue;
+        }
+        let value = args
+            .get(i + 1)
+            .ok_or_else(|| format!("Missing value for {}", flag))?;
+        let number = || parse::<f32>(flag, value);
+        match flag {
+            "--text" => options.text = Some(value.clone()),
+            "--output" => options.output = Some(value.clone()),
+            "--fps" => options.config.fps = parse(flag, value)?,
+            "--size" => {
+                let (width, height) = value
+                    .split_once('x')
+                    .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                    .ok_or_else(|| format!("Invalid size: {}", value))?;
+                options.config.width = width;
+                options.config.height = height;
+            }
+            "--modulation" => {
+                options.config.modulation = Modulation::from_name(value)
+                    .ok_or_else(|| format!("Invalid modulation: {}", value))?
+            }
+            "--coding" => {
+                options.config.coding =
+                    Coding::from_name(value).ok_or_else(|| format!("Invalid coding: {}", value))?
+            }
+            "--format" => {
+                options.config.format =
+                    Format::from_name(value).ok_or_else(|| format!("Invalid format: {}", value))?
+            }
+            "--window" => options.window = parse(flag, value)?,
+            "--src" | "--dst" | "--address" | "--group" => {
+                let address = Address::from_name(value)
+                    .ok_or_else(|| format!("Invalid address: {}", value))?;
+                match flag {
+                    "--src" => options.src = address,
+                    "--dst" => options.dst = address,
+                    "--address" => options.filter.address = Some(address),
+                    _ if address.is_group() => options.filter.join(address),
+                    _ => return Err(format!("Not a group: {}", value)),
+                }
+            }
+            "--contrast" => options.channel.contrast = number()?,
+            "--brightness" => options.channel.brightness = number()?,
+            "--noise" => options.channel.noise = number()?,
+            "--flicker" => options.channel.flicker = number()?,
+            "--drop" => options.channel.drop_rate = number()?,
+            "--repeat" => options.channel.repeat_rate = number()?,
+            "--seed" => options.channel.seed = parse(flag, value)?,
+            _ => return Err(format!("Invalid argument: {}", flag)),
+        }
+        i += 2;
+    }
+    let config = &options.config;
+    if config.fps == 0 || config.width == 0 || config.height == 0 {
+        return Err("fps and size must not be zero".to_string());
+    }
+    Ok(options)
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number for {}: {}", flag, value))
+}
+
+fn transmitter(options: &Options) -> Transmitter {
+    Transmitter {
+        src: options.src,
+        dst: options.dst,
+        ..Transmitter::new(options.config.clone())
+    }
+}
+
+fn receiver(options: &Options) -> Receiver {
+    Receiver {
+        filter: options.filter.clone(),
+        ..Receiver::new(options.config.clone())
+    }
+}
+
+fn payload(options: &Options) -> Vec<u8> {
+    match (&options.text, &options.input) {
+        (Some(text), _) => text.as_bytes().to_vec(),
+        (None, Some(path)) => fs::read(path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e))),
+        (None, None) => usage("Missing FILE or --text"),
+    }
+}
+
+// manifest and chunks of the input file, one package each, with smaller
+// chunks for formats that don't fit the default into a package
+fn file_packages(options: &Options) -> Vec<Package> {
+    let path = options
+        .input
+        .as_deref()
+        .unwrap_or_else(|| usage("Missing FILE"));
+    let mut chunk_size = DEFAULT_CHUNK_SIZE;
+    loop {
+        let messages = file_messages(Path::new(path), chunk_size)
+            .unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        let packages: Result<Vec<Package>, _> = messages
+            .iter()
+            .map(|message| {
+                Package::new(encode_message_as(message, options.config.format).to_bytes())
+                    .map(|package| package.addressed(options.src, options.dst))
+            })
+            .collect();
+        match packages {
+            Ok(packages) => return packages,
+            Err(_) if chunk_size > 1 => chunk_size /= 2,
+            Err(e) => usage(&format!("{}: {}", path, e)),
+        }
+    }
+}
+
+// size of typical messages in every format, with header and compression
+fn formats(options: &Options) {
+    let text = options.text.as_deref().unwrap_or("Hello World!");
+    let mut messages = vec![
+        Message::Text {
+            id: 1,
+            content: text.to_string(),
+        },
+        Message::Url(String::from("https://github.com/patte")),
+        Message::Ack { id: 1 },
+        Message::Config(options.config.clone()),
+    ];
+    if let Some(path) = &options.input {
+        let file = file_messages(Path::new(path), DEFAULT_CHUNK_SIZE)
+            .unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        messages.extend(file.into_iter().take(2));
+    }
+
+    print!("{:<14}", "bytes");
+    for format in Format::ALL {
+        print!("{:>16}", format.name());
+    }
+    println!();
+    for message in &messages {
+        print!("{:<14}", format!("{:?}", message.kind()));
+        for format in Format::ALL {
+            let encoded = encode_message_as(message, format);
+            assert_eq!(decode_message(&encoded).as_ref(), Ok(message));
+            print!("{:>16}", encoded.len() / 8);
+        }
+        println!();
+    }
+}
+
+// send the payload over a lossy link in memory, the receiver runs in a
+// thread and acknowledges over the same link
+fn arq(options: &Options) {
+    let sent = payload(options);
+    let payloads: Vec<Vec<u8>> = sent.chunks(ARQ_PAYLOAD_LEN).map(<[u8]>::to_vec).collect();
+    let mode = ArqMode::with_window(options.window);
+    let (drop_rate, seed) = (options.channel.drop_rate, options.channel.seed);
+    let (sender_end, receiver_end) = memory_pair();
+
+    let receiving = thread::spawn(move || {
+        let mut back = Lossy::new(receiver_end, drop_rate, seed.wrapping_add(1));
+        let mut receiver = ArqReceiver::new(mode);
+        let mut received = Vec::new();
+        // until the sender is done and drops its end
+        while let Ok(message) = back.recv_timeout(Duration::from_secs(1)) {
+            if let Some(message) = message {
+                let payloads = receiver
+                    .push(message, &mut back)
+                    .expect("Back-channel closed");
+                received.extend(payloads.concat());
+            }
+        }
+        received
+    });
+
+    let mut link = Lossy::new(sender_end, drop_rate, seed);
+    let result = ArqSender::new(mode, Duration::from_millis(50)).send(&payloads, &mut link);
+    drop(link);
+    let received = receiving.join().expect("Receiver failed");
+
+    println!(
+        "Size payload: {} bytes in {} messages, {:?}, {:.0}% lost",
+        sent.len(),
+        payloads.len(),
+        mode,
+        drop_rate * 100.0
+    );
+    match result {
+        Ok(stats) => println!(
+            "Sent {} messages, {} again, {} acks, {} nacks in {:.3}s",
+            stats.sent,
+            stats.retransmitted,
+            stats.acks,
+            stats.nacks,
+            stats.duration.as_secs_f32()
+        ),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1);
+        }
+    }
+    if received != sent {
+        eprintln!(
+            "Error: received {} bytes differ from the payload",
+            received.len()
+        );
+        exit(1);
+    }
+    println!("Transfer ok");
+}
+
+// both ends of a duplex link in one process, frame by frame through the
+// channel simulator
+fn duplex(options: &Options) {
+    let sent_a = payload(options);
+    let sent_b: Vec<u8> = sent_a.iter().rev().copied().collect();
+    let mode = if options.half {
+        DuplexMode::Half
+    } else {
+        DuplexMode::Full
+    };
+    let mut a = DuplexLink::new(options.config.clone(), mode);
+    let mut b = DuplexLink::new(options.config.clone(), mode);
+    a.send(&sent_a);
+    b.send(&sent_b);
+
+    let mut loopback = Loopback::new(a, b, options.channel.clone());
+    // an hour of video
+    let max_frames = options.config.fps as u64 * 3600;
+    let done = loopback.run_until(max_frames, |l| l.a.is_idle() && l.b.is_idle());
+    let frames = loopback.a.stats.frames;
+    println!(
+        "Size payload: {} bytes each way, {:?} duplex, {} frames ({:.1}s)",
+        sent_a.len(),
+        mode,
+        frames,
+        frames as f32 / options.config.fps as f32
+    );
+    print_duplex_stats("a", &loopback.a.stats);
+    print_duplex_stats("b", &loopback.b.stats);
+    if !done {
+        eprintln!("Error: not acknowledged after {} frames", frames);
+        exit(1);
+    }
+
+    let received_a: Vec<u8> = std::iter::from_fn(|| loopback.a.recv()).flatten().collect();
+    let received_b: Vec<u8> = std::iter::from_fn(|| loopback.b.recv()).flatten().collect();
+    if received_a != sent_b || received_b != sent_a {
+        eprintln!("Error: received payloads differ");
+        exit(1);
+    }
+    println!("Duplex ok");
+}
+
+fn print_duplex_stats(end: &str, stats: &DuplexStats) {
+    println!(
+        "{}: {} messages, {} again, {} acks piggybacked",
+        end, stats.messages, stats.retransmitted, stats.piggybacked
+    );
+}
+
+// send the payload in rounds of a few packages while the channel gets worse
+// and better again, the receiver reports the quality of every round to the
+// controller of the transmitter and lost packages are sent again
+fn adaptive(options: &Options) {
+    let sent = payload(options);
+    let chunks: Vec<&[u8]> = sent.chunks(ADAPTIVE_PAYLOAD_LEN).collect();
+    let mut received: Vec<Option<Vec<u8>>> = vec![None; chunks.len()];
+    let sampler = Sampler::default();
+    let mut controller = RateController::new();
+    let mut frames_sent = 0;
+
+    for round in 0.. {
+        let missing: Vec<usize> = (0..chunks.len())
+            .filter(|i| received[*i].is_none())
+            .take(ADAPTIVE_PACKAGES)
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        if round == 100 * chunks.len() {
+            eprintln!("Error: {} packages not received", missing.len());
+            exit(1);
+        }
+        let packages: Vec<Package> = missing
+            .iter()
+            .map(|i| {
+                let message = Message::Data {
+                    seq: *i as u32,
+                    ack: None,
+                    data: chunks[*i].to_vec(),
+                };
+                Package::new(encode_message(&message).to_bytes()).expect("Message too large")
+            })
+            .collect();
+
+        let mode = controller.mode();
+        let swing = (std::f32::consts::PI * round as f32 / ADAPTIVE_PERIOD).sin();
+        let channel = Channel {
+            flicker: options.channel.flicker * swing * swing,
+            seed: options.channel.seed.wrapping_add(round as u64),
+            ..options.channel.clone()
+        };
+        let frames = channel.apply(&rate::frames(&packages, mode, &options.config));
+        frames_sent += frames.len();
+
+        let values: Vec<f32> = frames
+            .iter()
+            .map(|frame| sampler.sample_image(frame).value)
+            .collect();
+        let (packages, quality) = rate::decode_values(&values);
+        for package in packages {
+            if let Some(Message::Data { seq, data, .. }) =
+                try_decode_message(&BitVec::from_bytes(package.data()))
+            {
+                // a corrupt or foreign package may have any number
+                if let Some(slot) = received.get_mut(seq as usize) {
+                    *slot = Some(data);
+                }
+            }
+        }
+        println!(
+            "Round {:>3}: flicker {:>5.1}, {:?} {:?} x{}: {} packages, {} lost, {} corrected, SNR {:.1} dB",
+            round,
+            channel.flicker,
+            mode.modulation,
+            mode.coding,
+            mode.symbol_frames,
+            quality.packages,
+            quality.crc_failures,
+            quality.corrections,
+            quality.snr.unwrap_or(f32::NAN)
+        );
+        // the report goes back as a message, e.g. over util::back_channel
+        let report = encode_message(&Message::LinkQuality(quality));
+        if let Some(Message::LinkQuality(quality)) = try_decode_message(&report) {
+            controller.update(&quality);
+        }
+    }
+
+    let received: Vec<u8> = received.into_iter().flatten().flatten().collect();
+    if received != sent {
+        eprintln!("Error: received payload differs");
+        exit(1);
+    }
+    let seconds = frames_sent as f32 / options.config.fps as f32;
+    println!(
+        "Size payload: {} bytes in {} frames ({:.1}s), {:.1} bit/s",
+        sent.len(),
+        frames_sent,
+        seconds,
+        sent.len() as f32 * 8.0 / seconds
+    );
+    println!("Adaptive ok");
+}
+
+// write the file of the transfer in `video_file` to `dir`, a partial file
+// from an earlier run is resumed
+fn receive_file(video_file: &str, dir: &str, receiver: Receiver) {
+    let mut files = FileReceiver::new(Path::new(dir));
+    let packages = receiver.decode_video(video_file).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        exit(1);
+    });
+    for package in packages {
+        let message = match try_decode_message(&BitVec::from_bytes(package.data())) {
+            Some(message) => message,
+            None => continue,
+        };
+        let event = match files.push(message) {
+            Ok(event) => event,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Ignored: {}", e);
+                continue;
+            }
+            Err(e) => panic!("Failed to write file: {}", e),
+        };
+        match event {
+            TransferEvent::Started { name, received } => {
+                println!("Receiving {}, {} chunks from before", name, received)
+            }
+            TransferEvent::Completed(path) => println!("File saved as {}", path.display()),
+            TransferEvent::HashMismatch => eprintln!("Error: SHA-256 mismatch, starting over"),
+            TransferEvent::Chunk { .. } | TransferEvent::Ignored => {}
+        }
+    }
+
+    if !files.is_complete() {
+        match files.manifest() {
+            Some(manifest) => eprintln!(
+                "Error: {} incomplete, missing bytes {:?}",
+                manifest.name,
+                files.missing_ranges()
+            ),
+            None => eprintln!("Error: no file manifest in {}", video_file),
+        }
+        exit(1);
+    }
+}
+
+// the original examples, asserting every transmission
+fn examples() {
     let encoded_data = BitVec::from_bytes(&[0b11001110, 0b00110001]);
     let decoded_package = send_receive(&encoded_data);
     println!("Decoded: {:?}", decoded_package);
@@ -22,19 +640,68 @@ fn main() {
     println!("");
     assert_eq!(text_message, decoded_message);
 
-    let message = Message {
+    let message = Message::Text {
         id: 1,
         content: String::from("Hello World!"),
     };
     let encoded_message = encode_message(&message);
     let decoded_package = send_receive(&encoded_message);
-    let decoded_message = decode_message(&decoded_package);
+    let decoded_message = decode_message(&decoded_package).expect("Failed to decode message");
     println!("Decoded: {:?}", decoded_message);
+    println!("");
     assert_eq!(message, decoded_message);
+
+    // repetitive content is compressed with lz4
+    let long_content = "Hello World! ".repeat(8);
+    let long_message = Message::Text {
+        id: 2,
+        content: long_content.clone(),
+    };
+    let encoded_long_message = encode_message(&long_message);
+    let decoded_package = send_receive(&encoded_long_message);
+    let decoded_message = decode_message(&decoded_package).expect("Failed to decode message");
+    println!(
+        "Decoded: {:?}, {} of {} bytes",
+        decoded_message,
+        encoded_long_message.len() / 8,
+        long_content.len()
+    );
+    println!("");
+    assert_eq!(long_message, decoded_message);
+
+    // many bits per frame
+    let decoded_package = send_receive_grid(&encoded_message);
+    let decoded_message = decode_message(&decoded_package).expect("Failed to decode message");
+    println!("Decoded: {:?}", decoded_message);
+    println!("");
+    assert_eq!(message, decoded_message);
+
+    // many bits per frame from a light blinking faster than the camera
+    let decoded_package = send_receive_rolling_shutter(&encoded_data);
+    println!("Decoded: {:?}", decoded_package);
+    println!("");
+    assert_eq!(encoded_data, decoded_package);
+
+    // invisible to viewers: small luminance changes around a gray carrier
+    let decoded_package = send_receive_complementary(&encoded_data);
+    println!("Decoded: {:?}", decoded_package);
+    println!("");
+    assert_eq!(encoded_data, decoded_package);
+
+    // hidden in an existing video
+    let decoded_package = embed_extract(&encoded_data);
+    println!("Decoded: {:?}", decoded_package);
+    println!("");
+    assert_eq!(encoded_data, decoded_package);
+
+    // measure the link before sending data
+    let decoded_package = send_receive_calibrated(&encoded_data);
+    println!("Decoded: {:?}", decoded_package);
+    assert_eq!(encoded_data, decoded_package);
 }
 
 fn send_receive(data: &BitVec) -> BitVec {
-    let mut package_data = encode_package(data);
+    let mut package_data = encode_package(data).expect("Data too large for a package");
 
     // add bytes to test robustness
     for _ in 0..3 {
@@ -46,7 +713,7 @@ fn send_receive(data: &BitVec) -> BitVec {
 
     write_video(&package_data, FPS, 2, 2);
 
-    let received_data = read_video();
+    let received_data = read_video(FPS as f64);
 
     let decoded_package = decode_package(&received_data).unwrap();
 
@@ -64,3 +731,135 @@ fn send_receive(data: &BitVec) -> BitVec {
 
     decoded_package
 }
+
+fn send_receive_grid(data: &BitVec) -> BitVec {
+    let package_data = encode_package(data).expect("Data too large for a package");
+
+    let layout = FrameLayout::grid(8, 4);
+    write_video_with_layout(&package_data, FPS, 640, 360, &layout);
+
+    let rectifier = Rectifier::new(layout, 640, 360);
+    let received_data = read_video_grid("output.mp4", &rectifier).expect("Failed to read video");
+
+    let decoded_package = decode_package(&received_data).unwrap();
+    println!(
+        "Size package: {} payload: {}, bits per frame: {} duration: {:.3}s",
+        package_data.len(),
+        decoded_package.len(),
+        layout.bits_per_frame(),
+        package_data.len().div_ceil(layout.bits_per_frame()) as f32 / FPS as f32
+    );
+
+    decoded_package
+}
+
+fn send_receive_rolling_shutter(data: &BitVec) -> BitVec {
+    let package_data = encode_package(data).expect("Data too large for a package");
+    let (width, height) = (64, 480);
+
+    // simulated camera reading out its rows in 80% of the frame time,
+    // the transmitter blinks with 4 rows per symbol
+    let row_time = 0.8 / (FPS as f64 * height as f64);
+    let symbol_rate = 1.0 / (4.0 * row_time);
+    let camera = RollingShutter::new(symbol_rate, row_time);
+
+    // the receiver only knows the symbol rate and calibrates the row time
+    write_frames(
+        camera.render_capture(&calibration_pattern(64), FPS, width, height, 5),
+        FPS,
+    );
+    let shutter = RollingShutter::calibrate(symbol_rate, &read_video_rows("output.mp4"))
+        .expect("Failed to calibrate row time");
+    println!(
+        "Calibrated {:.2} rows per symbol",
+        shutter.rows_per_symbol()
+    );
+    if shutter.rows_per_symbol() < 2.0 {
+        eprintln!("Too few rows per symbol to decode");
+    }
+
+    write_frames(
+        camera.render_capture(&package_data, FPS, width, height, FPS as usize),
+        FPS,
+    );
+    let received_frames = read_video_rolling_shutter("output.mp4", &shutter);
+    let decoded_packages: Vec<BitVec> = received_frames.iter().filter_map(decode_package).collect();
+
+    println!(
+        "Decoded packages: {}/{} frames, bits per frame: {}, symbol rate: {:.0}/s",
+        decoded_packages.len(),
+        received_frames.len(),
+        shutter.bits_per_frame(height),
+        symbol_rate
+    );
+
+    decoded_packages.into_iter().next().unwrap()
+}
+
+fn send_receive_complementary(data: &BitVec) -> BitVec {
+    let package_data = encode_package(data).expect("Data too large for a package");
+    let fps = 60;
+
+    let modulation = Complementary::default();
+    let carrier = Complementary::gray_carrier(64, 64);
+    write_frames(modulation.render(&package_data, &carrier), fps);
+
+    let received_data = read_video_complementary("output.mp4", &Sampler::default(), &modulation);
+    let decoded_package = decode_package(&received_data).unwrap();
+
+    println!(
+        "Size package: {} payload: {}, delta: {} duration: {:.3}s",
+        package_data.len(),
+        decoded_package.len(),
+        modulation.delta,
+        (package_data.len() * modulation.frames_per_bit()) as f32 / fps as f32
+    );
+
+    decoded_package
+}
+
+fn embed_extract(data: &BitVec) -> BitVec {
+    let package_data = encode_package(data).expect("Data too large for a package");
+    let watermark = Watermark {
+        region: Some(Roi::new(40, 30, 80, 60)),
+        ..Default::default()
+    };
+
+    // a moving gradient stands in for a real video
+    let total_frames = watermark.frames_needed(&package_data) + FPS as usize;
+    let scene = (0..total_frames).map(|i| {
+        RgbImage::from_fn(160, 120, |x, y| {
+            Rgb([((x + i as u32 * 2) % 256) as u8, (y * 2) as u8, 96])
+        })
+    });
+    encode_frames(scene, &FPS.to_string(), None, "carrier.mp4");
+
+    embed_video("carrier.mp4", "output.mp4", &package_data, &watermark)
+        .expect("Failed to embed the data");
+
+    let with_original = decode_package(&read_video_watermark(
+        "output.mp4",
+        Some("carrier.mp4"),
+        &watermark,
+    ));
+    let blind = decode_package(&read_video_watermark("output.mp4", None, &watermark));
+    println!(
+        "Size package: {} frames: {} decoded with original: {} blind: {}",
+        package_data.len(),
+        watermark.frames_needed(&package_data),
+        with_original.is_some(),
+        blind.is_some()
+    );
+
+    with_original.unwrap()
+}
+
+fn send_receive_calibrated(data: &BitVec) -> BitVec {
+    let package_data = encode_package(data).expect("Data too large for a package");
+    write_levels(&link_sequence(&package_data), FPS, 2, 2);
+
+    let (report, decoded_package) = read_video_link("output.mp4", &Sampler::default(), FPS);
+    println!("Link report: {:#?}", report.expect("Calibration not found"));
+
+    decoded_package.unwrap()
+}